@@ -0,0 +1 @@
+ALTER TABLE handler_config DROP COLUMN is_secret;
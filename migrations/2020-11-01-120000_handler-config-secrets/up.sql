@@ -0,0 +1,2 @@
+ALTER TABLE handler_config
+  ADD COLUMN is_secret BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,3 @@
+DROP TABLE handler_config_groups;
+DROP TABLE config_group_entries;
+DROP TABLE config_groups;
@@ -0,0 +1,60 @@
+CREATE TABLE IF NOT EXISTS config_groups
+  ( id UUID DEFAULT uuid_generate_v4() NOT NULL
+  , user_id UUID NOT NULL
+  , name VARCHAR NOT NULL
+  , created_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , PRIMARY KEY (id)
+  , CONSTRAINT fk_user_id
+    FOREIGN KEY (user_id)
+    REFERENCES users(id)
+  );
+
+CREATE UNIQUE INDEX config_groups_user_id_name_idx ON config_groups(user_id, name);
+
+CREATE TRIGGER set_timestamp_config_groups
+  BEFORE UPDATE ON config_groups
+  FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
+
+CREATE TABLE IF NOT EXISTS config_group_entries
+  ( key_name VARCHAR NOT NULL
+  , value_contents VARCHAR NOT NULL
+  , group_id UUID NOT NULL
+  , is_secret BOOLEAN NOT NULL DEFAULT false
+  , created_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , PRIMARY KEY (key_name, group_id)
+  , CONSTRAINT fk_group_id
+    FOREIGN KEY (group_id)
+    REFERENCES config_groups(id)
+    ON DELETE CASCADE
+  );
+
+CREATE TRIGGER set_timestamp_config_group_entries
+  BEFORE UPDATE ON config_group_entries
+  FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
+
+-- Groups with a higher priority win over groups with a lower one. The config
+-- of the handler itself always wins over every group.
+CREATE TABLE IF NOT EXISTS handler_config_groups
+  ( handler_id UUID NOT NULL
+  , group_id UUID NOT NULL
+  , priority INTEGER NOT NULL DEFAULT 0
+  , created_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , PRIMARY KEY (handler_id, group_id)
+  , CONSTRAINT fk_handler_id
+    FOREIGN KEY (handler_id)
+    REFERENCES handlers(id)
+  , CONSTRAINT fk_group_id
+    FOREIGN KEY (group_id)
+    REFERENCES config_groups(id)
+    ON DELETE CASCADE
+  );
+
+CREATE TRIGGER set_timestamp_handler_config_groups
+  BEFORE UPDATE ON handler_config_groups
+  FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
@@ -0,0 +1,2 @@
+DROP TABLE handler_config_history;
+DROP TABLE handler_versions;
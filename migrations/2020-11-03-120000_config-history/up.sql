@@ -0,0 +1,61 @@
+CREATE TABLE IF NOT EXISTS handler_versions
+  ( id UUID DEFAULT uuid_generate_v4() NOT NULL
+  , handler_id UUID NOT NULL
+  , module_url VARCHAR NOT NULL
+  , created_by UUID NOT NULL
+  , created_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , PRIMARY KEY (id)
+  , CONSTRAINT fk_handler_id
+    FOREIGN KEY (handler_id)
+    REFERENCES handlers(id)
+  , CONSTRAINT fk_created_by
+    FOREIGN KEY (created_by)
+    REFERENCES users(id)
+  );
+
+CREATE INDEX handler_versions_handler_id_created_at_idx
+  ON handler_versions(handler_id, created_at);
+
+CREATE TRIGGER set_timestamp_handler_versions
+  BEFORE UPDATE ON handler_versions
+  FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
+
+INSERT INTO handler_versions (handler_id, module_url, created_by, created_at)
+  SELECT id, current_version, user_id, updated_at
+  FROM handlers
+  WHERE current_version IS NOT NULL;
+
+-- action is either 'set' or 'delete'. new_value_contents is kept (encrypted
+-- for secrets) so that old config can be restored.
+CREATE TABLE IF NOT EXISTS handler_config_history
+  ( id UUID DEFAULT uuid_generate_v4() NOT NULL
+  , handler_id UUID NOT NULL
+  , key_name VARCHAR NOT NULL
+  , action VARCHAR NOT NULL
+  , old_value_hash VARCHAR
+  , new_value_hash VARCHAR
+  , new_value_contents VARCHAR
+  , is_secret BOOLEAN NOT NULL DEFAULT false
+  , changed_by UUID NOT NULL
+  , created_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , PRIMARY KEY (id)
+  , CONSTRAINT fk_handler_id
+    FOREIGN KEY (handler_id)
+    REFERENCES handlers(id)
+  , CONSTRAINT fk_changed_by
+    FOREIGN KEY (changed_by)
+    REFERENCES users(id)
+  );
+
+CREATE INDEX handler_config_history_handler_id_created_at_idx
+  ON handler_config_history(handler_id, created_at);
+
+-- Config that predates the history table becomes its first entry. Hashes are
+-- computed by the application, so they are left empty here.
+INSERT INTO handler_config_history
+  (handler_id, key_name, action, new_value_contents, is_secret, changed_by, created_at)
+  SELECT hc.handler_id, hc.key_name, 'set', hc.value_contents, hc.is_secret, h.user_id, hc.updated_at
+  FROM handler_config hc
+  INNER JOIN handlers h ON h.id = hc.handler_id;
@@ -0,0 +1,3 @@
+DROP TABLE environment_config;
+DROP TABLE handler_environments;
+ALTER TABLE handlers DROP COLUMN current_version_id;
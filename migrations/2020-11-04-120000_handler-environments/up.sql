@@ -0,0 +1,59 @@
+CREATE TABLE IF NOT EXISTS handler_environments
+  ( id UUID DEFAULT uuid_generate_v4() NOT NULL
+  , handler_id UUID NOT NULL
+  , name VARCHAR NOT NULL
+  , current_version VARCHAR
+  , version_id UUID
+  , created_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , PRIMARY KEY (id)
+  , CONSTRAINT fk_handler_id
+    FOREIGN KEY (handler_id)
+    REFERENCES handlers(id)
+  , CONSTRAINT fk_version_id
+    FOREIGN KEY (version_id)
+    REFERENCES handler_versions(id)
+  );
+
+CREATE UNIQUE INDEX handler_environments_handler_id_name_idx
+  ON handler_environments(handler_id, name);
+
+CREATE TRIGGER set_timestamp_handler_environments
+  BEFORE UPDATE ON handler_environments
+  FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
+
+-- Handlers point at the exact version they run as well, so that new
+-- environments can start from it. Module URLs are content-addressed, versions
+-- can share one.
+ALTER TABLE handlers
+  ADD COLUMN current_version_id UUID REFERENCES handler_versions(id);
+
+UPDATE handlers h
+   SET current_version_id = (
+     SELECT v.id FROM handler_versions v
+      WHERE v.handler_id = h.id AND v.module_url = h.current_version
+      ORDER BY v.created_at DESC
+      LIMIT 1
+   )
+ WHERE h.current_version IS NOT NULL;
+
+-- Environment config is layered on top of the effective config of the handler.
+CREATE TABLE IF NOT EXISTS environment_config
+  ( key_name VARCHAR NOT NULL
+  , value_contents VARCHAR NOT NULL
+  , environment_id UUID NOT NULL
+  , is_secret BOOLEAN NOT NULL DEFAULT false
+  , created_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , PRIMARY KEY (key_name, environment_id)
+  , CONSTRAINT fk_environment_id
+    FOREIGN KEY (environment_id)
+    REFERENCES handler_environments(id)
+    ON DELETE CASCADE
+  );
+
+CREATE TRIGGER set_timestamp_environment_config
+  BEFORE UPDATE ON environment_config
+  FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
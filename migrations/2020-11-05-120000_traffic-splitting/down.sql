@@ -0,0 +1,5 @@
+DROP TABLE handler_canary_policies;
+DROP TABLE handler_traffic;
+ALTER TABLE executions
+  DROP COLUMN exit_code,
+  DROP COLUMN version_id;
@@ -0,0 +1,46 @@
+ALTER TABLE executions
+  ADD COLUMN version_id UUID REFERENCES handler_versions(id),
+  ADD COLUMN exit_code INTEGER;
+
+CREATE TABLE IF NOT EXISTS handler_traffic
+  ( handler_id UUID NOT NULL
+  , version_id UUID NOT NULL
+  , weight INTEGER NOT NULL CHECK (weight >= 0)
+  , created_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , PRIMARY KEY (handler_id, version_id)
+  , CONSTRAINT fk_handler_id
+    FOREIGN KEY (handler_id)
+    REFERENCES handlers(id)
+  , CONSTRAINT fk_version_id
+    FOREIGN KEY (version_id)
+    REFERENCES handler_versions(id)
+  );
+
+CREATE TRIGGER set_timestamp_handler_traffic
+  BEFORE UPDATE ON handler_traffic
+  FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
+
+-- When a canary's failure rate goes over failure_threshold (after at least
+-- min_executions runs), all traffic is sent back to stable_version_id.
+CREATE TABLE IF NOT EXISTS handler_canary_policies
+  ( handler_id UUID NOT NULL
+  , stable_version_id UUID NOT NULL
+  , failure_threshold DOUBLE PRECISION NOT NULL
+  , min_executions INTEGER NOT NULL DEFAULT 20
+  , created_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , PRIMARY KEY (handler_id)
+  , CONSTRAINT fk_handler_id
+    FOREIGN KEY (handler_id)
+    REFERENCES handlers(id)
+  , CONSTRAINT fk_stable_version_id
+    FOREIGN KEY (stable_version_id)
+    REFERENCES handler_versions(id)
+  );
+
+CREATE TRIGGER set_timestamp_handler_canary_policies
+  BEFORE UPDATE ON handler_canary_policies
+  FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
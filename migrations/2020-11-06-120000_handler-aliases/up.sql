@@ -0,0 +1,20 @@
+-- Aliases without an expiry are user-defined. Renaming a handler leaves its old
+-- name behind as an alias that redirects until expires_at.
+CREATE TABLE IF NOT EXISTS handler_aliases
+  ( name VARCHAR NOT NULL
+  , handler_id UUID NOT NULL
+  , expires_at TIMESTAMP
+  , created_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , PRIMARY KEY (name)
+  , CONSTRAINT fk_handler_id
+    FOREIGN KEY (handler_id)
+    REFERENCES handlers(id)
+  );
+
+CREATE INDEX handler_aliases_handler_id_idx ON handler_aliases(handler_id);
+
+CREATE TRIGGER set_timestamp_handler_aliases
+  BEFORE UPDATE ON handler_aliases
+  FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
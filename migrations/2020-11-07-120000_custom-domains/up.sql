@@ -0,0 +1,23 @@
+-- A custom domain only routes to its handler once verified_at is set, which
+-- happens after the owner publishes verification_token in a DNS TXT record.
+CREATE TABLE IF NOT EXISTS custom_domains
+  ( id UUID DEFAULT uuid_generate_v4() NOT NULL
+  , handler_id UUID NOT NULL
+  , domain VARCHAR NOT NULL UNIQUE
+  , verification_token VARCHAR NOT NULL
+  , verified_at TIMESTAMP
+  , created_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , PRIMARY KEY (id)
+  , CONSTRAINT fk_handler_id
+    FOREIGN KEY (handler_id)
+    REFERENCES handlers(id)
+    ON DELETE CASCADE
+  );
+
+CREATE INDEX custom_domains_handler_id_idx ON custom_domains(handler_id);
+
+CREATE TRIGGER set_timestamp_custom_domains
+  BEFORE UPDATE ON custom_domains
+  FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
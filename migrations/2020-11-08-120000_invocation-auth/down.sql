@@ -0,0 +1,3 @@
+DROP TABLE invocation_keys;
+ALTER TABLE tokens DROP COLUMN scopes;
+ALTER TABLE handlers DROP COLUMN visibility;
@@ -0,0 +1,34 @@
+-- public handlers can be run by anyone, api_key handlers need one of their
+-- invocation keys and jwt handlers need an API token of their owner that has
+-- the invoke scope.
+ALTER TABLE handlers
+  ADD COLUMN visibility VARCHAR NOT NULL DEFAULT 'public'
+  CHECK (visibility IN ('public', 'api_key', 'jwt'));
+
+-- Existing tokens keep their access to the API, but can't invoke handlers.
+ALTER TABLE tokens
+  ADD COLUMN scopes VARCHAR NOT NULL DEFAULT 'api';
+
+-- Only a hash of each key is kept, the key itself is shown once when it is
+-- created.
+CREATE TABLE IF NOT EXISTS invocation_keys
+  ( id UUID DEFAULT uuid_generate_v4() NOT NULL
+  , handler_id UUID NOT NULL
+  , name VARCHAR NOT NULL
+  , key_hash VARCHAR NOT NULL UNIQUE
+  , created_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , deleted_at TIMESTAMP
+  , PRIMARY KEY (id)
+  , CONSTRAINT fk_handler_id
+    FOREIGN KEY (handler_id)
+    REFERENCES handlers(id)
+    ON DELETE CASCADE
+  );
+
+CREATE INDEX invocation_keys_handler_id_idx ON invocation_keys(handler_id);
+
+CREATE TRIGGER set_timestamp_invocation_keys
+  BEFORE UPDATE ON invocation_keys
+  FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
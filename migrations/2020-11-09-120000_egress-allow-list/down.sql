@@ -0,0 +1,2 @@
+DROP TABLE execution_http_requests;
+DROP TABLE handler_allowed_hosts;
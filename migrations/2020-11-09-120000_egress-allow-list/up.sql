@@ -0,0 +1,45 @@
+-- Hosts a handler may make HTTP requests to through the executor. A host
+-- starting with "*." matches all of its subdomains.
+CREATE TABLE IF NOT EXISTS handler_allowed_hosts
+  ( handler_id UUID NOT NULL
+  , host VARCHAR NOT NULL
+  , created_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , PRIMARY KEY (handler_id, host)
+  , CONSTRAINT fk_handler_id
+    FOREIGN KEY (handler_id)
+    REFERENCES handlers(id)
+    ON DELETE CASCADE
+  );
+
+CREATE TRIGGER set_timestamp_handler_allowed_hosts
+  BEFORE UPDATE ON handler_allowed_hosts
+  FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
+
+-- Every outbound request a guest made, including the ones that were refused.
+CREATE TABLE IF NOT EXISTS execution_http_requests
+  ( id UUID DEFAULT uuid_generate_v4() NOT NULL
+  , execution_id UUID NOT NULL
+  , method VARCHAR NOT NULL
+  , url VARCHAR NOT NULL
+  , status INTEGER
+  , response_bytes BIGINT NOT NULL DEFAULT 0
+  , duration_ms INTEGER NOT NULL
+  , error VARCHAR
+  , created_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , PRIMARY KEY (id)
+  , CONSTRAINT fk_execution_id
+    FOREIGN KEY (execution_id)
+    REFERENCES executions(id)
+    ON DELETE CASCADE
+  );
+
+CREATE INDEX execution_http_requests_execution_id_idx
+  ON execution_http_requests(execution_id);
+
+CREATE TRIGGER set_timestamp_execution_http_requests
+  BEFORE UPDATE ON execution_http_requests
+  FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
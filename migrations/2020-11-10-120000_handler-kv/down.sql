@@ -0,0 +1 @@
+DROP TABLE handler_kv;
@@ -0,0 +1,22 @@
+-- Values stored by handlers for themselves. Keys with an expires_at in the past
+-- are treated as missing and cleaned up lazily.
+CREATE TABLE IF NOT EXISTS handler_kv
+  ( handler_id UUID NOT NULL
+  , key_name VARCHAR NOT NULL
+  , value BYTEA NOT NULL
+  , expires_at TIMESTAMP
+  , created_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , PRIMARY KEY (handler_id, key_name)
+  , CONSTRAINT fk_handler_id
+    FOREIGN KEY (handler_id)
+    REFERENCES handlers(id)
+    ON DELETE CASCADE
+  );
+
+CREATE INDEX handler_kv_expires_at_idx ON handler_kv(expires_at);
+
+CREATE TRIGGER set_timestamp_handler_kv
+  BEFORE UPDATE ON handler_kv
+  FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
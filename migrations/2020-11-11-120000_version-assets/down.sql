@@ -0,0 +1 @@
+ALTER TABLE handler_versions DROP COLUMN assets_url;
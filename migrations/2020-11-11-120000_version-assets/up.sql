@@ -0,0 +1,4 @@
+-- An optional tar archive of static files that is mounted read-only for the
+-- module of this version.
+ALTER TABLE handler_versions
+  ADD COLUMN assets_url VARCHAR;
@@ -0,0 +1,2 @@
+DROP TABLE execution_logs;
+ALTER TABLE executions DROP COLUMN stdout;
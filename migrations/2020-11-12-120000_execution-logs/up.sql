@@ -0,0 +1,28 @@
+ALTER TABLE executions
+  ADD COLUMN stdout VARCHAR;
+
+-- Every line a module wrote, with when the executor read it. stream is either
+-- 'stdout' or 'stderr'.
+CREATE TABLE IF NOT EXISTS execution_logs
+  ( id UUID DEFAULT uuid_generate_v4() NOT NULL
+  , execution_id UUID NOT NULL
+  , stream VARCHAR NOT NULL CHECK (stream IN ('stdout', 'stderr'))
+  , line_number INTEGER NOT NULL
+  , message VARCHAR NOT NULL
+  , logged_at TIMESTAMP NOT NULL
+  , created_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+  , PRIMARY KEY (id)
+  , CONSTRAINT fk_execution_id
+    FOREIGN KEY (execution_id)
+    REFERENCES executions(id)
+    ON DELETE CASCADE
+  );
+
+CREATE INDEX execution_logs_execution_id_logged_at_idx
+  ON execution_logs(execution_id, logged_at);
+
+CREATE TRIGGER set_timestamp_execution_logs
+  BEFORE UPDATE ON execution_logs
+  FOR EACH ROW
+    EXECUTE PROCEDURE trigger_set_timestamp();
@@ -0,0 +1,2 @@
+DROP INDEX executions_trace_id_idx;
+ALTER TABLE executions DROP COLUMN trace_id;
@@ -0,0 +1,7 @@
+-- The W3C trace ID of the request that caused an execution, so it can be
+-- found in the tracing backend.
+ALTER TABLE executions
+  ADD COLUMN trace_id VARCHAR;
+
+CREATE INDEX executions_trace_id_idx
+  ON executions(trace_id);
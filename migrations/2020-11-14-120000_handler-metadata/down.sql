@@ -0,0 +1,4 @@
+ALTER TABLE handlers
+  DROP COLUMN description,
+  DROP COLUMN tags,
+  DROP COLUMN timeout_ms;
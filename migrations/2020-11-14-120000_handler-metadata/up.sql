@@ -0,0 +1,6 @@
+-- Free-form details about a handler, and how long its executions may run in
+-- milliseconds (no limit if NULL).
+ALTER TABLE handlers
+  ADD COLUMN description VARCHAR NOT NULL DEFAULT '',
+  ADD COLUMN tags VARCHAR[] NOT NULL DEFAULT '{}',
+  ADD COLUMN timeout_ms INTEGER CHECK (timeout_ms > 0);
@@ -0,0 +1,3 @@
+DROP INDEX handlers_tags;
+DROP INDEX handlers_search;
+DROP FUNCTION handler_search_document(VARCHAR, VARCHAR, VARCHAR[]);
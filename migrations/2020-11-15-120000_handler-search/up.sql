@@ -0,0 +1,18 @@
+-- What handlers are searched by: their name and tags weigh more than their
+-- description. The 'simple' configuration doesn't stem words, handler names
+-- are made up anyway. array_to_string isn't marked immutable, but it is for
+-- text arrays, which is what an index needs.
+CREATE FUNCTION handler_search_document(name VARCHAR, description VARCHAR, tags VARCHAR[])
+RETURNS tsvector
+LANGUAGE SQL
+IMMUTABLE
+AS $$
+  SELECT setweight(to_tsvector('simple', name), 'A')
+      || setweight(to_tsvector('simple', array_to_string(tags, ' ')), 'A')
+      || setweight(to_tsvector('simple', description), 'B')
+$$;
+
+CREATE INDEX handlers_search ON handlers
+  USING GIN (handler_search_document(human_name, description, tags));
+
+CREATE INDEX handlers_tags ON handlers USING GIN (tags);
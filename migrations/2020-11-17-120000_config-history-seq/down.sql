@@ -0,0 +1,5 @@
+DROP INDEX handler_config_history_handler_id_created_at_seq_idx;
+CREATE INDEX handler_config_history_handler_id_created_at_idx
+  ON handler_config_history(handler_id, created_at);
+
+ALTER TABLE handler_config_history DROP COLUMN seq;
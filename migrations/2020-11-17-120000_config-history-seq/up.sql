@@ -0,0 +1,27 @@
+-- Changes made in one transaction share NOW(), so created_at alone can't tell
+-- them apart. seq keeps the order they were written in.
+ALTER TABLE handler_config_history ADD COLUMN seq BIGINT;
+
+UPDATE handler_config_history h
+   SET seq = o.n
+  FROM (
+    SELECT id, row_number() OVER (ORDER BY created_at, id) AS n
+      FROM handler_config_history
+  ) o
+ WHERE o.id = h.id;
+
+CREATE SEQUENCE handler_config_history_seq_seq
+  OWNED BY handler_config_history.seq;
+SELECT setval(
+  'handler_config_history_seq_seq',
+  COALESCE((SELECT max(seq) FROM handler_config_history), 0) + 1,
+  false
+);
+
+ALTER TABLE handler_config_history
+  ALTER COLUMN seq SET DEFAULT nextval('handler_config_history_seq_seq'),
+  ALTER COLUMN seq SET NOT NULL;
+
+DROP INDEX handler_config_history_handler_id_created_at_idx;
+CREATE INDEX handler_config_history_handler_id_created_at_seq_idx
+  ON handler_config_history(handler_id, created_at, seq);
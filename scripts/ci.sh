@@ -0,0 +1,13 @@
+#!/usr/bin/env bash
+
+# What CI runs: the default build, and the one with OTLP export so the
+# optional tracing crates keep compiling against each other.
+
+set -e
+set -x
+
+for features in "" "otlp"; do
+  cargo build --workspace --features "$features"
+  cargo clippy --workspace --all-targets --features "$features" -- -D warnings
+  cargo test --workspace --features "$features"
+done
@@ -0,0 +1,19 @@
+use super::{Error, Result};
+use crate::{gc, models, MainDatabase};
+use rocket_contrib::json::Json;
+
+/// Deletes module blobs nothing refers to anymore. Pass `dry_run=true` to only
+/// see what would be deleted.
+#[post("/admin/gc?<dry_run>")]
+#[instrument(skip(conn), err)]
+pub fn collect_garbage(
+    user: models::User,
+    dry_run: Option<bool>,
+    conn: MainDatabase,
+) -> Result<Json<gc::Report>> {
+    if !user.is_admin {
+        return Err(Error::LackPermissions);
+    }
+
+    Ok(Json(gc::collect(&*conn, dry_run.unwrap_or(false))?))
+}
@@ -0,0 +1,116 @@
+use super::{
+    owned_handler,
+    page::{self, Cursor, Page, Sort},
+    Error, Result,
+};
+use crate::{models, routing, schema, MainDatabase};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use rocket::request::LenientForm;
+use rocket_contrib::{json::Json, uuid::Uuid};
+use serde::Deserialize;
+
+#[derive(Debug, Eq, PartialEq, Deserialize)]
+pub struct New {
+    /// A name or a custom path such as `team/billing/webhook`.
+    pub name: String,
+}
+
+#[post("/handler/<hdl_id>/alias", format = "json", data = "<input>")]
+#[instrument(skip(conn), err)]
+pub fn create(
+    user: models::User,
+    hdl_id: Uuid,
+    input: Json<New>,
+    conn: MainDatabase,
+) -> Result<Json<models::HandlerAlias>> {
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let input = input.into_inner();
+
+    if !routing::valid_path(&input.name) {
+        return Err(Error::BadRequest(format!("invalid alias {:?}", input.name)));
+    }
+
+    let alias = conn.transaction(|| {
+        if routing::name_taken(&*conn, &input.name)? {
+            return Err(Error::Conflict(format!(
+                "name {:?} is already taken",
+                input.name
+            )));
+        }
+
+        Ok(diesel::insert_into(schema::handler_aliases::table)
+            .values(&models::NewHandlerAlias {
+                name: input.name.clone(),
+                handler_id: handler.id,
+                expires_at: None,
+            })
+            .get_result::<models::HandlerAlias>(&*conn)?)
+    })?;
+
+    info!(
+        handler.id = &handler.id.to_string()[..],
+        alias = &alias.name[..],
+        "created alias"
+    );
+
+    Ok(Json(alias))
+}
+
+#[get("/handler/<hdl_id>/alias?<params..>")]
+#[instrument(skip(conn), err)]
+pub fn list(
+    user: models::User,
+    hdl_id: Uuid,
+    params: LenientForm<page::Params>,
+    conn: MainDatabase,
+) -> Result<Page<models::HandlerAlias>> {
+    use schema::handler_aliases::dsl::{created_at, handler_aliases, handler_id, name};
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let page = params
+        .into_inner()
+        .query(&[Sort::Name, Sort::CreatedAt], true)?;
+    let filtered = || {
+        let mut query = handler_aliases
+            .filter(handler_id.eq(handler.id))
+            .into_boxed();
+        if let Some(pattern) = page.prefix_pattern() {
+            query = query.filter(name.like(pattern));
+        }
+        query
+    };
+
+    // Aliases are keyed by their name, so it breaks ties.
+    let total = filtered().count().get_result::<i64>(&*conn)?;
+    let rows = paginate!(filtered(), &page, name => String, {
+        Sort::Name => name => String,
+        Sort::CreatedAt => created_at => NaiveDateTime,
+    })
+    .load::<models::HandlerAlias>(&*conn)?;
+
+    Ok(Page::new(rows, &page, total, |a| match page.sort {
+        Sort::Name => Cursor::new(&a.name, &a.name),
+        Sort::CreatedAt => Cursor::new(&a.created_at, &a.name),
+    }))
+}
+
+#[delete("/handler/<hdl_id>/alias?<name>")]
+#[instrument(skip(conn), err)]
+pub fn delete(user: models::User, hdl_id: Uuid, name: String, conn: MainDatabase) -> Result {
+    use schema::handler_aliases::dsl::{handler_aliases, handler_id};
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    let deleted = diesel::delete(
+        handler_aliases
+            .find(&name)
+            .filter(handler_id.eq(handler.id)),
+    )
+    .execute(&*conn)
+    .map_err(Error::Database)?;
+
+    if deleted == 0 {
+        return Err(Error::NotFound(format!("alias {:?}", name)));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,293 @@
+use super::{
+    handler::Cfg,
+    owned_handler,
+    page::{self, Cursor, Page, Sort},
+    Error, Result,
+};
+use crate::{models, schema, secrets, MainDatabase};
+use chrono::NaiveDateTime;
+use diesel::{pg::PgConnection, prelude::*};
+use rocket::request::LenientForm;
+use rocket_contrib::{json::Json, uuid::Uuid};
+use serde::Deserialize;
+
+#[derive(Debug, Eq, PartialEq, Deserialize)]
+pub struct New {
+    pub name: String,
+}
+
+fn owned_group(
+    conn: &PgConnection,
+    user: &models::User,
+    uuid: uuid::Uuid,
+) -> Result<models::ConfigGroup> {
+    use schema::config_groups::dsl::config_groups;
+
+    let group = config_groups
+        .find(uuid)
+        .get_result::<models::ConfigGroup>(conn)
+        .map_err(Error::Database)?;
+
+    if group.user_id != user.id {
+        return Err(Error::LackPermissions);
+    }
+
+    Ok(group)
+}
+
+#[post("/config_group", format = "json", data = "<input>")]
+#[instrument(skip(conn), err)]
+pub fn create(
+    user: models::User,
+    input: Json<New>,
+    conn: MainDatabase,
+) -> Result<Json<models::ConfigGroup>> {
+    let group = diesel::insert_into(schema::config_groups::table)
+        .values(&models::NewConfigGroup {
+            user_id: user.id.clone(),
+            name: input.into_inner().name,
+        })
+        .get_result::<models::ConfigGroup>(&*conn)
+        .map_err(Error::Database)?;
+
+    info!(
+        group.id = &group.id.to_string()[..],
+        group.name = &group.name[..],
+        "created config group"
+    );
+
+    Ok(Json(group))
+}
+
+#[get("/config_group?<params..>")]
+#[instrument(skip(conn), err)]
+pub fn list(
+    user: models::User,
+    params: LenientForm<page::Params>,
+    conn: MainDatabase,
+) -> Result<Page<models::ConfigGroup>> {
+    use schema::config_groups::dsl::{config_groups, created_at, id, name, user_id};
+    let page = params
+        .into_inner()
+        .query(&[Sort::CreatedAt, Sort::Name], true)?;
+    let filtered = || {
+        let mut query = config_groups.filter(user_id.eq(user.id)).into_boxed();
+        if let Some(pattern) = page.prefix_pattern() {
+            query = query.filter(name.like(pattern));
+        }
+        query
+    };
+
+    let total = filtered().count().get_result::<i64>(&*conn)?;
+    let rows = paginate!(filtered(), &page, id => uuid::Uuid, {
+        Sort::CreatedAt => created_at => NaiveDateTime,
+        Sort::Name => name => String,
+    })
+    .load::<models::ConfigGroup>(&*conn)?;
+
+    Ok(Page::new(rows, &page, total, |g| match page.sort {
+        Sort::Name => Cursor::new(&g.name, &g.id),
+        Sort::CreatedAt => Cursor::new(&g.created_at, &g.id),
+    }))
+}
+
+#[get("/config_group/<grp_id>")]
+#[instrument(skip(conn), err)]
+pub fn get(
+    user: models::User,
+    grp_id: Uuid,
+    conn: MainDatabase,
+) -> Result<Json<models::ConfigGroup>> {
+    Ok(Json(owned_group(&*conn, &user, grp_id.into_inner())?))
+}
+
+#[delete("/config_group/<grp_id>")]
+#[instrument(skip(conn), err)]
+pub fn delete(user: models::User, grp_id: Uuid, conn: MainDatabase) -> Result {
+    use schema::config_groups::dsl::config_groups;
+    let group = owned_group(&*conn, &user, grp_id.into_inner())?;
+
+    // Entries and handler attachments are removed by the database.
+    diesel::delete(config_groups.find(group.id))
+        .execute(&*conn)
+        .map_err(Error::Database)?;
+
+    info!(group.id = &group.id.to_string()[..], "deleted config group");
+
+    Ok(())
+}
+
+#[get("/config_group/<grp_id>/config")]
+#[instrument(skip(conn), err)]
+pub fn get_config(
+    user: models::User,
+    grp_id: Uuid,
+    conn: MainDatabase,
+) -> Result<Json<Vec<models::ConfigGroupEntry>>> {
+    use schema::config_group_entries::dsl::{config_group_entries, group_id};
+    let group = owned_group(&*conn, &user, grp_id.into_inner())?;
+
+    let config = config_group_entries
+        .filter(group_id.eq(group.id))
+        .load::<models::ConfigGroupEntry>(&*conn)
+        .map_err(Error::Database)?
+        .into_iter()
+        .map(models::ConfigGroupEntry::masked)
+        .collect();
+
+    Ok(Json(config))
+}
+
+#[post("/config_group/<grp_id>/config", format = "json", data = "<cfg>")]
+#[instrument(skip(conn, cfg), err)]
+pub fn set_config(
+    user: models::User,
+    grp_id: Uuid,
+    cfg: Json<Vec<Cfg>>,
+    conn: MainDatabase,
+) -> Result {
+    use schema::config_group_entries::dsl::{
+        config_group_entries, group_id, is_secret, key_name, value_contents,
+    };
+    let group = owned_group(&*conn, &user, grp_id.into_inner())?;
+
+    let cfg = cfg
+        .into_inner()
+        .into_iter()
+        .map(|kv| {
+            Ok(models::NewConfigGroupEntry {
+                value_contents: if kv.secret {
+                    secrets::seal(secrets::Owner::Group(group.id), &kv.key, &kv.value)?
+                } else {
+                    kv.value
+                },
+                key_name: kv.key,
+                group_id: group.id.clone(),
+                is_secret: kv.secret,
+            })
+        })
+        .collect::<Result<Vec<models::NewConfigGroupEntry>>>()?;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        for kv in cfg.iter() {
+            diesel::insert_into(config_group_entries)
+                .values(kv)
+                .on_conflict((key_name, group_id))
+                .do_update()
+                .set((
+                    value_contents.eq(&kv.value_contents),
+                    is_secret.eq(kv.is_secret),
+                ))
+                .execute(&*conn)?;
+        }
+
+        Ok(())
+    })
+    .map_err(Error::Database)?;
+
+    for kv in cfg.iter() {
+        info!(name = kv.key_name.as_str(), "group config set");
+    }
+
+    Ok(())
+}
+
+#[delete("/config_group/<grp_id>/config/<key>")]
+#[instrument(skip(conn), err)]
+pub fn delete_config(user: models::User, grp_id: Uuid, key: String, conn: MainDatabase) -> Result {
+    use schema::config_group_entries::dsl::{config_group_entries, group_id, key_name};
+    let group = owned_group(&*conn, &user, grp_id.into_inner())?;
+
+    diesel::delete(
+        config_group_entries
+            .filter(group_id.eq(group.id))
+            .filter(key_name.eq(&key)),
+    )
+    .execute(&*conn)
+    .map_err(Error::Database)?;
+
+    Ok(())
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize)]
+pub struct Attach {
+    /// Groups with a higher priority override groups with a lower one.
+    #[serde(default)]
+    pub priority: i32,
+}
+
+#[put(
+    "/handler/<hdl_id>/config_group/<grp_id>",
+    format = "json",
+    data = "<input>"
+)]
+#[instrument(skip(conn), err)]
+pub fn attach(
+    user: models::User,
+    hdl_id: Uuid,
+    grp_id: Uuid,
+    input: Json<Attach>,
+    conn: MainDatabase,
+) -> Result<Json<models::HandlerConfigGroup>> {
+    use schema::handler_config_groups::dsl::{
+        group_id, handler_config_groups, handler_id, priority,
+    };
+
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    let group = owned_group(&*conn, &user, grp_id.into_inner())?;
+
+    let attached = diesel::insert_into(handler_config_groups)
+        .values(&models::NewHandlerConfigGroup {
+            handler_id: handler.id,
+            group_id: group.id,
+            priority: input.priority,
+        })
+        .on_conflict((handler_id, group_id))
+        .do_update()
+        .set(priority.eq(input.priority))
+        .get_result::<models::HandlerConfigGroup>(&*conn)
+        .map_err(Error::Database)?;
+
+    info!(
+        handler.id = &handler.id.to_string()[..],
+        group.id = &group.id.to_string()[..],
+        "attached config group"
+    );
+
+    Ok(Json(attached))
+}
+
+#[delete("/handler/<hdl_id>/config_group/<grp_id>")]
+#[instrument(skip(conn), err)]
+pub fn detach(user: models::User, hdl_id: Uuid, grp_id: Uuid, conn: MainDatabase) -> Result {
+    use schema::handler_config_groups::dsl::handler_config_groups;
+
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    diesel::delete(handler_config_groups.find((handler.id, grp_id.into_inner())))
+        .execute(&*conn)
+        .map_err(Error::Database)?;
+
+    Ok(())
+}
+
+#[get("/handler/<hdl_id>/config_group")]
+#[instrument(skip(conn), err)]
+pub fn list_attached(
+    user: models::User,
+    hdl_id: Uuid,
+    conn: MainDatabase,
+) -> Result<Json<Vec<models::HandlerConfigGroup>>> {
+    use schema::handler_config_groups::dsl::{handler_config_groups, handler_id, priority};
+
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    Ok(Json(
+        handler_config_groups
+            .filter(handler_id.eq(handler.id))
+            .order(priority.desc())
+            .load::<models::HandlerConfigGroup>(&*conn)
+            .map_err(Error::Database)?,
+    ))
+}
@@ -0,0 +1,218 @@
+use super::{
+    owned_handler,
+    page::{self, Cursor, Page, Sort},
+    Error, Result,
+};
+use crate::{domains, models, schema, MainDatabase};
+use chrono::prelude::*;
+use diesel::{pg::PgConnection, prelude::*};
+use rocket::{request::LenientForm, State};
+use rocket_contrib::{json::Json, uuid::Uuid};
+use serde::{Deserialize, Serialize};
+
+fn find_domain(
+    conn: &PgConnection,
+    hdl: &models::Handler,
+    name: &str,
+) -> Result<models::CustomDomain> {
+    use schema::custom_domains::dsl::{custom_domains, domain, handler_id};
+
+    custom_domains
+        .filter(handler_id.eq(hdl.id))
+        .filter(domain.eq(domains::normalize(name)))
+        .first::<models::CustomDomain>(conn)
+        .optional()?
+        .ok_or_else(|| Error::NotFound(format!("domain {:?}", name)))
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize)]
+pub struct New {
+    pub domain: String,
+}
+
+/// The DNS record that proves ownership of a domain.
+#[derive(Debug, Clone, Serialize)]
+pub struct Challenge {
+    pub record_type: &'static str,
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Domain {
+    #[serde(flatten)]
+    pub domain: models::CustomDomain,
+    pub challenge: Challenge,
+}
+
+impl From<models::CustomDomain> for Domain {
+    fn from(domain: models::CustomDomain) -> Self {
+        let challenge = Challenge {
+            record_type: "TXT",
+            name: domains::challenge_name(&domain.domain),
+            value: format!(
+                "{}{}",
+                domains::CHALLENGE_VALUE_PREFIX,
+                domain.verification_token
+            ),
+        };
+
+        Domain { domain, challenge }
+    }
+}
+
+#[post("/handler/<hdl_id>/domain", format = "json", data = "<input>")]
+#[instrument(skip(conn), err)]
+pub fn create(
+    user: models::User,
+    hdl_id: Uuid,
+    input: Json<New>,
+    conn: MainDatabase,
+) -> Result<Json<Domain>> {
+    use schema::custom_domains::dsl::{custom_domains, domain};
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let name = domains::normalize(&input.domain);
+
+    if !domains::valid_domain(&name) {
+        return Err(Error::BadRequest(format!("invalid domain {:?}", name)));
+    }
+    if let Some(wildcard) = domains::WILDCARD_DOMAIN.as_ref() {
+        if name == *wildcard || name.ends_with(&format!(".{}", wildcard)) {
+            return Err(Error::BadRequest(format!(
+                "subdomains of {} are assigned by handler name",
+                wildcard
+            )));
+        }
+    }
+
+    let dom = conn.transaction(|| {
+        // Unverified claims don't block anyone else, otherwise anybody could
+        // squat on a domain they don't own.
+        if let Some(existing) = custom_domains
+            .filter(domain.eq(&name))
+            .for_update()
+            .first::<models::CustomDomain>(&*conn)
+            .optional()?
+        {
+            if existing.verified_at.is_some() || existing.handler_id == handler.id {
+                return Err(Error::Conflict(format!(
+                    "domain {:?} is already in use",
+                    name
+                )));
+            }
+            diesel::delete(custom_domains.find(existing.id)).execute(&*conn)?;
+        }
+
+        Ok(diesel::insert_into(custom_domains)
+            .values(&models::NewCustomDomain {
+                handler_id: handler.id,
+                domain: name.clone(),
+                verification_token: domains::new_token(),
+            })
+            .get_result::<models::CustomDomain>(&*conn)?)
+    })?;
+
+    info!(
+        handler.id = &handler.id.to_string()[..],
+        domain = &dom.domain[..],
+        "added custom domain"
+    );
+
+    Ok(Json(dom.into()))
+}
+
+#[get("/handler/<hdl_id>/domain?<params..>")]
+#[instrument(skip(conn), err)]
+pub fn list(
+    user: models::User,
+    hdl_id: Uuid,
+    params: LenientForm<page::Params>,
+    conn: MainDatabase,
+) -> Result<Page<Domain>> {
+    use schema::custom_domains::dsl::{created_at, custom_domains, domain, handler_id, id};
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let page = params
+        .into_inner()
+        .query(&[Sort::Name, Sort::CreatedAt], true)?;
+    let filtered = || {
+        let mut query = custom_domains
+            .filter(handler_id.eq(handler.id))
+            .into_boxed();
+        if let Some(pattern) = page.prefix_pattern() {
+            query = query.filter(domain.like(pattern));
+        }
+        query
+    };
+
+    let total = filtered().count().get_result::<i64>(&*conn)?;
+    let rows = paginate!(filtered(), &page, id => uuid::Uuid, {
+        Sort::Name => domain => String,
+        Sort::CreatedAt => created_at => NaiveDateTime,
+    })
+    .load::<models::CustomDomain>(&*conn)?;
+
+    Ok(Page::new(rows, &page, total, |d| match page.sort {
+        Sort::Name => Cursor::new(&d.domain, &d.id),
+        Sort::CreatedAt => Cursor::new(&d.created_at, &d.id),
+    })
+    .map(Domain::from))
+}
+
+#[post("/handler/<hdl_id>/domain/<name>/verify")]
+#[instrument(skip(conn, resolver), err)]
+pub fn verify(
+    user: models::User,
+    hdl_id: Uuid,
+    name: String,
+    resolver: State<domains::Resolver>,
+    conn: MainDatabase,
+) -> Result<Json<Domain>> {
+    use schema::custom_domains::dsl::{custom_domains, verified_at};
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let dom = find_domain(&*conn, &handler, &name)?;
+
+    if dom.verified_at.is_some() {
+        return Ok(Json(dom.into()));
+    }
+
+    if !resolver.verify(&dom) {
+        let dom = Domain::from(dom);
+        return Err(Error::BadRequest(format!(
+            "TXT record {} does not contain {:?} yet",
+            dom.challenge.name, dom.challenge.value
+        )));
+    }
+
+    let dom = diesel::update(custom_domains.find(dom.id))
+        .set(verified_at.eq(Some(Utc::now().naive_utc())))
+        .get_result::<models::CustomDomain>(&*conn)
+        .map_err(Error::Database)?;
+
+    info!(
+        handler.id = &handler.id.to_string()[..],
+        domain = &dom.domain[..],
+        "verified custom domain"
+    );
+
+    Ok(Json(dom.into()))
+}
+
+#[delete("/handler/<hdl_id>/domain/<name>")]
+#[instrument(skip(conn), err)]
+pub fn delete(user: models::User, hdl_id: Uuid, name: String, conn: MainDatabase) -> Result {
+    use schema::custom_domains::dsl::custom_domains;
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let dom = find_domain(&*conn, &handler, &name)?;
+
+    diesel::delete(custom_domains.find(dom.id))
+        .execute(&*conn)
+        .map_err(Error::Database)?;
+
+    info!(
+        handler.id = &handler.id.to_string()[..],
+        domain = &dom.domain[..],
+        "removed custom domain"
+    );
+
+    Ok(())
+}
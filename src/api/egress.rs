@@ -0,0 +1,70 @@
+use super::{owned_handler, Error, Result};
+use crate::{egress, models, schema, MainDatabase};
+use diesel::prelude::*;
+use rocket_contrib::{json::Json, uuid::Uuid};
+use serde::{Deserialize, Serialize};
+
+/// The hosts a handler may make HTTP requests to.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AllowList {
+    pub hosts: Vec<String>,
+}
+
+#[get("/handler/<hdl_id>/egress")]
+#[instrument(skip(conn), err)]
+pub fn get(user: models::User, hdl_id: Uuid, conn: MainDatabase) -> Result<Json<AllowList>> {
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    Ok(Json(AllowList {
+        hosts: egress::allow_list(&*conn, handler.id)?,
+    }))
+}
+
+#[put("/handler/<hdl_id>/egress", format = "json", data = "<input>")]
+#[instrument(skip(conn), err)]
+pub fn set(
+    user: models::User,
+    hdl_id: Uuid,
+    input: Json<AllowList>,
+    conn: MainDatabase,
+) -> Result<Json<AllowList>> {
+    use schema::handler_allowed_hosts::dsl::{handler_allowed_hosts, handler_id};
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    let mut hosts: Vec<String> = input
+        .into_inner()
+        .hosts
+        .into_iter()
+        .map(|host| host.trim().to_ascii_lowercase())
+        .collect();
+    hosts.sort();
+    hosts.dedup();
+    if let Some(host) = hosts.iter().find(|host| !egress::valid_host(host)) {
+        return Err(Error::BadRequest(format!("invalid host {:?}", host)));
+    }
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::delete(handler_allowed_hosts.filter(handler_id.eq(handler.id))).execute(&*conn)?;
+        diesel::insert_into(handler_allowed_hosts)
+            .values(
+                &hosts
+                    .iter()
+                    .map(|host| models::NewHandlerAllowedHost {
+                        handler_id: handler.id,
+                        host: host.clone(),
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .execute(&*conn)?;
+        Ok(())
+    })
+    .map_err(Error::Database)?;
+
+    info!(
+        handler.id = &handler.id.to_string()[..],
+        hosts = hosts.len(),
+        "set egress allow-list"
+    );
+
+    Ok(Json(AllowList { hosts }))
+}
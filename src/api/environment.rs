@@ -0,0 +1,471 @@
+use super::{
+    handler::Cfg,
+    owned_handler,
+    page::{self, Cursor, Page, Sort},
+    Error, Result,
+};
+use crate::{config, models, schema, secrets, MainDatabase};
+use chrono::NaiveDateTime;
+use diesel::{pg::PgConnection, prelude::*};
+use rocket::request::LenientForm;
+use rocket_contrib::{json::Json, uuid::Uuid};
+use serde::Deserialize;
+
+#[derive(Debug, Eq, PartialEq, Deserialize)]
+pub struct New {
+    pub name: String,
+    /// The handler version to start with. Defaults to the current version of
+    /// the handler.
+    pub version_id: Option<uuid::Uuid>,
+}
+
+/// Environment names end up in invocation URLs, so they are kept simple.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+fn find_environment(
+    conn: &PgConnection,
+    hdl: &models::Handler,
+    env_name: &str,
+) -> Result<models::HandlerEnvironment> {
+    use schema::handler_environments::dsl::{handler_environments, handler_id, name};
+
+    handler_environments
+        .filter(handler_id.eq(hdl.id))
+        .filter(name.eq(env_name))
+        .first::<models::HandlerEnvironment>(conn)
+        .map_err(Error::Database)
+}
+
+fn find_version(
+    conn: &PgConnection,
+    hdl: &models::Handler,
+    version: uuid::Uuid,
+) -> Result<models::HandlerVersion> {
+    use schema::handler_versions::dsl::handler_versions;
+
+    let version = handler_versions
+        .find(version)
+        .get_result::<models::HandlerVersion>(conn)
+        .map_err(Error::Database)?;
+
+    if version.handler_id != hdl.id {
+        return Err(Error::BadRequest(format!(
+            "version {} does not belong to this handler",
+            version.id
+        )));
+    }
+
+    Ok(version)
+}
+
+#[post("/handler/<hdl_id>/environment", format = "json", data = "<input>")]
+#[instrument(skip(conn), err)]
+pub fn create(
+    user: models::User,
+    hdl_id: Uuid,
+    input: Json<New>,
+    conn: MainDatabase,
+) -> Result<Json<models::HandlerEnvironment>> {
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let input = input.into_inner();
+
+    if !valid_name(&input.name) {
+        return Err(Error::BadRequest(format!(
+            "environment name {:?} must only contain a-z, 0-9 and -",
+            input.name
+        )));
+    }
+
+    let (version, version_id) = match input.version_id {
+        Some(version) => {
+            let version = find_version(&*conn, &handler, version)?;
+            (Some(version.module_url), Some(version.id))
+        }
+        None => (handler.current_version.clone(), handler.current_version_id),
+    };
+
+    let env = diesel::insert_into(schema::handler_environments::table)
+        .values(&models::NewHandlerEnvironment {
+            handler_id: handler.id,
+            name: input.name,
+            current_version: version,
+            version_id,
+        })
+        .get_result::<models::HandlerEnvironment>(&*conn)
+        .map_err(Error::Database)?;
+
+    info!(
+        handler.id = &handler.id.to_string()[..],
+        environment = &env.name[..],
+        "created environment"
+    );
+
+    Ok(Json(env))
+}
+
+#[get("/handler/<hdl_id>/environment?<params..>")]
+#[instrument(skip(conn), err)]
+pub fn list(
+    user: models::User,
+    hdl_id: Uuid,
+    params: LenientForm<page::Params>,
+    conn: MainDatabase,
+) -> Result<Page<models::HandlerEnvironment>> {
+    use schema::handler_environments::dsl::{
+        created_at, handler_environments, handler_id, id, name,
+    };
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let page = params
+        .into_inner()
+        .query(&[Sort::Name, Sort::CreatedAt], true)?;
+    let filtered = || {
+        let mut query = handler_environments
+            .filter(handler_id.eq(handler.id))
+            .into_boxed();
+        if let Some(pattern) = page.prefix_pattern() {
+            query = query.filter(name.like(pattern));
+        }
+        query
+    };
+
+    let total = filtered().count().get_result::<i64>(&*conn)?;
+    let rows = paginate!(filtered(), &page, id => uuid::Uuid, {
+        Sort::Name => name => String,
+        Sort::CreatedAt => created_at => NaiveDateTime,
+    })
+    .load::<models::HandlerEnvironment>(&*conn)?;
+
+    Ok(Page::new(rows, &page, total, |e| match page.sort {
+        Sort::Name => Cursor::new(&e.name, &e.id),
+        Sort::CreatedAt => Cursor::new(&e.created_at, &e.id),
+    }))
+}
+
+#[get("/handler/<hdl_id>/environment/<env_name>")]
+#[instrument(skip(conn), err)]
+pub fn get(
+    user: models::User,
+    hdl_id: Uuid,
+    env_name: String,
+    conn: MainDatabase,
+) -> Result<Json<models::HandlerEnvironment>> {
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    Ok(Json(find_environment(&*conn, &handler, &env_name)?))
+}
+
+#[delete("/handler/<hdl_id>/environment/<env_name>")]
+#[instrument(skip(conn), err)]
+pub fn delete(user: models::User, hdl_id: Uuid, env_name: String, conn: MainDatabase) -> Result {
+    use schema::handler_environments::dsl::handler_environments;
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let env = find_environment(&*conn, &handler, &env_name)?;
+
+    // Environment config is removed by the database.
+    diesel::delete(handler_environments.find(env.id))
+        .execute(&*conn)
+        .map_err(Error::Database)?;
+
+    info!(
+        handler.id = &handler.id.to_string()[..],
+        environment = &env.name[..],
+        "deleted environment"
+    );
+
+    Ok(())
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize)]
+pub struct SetVersion {
+    pub version_id: uuid::Uuid,
+}
+
+#[put(
+    "/handler/<hdl_id>/environment/<env_name>/version",
+    format = "json",
+    data = "<input>"
+)]
+#[instrument(skip(conn), err)]
+pub fn set_version(
+    user: models::User,
+    hdl_id: Uuid,
+    env_name: String,
+    input: Json<SetVersion>,
+    conn: MainDatabase,
+) -> Result<Json<models::HandlerEnvironment>> {
+    use schema::handler_environments::dsl::{current_version, handler_environments, version_id};
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let env = find_environment(&*conn, &handler, &env_name)?;
+    let version = find_version(&*conn, &handler, input.version_id)?;
+
+    let env = diesel::update(handler_environments.find(env.id))
+        .set((
+            current_version.eq(Some(version.module_url)),
+            version_id.eq(Some(version.id)),
+        ))
+        .get_result::<models::HandlerEnvironment>(&*conn)
+        .map_err(Error::Database)?;
+
+    info!(
+        environment = &env.name[..],
+        version = &version.id.to_string()[..],
+        "set environment version"
+    );
+
+    Ok(Json(env))
+}
+
+/// Points the `to` environment of a handler at the version `from` runs.
+fn copy_version(
+    conn: &PgConnection,
+    hdl: &models::Handler,
+    from: &str,
+    to: &str,
+) -> Result<models::HandlerEnvironment> {
+    use schema::handler_environments::dsl::{
+        current_version, handler_environments, handler_id, name, version_id,
+    };
+
+    // The source row is locked so that a concurrent change to it can't slip in
+    // between reading its version and writing it to the target.
+    conn.transaction(|| {
+        let source = handler_environments
+            .filter(handler_id.eq(hdl.id))
+            .filter(name.eq(from))
+            .for_update()
+            .first::<models::HandlerEnvironment>(conn)?;
+        let target = find_environment(conn, hdl, to)?;
+
+        Ok(diesel::update(handler_environments.find(target.id))
+            .set((
+                current_version.eq(source.current_version),
+                version_id.eq(source.version_id),
+            ))
+            .get_result::<models::HandlerEnvironment>(conn)?)
+    })
+}
+
+#[post("/handler/<hdl_id>/environment/<from>/promote/<to>")]
+#[instrument(skip(conn), err)]
+pub fn promote(
+    user: models::User,
+    hdl_id: Uuid,
+    from: String,
+    to: String,
+    conn: MainDatabase,
+) -> Result<Json<models::HandlerEnvironment>> {
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let env = copy_version(&*conn, &handler, &from, &to)?;
+
+    info!(
+        handler.id = &handler.id.to_string()[..],
+        from = &from[..],
+        to = &to[..],
+        "promoted environment"
+    );
+
+    Ok(Json(env))
+}
+
+#[get("/handler/<hdl_id>/environment/<env_name>/config")]
+#[instrument(skip(conn), err)]
+pub fn get_config(
+    user: models::User,
+    hdl_id: Uuid,
+    env_name: String,
+    conn: MainDatabase,
+) -> Result<Json<Vec<models::EnvironmentConfig>>> {
+    use schema::environment_config::dsl::{environment_config, environment_id};
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let env = find_environment(&*conn, &handler, &env_name)?;
+
+    let config = environment_config
+        .filter(environment_id.eq(env.id))
+        .load::<models::EnvironmentConfig>(&*conn)
+        .map_err(Error::Database)?
+        .into_iter()
+        .map(models::EnvironmentConfig::masked)
+        .collect();
+
+    Ok(Json(config))
+}
+
+#[post(
+    "/handler/<hdl_id>/environment/<env_name>/config",
+    format = "json",
+    data = "<cfg>"
+)]
+#[instrument(skip(conn, cfg), err)]
+pub fn set_config(
+    user: models::User,
+    hdl_id: Uuid,
+    env_name: String,
+    cfg: Json<Vec<Cfg>>,
+    conn: MainDatabase,
+) -> Result {
+    use schema::environment_config::dsl::{
+        environment_config, environment_id, is_secret, key_name, value_contents,
+    };
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let env = find_environment(&*conn, &handler, &env_name)?;
+
+    let cfg = cfg
+        .into_inner()
+        .into_iter()
+        .map(|kv| {
+            Ok(models::NewEnvironmentConfig {
+                value_contents: if kv.secret {
+                    secrets::seal(secrets::Owner::Environment(env.id), &kv.key, &kv.value)?
+                } else {
+                    kv.value
+                },
+                key_name: kv.key,
+                environment_id: env.id,
+                is_secret: kv.secret,
+            })
+        })
+        .collect::<Result<Vec<models::NewEnvironmentConfig>>>()?;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        for kv in cfg.iter() {
+            diesel::insert_into(environment_config)
+                .values(kv)
+                .on_conflict((key_name, environment_id))
+                .do_update()
+                .set((
+                    value_contents.eq(&kv.value_contents),
+                    is_secret.eq(kv.is_secret),
+                ))
+                .execute(&*conn)?;
+        }
+
+        Ok(())
+    })
+    .map_err(Error::Database)?;
+
+    for kv in cfg.iter() {
+        info!(
+            name = kv.key_name.as_str(),
+            environment = &env.name[..],
+            "environment config set"
+        );
+    }
+
+    Ok(())
+}
+
+#[delete("/handler/<hdl_id>/environment/<env_name>/config/<key>")]
+#[instrument(skip(conn), err)]
+pub fn delete_config(
+    user: models::User,
+    hdl_id: Uuid,
+    env_name: String,
+    key: String,
+    conn: MainDatabase,
+) -> Result {
+    use schema::environment_config::dsl::{environment_config, environment_id, key_name};
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let env = find_environment(&*conn, &handler, &env_name)?;
+
+    diesel::delete(
+        environment_config
+            .filter(environment_id.eq(env.id))
+            .filter(key_name.eq(&key)),
+    )
+    .execute(&*conn)
+    .map_err(Error::Database)?;
+
+    Ok(())
+}
+
+#[get("/handler/<hdl_id>/environment/<env_name>/config/effective")]
+#[instrument(skip(conn), err)]
+pub fn get_effective_config(
+    user: models::User,
+    hdl_id: Uuid,
+    env_name: String,
+    conn: MainDatabase,
+) -> Result<Json<Vec<config::Entry>>> {
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let env = find_environment(&*conn, &handler, &env_name)?;
+
+    let config = config::effective_in(&*conn, &env)
+        .map_err(Error::Database)?
+        .into_iter()
+        .map(config::Entry::masked)
+        .collect();
+
+    Ok(Json(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn environment_names() {
+        for name in &["production", "staging-2", "pr-1234"] {
+            assert!(valid_name(name), "{}", name);
+        }
+        for name in &["", "Production", "under_score", "dot.ted", "sp ace"] {
+            assert!(!valid_name(name), "{:?}", name);
+        }
+    }
+
+    fn environment(
+        conn: &PgConnection,
+        hdl: &models::Handler,
+        env_name: &str,
+        version: &models::HandlerVersion,
+    ) -> models::HandlerEnvironment {
+        diesel::insert_into(schema::handler_environments::table)
+            .values(&models::NewHandlerEnvironment {
+                handler_id: hdl.id,
+                name: env_name.to_string(),
+                current_version: Some(version.module_url.clone()),
+                version_id: Some(version.id),
+            })
+            .get_result(conn)
+            .unwrap()
+    }
+
+    #[test]
+    #[ignore]
+    fn promotes_the_exact_version() {
+        let conn = testing::conn();
+        let user = testing::user(&conn);
+        let hdl = testing::handler(&conn, &user);
+        // Both versions share a module, promotion must keep them apart.
+        let old = testing::version(&conn, &hdl, "https://cdn.example.com/file/module.wasm");
+        let new = testing::version(&conn, &hdl, "https://cdn.example.com/file/module.wasm");
+        environment(&conn, &hdl, "staging", &new);
+        environment(&conn, &hdl, "production", &old);
+
+        let promoted = copy_version(&conn, &hdl, "staging", "production").unwrap();
+        assert_eq!(promoted.name, "production");
+        assert_eq!(promoted.version_id, Some(new.id));
+        assert_eq!(promoted.current_version, Some(new.module_url.clone()));
+
+        let staging = find_environment(&conn, &hdl, "staging").unwrap();
+        assert_eq!(staging.version_id, Some(new.id));
+    }
+
+    #[test]
+    #[ignore]
+    fn promotes_only_between_existing_environments() {
+        let conn = testing::conn();
+        let user = testing::user(&conn);
+        let hdl = testing::handler(&conn, &user);
+        let version = testing::version(&conn, &hdl, "https://cdn.example.com/file/module.wasm");
+        environment(&conn, &hdl, "staging", &version);
+
+        assert!(copy_version(&conn, &hdl, "staging", "production").is_err());
+        assert!(copy_version(&conn, &hdl, "nope", "staging").is_err());
+    }
+}
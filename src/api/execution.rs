@@ -0,0 +1,133 @@
+use super::{
+    owned_handler,
+    page::{self, Cursor, Page, Sort},
+    Error, Result,
+};
+use crate::{events, models, schema, MainDatabase};
+use chrono::NaiveDateTime;
+use diesel::{pg::PgConnection, prelude::*};
+use rocket::{
+    http::ContentType,
+    request::LenientForm,
+    response::{content::Content, Stream},
+};
+use rocket_contrib::{json::Json, uuid::Uuid};
+
+fn find_execution(
+    conn: &PgConnection,
+    hdl: &models::Handler,
+    uuid: uuid::Uuid,
+) -> Result<models::Execution> {
+    use schema::executions::dsl::executions;
+
+    let execution = executions
+        .find(uuid)
+        .get_result::<models::Execution>(conn)
+        .optional()?
+        .ok_or_else(|| Error::NotFound("execution".into()))?;
+
+    if execution.handler_id != hdl.id {
+        return Err(Error::NotFound("execution".into()));
+    }
+
+    Ok(execution)
+}
+
+#[get("/handler/<hdl_id>/execution?<params..>")]
+#[instrument(skip(conn), err)]
+pub fn list(
+    user: models::User,
+    hdl_id: Uuid,
+    params: LenientForm<page::Params>,
+    conn: MainDatabase,
+) -> Result<Page<models::Execution>> {
+    use schema::executions::dsl::{created_at, executions, handler_id, id};
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let page = params.into_inner().query(&[Sort::CreatedAt], false)?;
+
+    let filtered = || executions.filter(handler_id.eq(handler.id)).into_boxed();
+    let total = filtered().count().get_result::<i64>(&*conn)?;
+    let rows = paginate!(filtered(), &page, id => uuid::Uuid, {
+        Sort::CreatedAt => created_at => NaiveDateTime,
+    })
+    .load::<models::Execution>(&*conn)?;
+
+    Ok(Page::new(rows, &page, total, |e| {
+        Cursor::new(&e.created_at, &e.id)
+    }))
+}
+
+#[get("/handler/<hdl_id>/execution/<exec_id>")]
+#[instrument(skip(conn), err)]
+pub fn get(
+    user: models::User,
+    hdl_id: Uuid,
+    exec_id: Uuid,
+    conn: MainDatabase,
+) -> Result<Json<models::Execution>> {
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    Ok(Json(find_execution(
+        &*conn,
+        &handler,
+        exec_id.into_inner(),
+    )?))
+}
+
+/// The log lines of an execution in the order they were written, optionally
+/// only the ones of one stream (`stdout` or `stderr`).
+#[get("/handler/<hdl_id>/execution/<exec_id>/logs?<stream>")]
+#[instrument(skip(conn), err)]
+pub fn logs(
+    user: models::User,
+    hdl_id: Uuid,
+    exec_id: Uuid,
+    stream: Option<String>,
+    conn: MainDatabase,
+) -> Result<Json<Vec<models::ExecutionLog>>> {
+    use schema::execution_logs::dsl::{
+        execution_id, execution_logs, line_number, logged_at, stream as log_stream,
+    };
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let execution = find_execution(&*conn, &handler, exec_id.into_inner())?;
+
+    let mut query = execution_logs
+        .filter(execution_id.eq(execution.id))
+        .order((logged_at.asc(), line_number.asc()))
+        .into_boxed();
+    if let Some(stream) = stream {
+        if stream != "stdout" && stream != "stderr" {
+            return Err(Error::BadRequest(format!("unknown stream {:?}", stream)));
+        }
+        query = query.filter(log_stream.eq(stream));
+    }
+
+    Ok(Json(
+        query
+            .load::<models::ExecutionLog>(&*conn)
+            .map_err(Error::Database)?,
+    ))
+}
+
+/// Follows the executions of a handler as Server-Sent Events: `started` and
+/// `finished` for every execution and `log` for every line it writes. Only a
+/// few streams are served at once, each keeps a worker busy.
+#[get("/handler/<hdl_id>/logs/stream")]
+#[instrument(skip(conn), err)]
+pub fn stream(
+    user: models::User,
+    hdl_id: Uuid,
+    conn: MainDatabase,
+) -> Result<Content<Stream<events::EventStream>>> {
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let slot = events::StreamSlot::take()
+        .ok_or_else(|| Error::Busy("too many log streams are open".into()))?;
+
+    Ok(Content(
+        ContentType::new("text", "event-stream"),
+        Stream::chunked(
+            events::EventStream::new(events::HUB.subscribe(handler.id), slot),
+            4096,
+        ),
+    ))
+}
@@ -1,16 +1,33 @@
-use super::{Error, Result};
-use crate::{b2, models, schema, MainDatabase};
+use super::{
+    owned_handler,
+    page::{self, Cursor, Page, Sort},
+    Error, Result,
+};
+use crate::{
+    assets, auth::Visibility, b2, config, models, purge, routing, schema, secrets, MainDatabase,
+};
 use chrono::prelude::*;
-use diesel::prelude::*;
+use diesel::{pg::PgConnection, prelude::*};
+use rocket::{http::ContentType, request::LenientForm, response::Content, Data};
 use rocket_contrib::{json::Json, uuid::Uuid};
 use rocket_upload::MultipartDatas;
 use schema::handlers::dsl::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::{fs, io::Read};
+
+/// The largest config file that can be imported at once.
+const CONFIG_IMPORT_LIMIT: u64 = 1024 * 1024;
 
 #[derive(Debug, Eq, PartialEq, Deserialize)]
 pub struct New {
     pub name: Option<String>,
     pub async_impl: bool,
+    #[serde(default)]
+    pub visibility: Visibility,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
 #[post("/handler", format = "json", data = "<input>")]
@@ -21,6 +38,19 @@ pub fn create(
     conn: MainDatabase,
 ) -> Result<Json<models::Handler>> {
     let input = input.into_inner();
+    if let Some(name) = input.name.as_ref() {
+        if !routing::valid_name(name) {
+            return Err(Error::BadRequest(format!(
+                "invalid handler name {:?}",
+                name
+            )));
+        }
+        if routing::name_taken(&*conn, name)? {
+            return Err(Error::Conflict(format!("name {:?} is already taken", name)));
+        }
+    }
+    check_description(&input.description)?;
+    check_tags(&input.tags)?;
     let name = input.name.unwrap_or(elfs::next().to_lowercase());
     let hdl = diesel::insert_into(schema::handlers::table)
         .values(&models::NewHandler {
@@ -28,6 +58,9 @@ pub fn create(
             human_name: name,
             current_version: None,
             async_impl: input.async_impl,
+            visibility: input.visibility.as_str().to_string(),
+            description: input.description,
+            tags: normalize_tags(input.tags),
         })
         .get_result::<models::Handler>(&*conn)
         .map_err(Error::Database)?;
@@ -41,31 +74,130 @@ pub fn create(
     Ok(Json(hdl))
 }
 
-#[get("/handler")]
+/// Splits a comma-separated list of tags, ignoring empty entries.
+fn parse_tags(tags: &str) -> Result<Vec<String>> {
+    let tags: Vec<String> = tags
+        .split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_string)
+        .collect();
+    if let Some(tag) = tags.iter().find(|t| !valid_tag(t)) {
+        return Err(Error::BadRequest(format!("invalid tag {:?}", tag)));
+    }
+    Ok(tags)
+}
+
+/// Lists the handlers of a user. `tags` is a comma-separated list, only
+/// handlers that have all of them are listed.
+#[get("/handler?<tags>&<params..>")]
 #[instrument(skip(conn), err)]
-pub fn list(user: models::User, conn: MainDatabase) -> Result<Json<Vec<models::Handler>>> {
-    Ok(Json(
-        handlers
-            .filter(user_id.eq(user.id))
-            .load::<models::Handler>(&*conn)
-            .map_err(Error::Database)?,
+pub fn list(
+    user: models::User,
+    tags: Option<String>,
+    params: LenientForm<page::Params>,
+    conn: MainDatabase,
+) -> Result<Page<models::Handler>> {
+    let page = params
+        .into_inner()
+        .query(&[Sort::CreatedAt, Sort::Name], true)?;
+    let wanted = parse_tags(tags.as_deref().unwrap_or(""))?;
+    let filtered = || {
+        let mut query = handlers.filter(user_id.eq(user.id)).into_boxed();
+        if !page.include_deleted {
+            query = query.filter(deleted_at.is_null());
+        }
+        if let Some(pattern) = page.prefix_pattern() {
+            query = query.filter(human_name.like(pattern));
+        }
+        if !wanted.is_empty() {
+            query = query.filter(schema::handlers::tags.contains(wanted.clone()));
+        }
+        query
+    };
+
+    let total = filtered().count().get_result::<i64>(&*conn)?;
+    let rows = paginate!(filtered(), &page, id => uuid::Uuid, {
+        Sort::CreatedAt => created_at => NaiveDateTime,
+        Sort::Name => human_name => String,
+    })
+    .load::<models::Handler>(&*conn)?;
+
+    Ok(Page::new(rows, &page, total, |h| match page.sort {
+        Sort::Name => Cursor::new(&h.human_name, &h.id),
+        Sort::CreatedAt => Cursor::new(&h.created_at, &h.id),
+    }))
+}
+
+/// The most search results that are sent back.
+const MAX_SEARCH_RESULTS: i64 = 50;
+
+/// The text search document of a handler, see the handler-search migration.
+/// It has to be spelled out the same way as in the index for the index to be
+/// used.
+const SEARCH_DOCUMENT: &str = "handler_search_document(human_name, description, tags)";
+
+/// Searches the names, descriptions and tags of a user's handlers. The query
+/// can use the syntax of web search engines: `"quoted phrases"`, `or` and
+/// `-excluded` words. The best matches come first.
+#[get("/handler/search?<q>&<limit>")]
+#[instrument(skip(conn), err)]
+pub fn search(
+    user: models::User,
+    q: String,
+    limit: Option<i64>,
+    conn: MainDatabase,
+) -> Result<Json<Vec<models::Handler>>> {
+    let q = q.trim();
+    if q.is_empty() {
+        return Err(Error::BadRequest("search query is empty".into()));
+    }
+    let limit = limit
+        .unwrap_or(MAX_SEARCH_RESULTS)
+        .max(1)
+        .min(MAX_SEARCH_RESULTS);
+
+    Ok(Json(search_handlers(&*conn, &user, q, limit)?))
+}
+
+/// The handlers of user that match a text search query, best matches first.
+fn search_handlers(
+    conn: &PgConnection,
+    user: &models::User,
+    q: &str,
+    limit: i64,
+) -> QueryResult<Vec<models::Handler>> {
+    use diesel::{
+        dsl::sql,
+        sql_types::{Bool, Float, Text},
+    };
+
+    let matches = sql::<Bool>(&format!(
+        "{} @@ websearch_to_tsquery('simple', ",
+        SEARCH_DOCUMENT
+    ))
+    .bind::<Text, _>(q)
+    .sql(")");
+    let rank = sql::<Float>(&format!(
+        "ts_rank({}, websearch_to_tsquery('simple', ",
+        SEARCH_DOCUMENT
     ))
+    .bind::<Text, _>(q)
+    .sql("))");
+
+    handlers
+        .filter(user_id.eq(user.id))
+        .filter(deleted_at.is_null())
+        .filter(matches)
+        .order((rank.desc(), human_name.asc()))
+        .limit(limit)
+        .load::<models::Handler>(conn)
 }
 
 #[get("/handler/<hdl_id>")]
 #[instrument(skip(conn), err)]
 pub fn get(user: models::User, hdl_id: Uuid, conn: MainDatabase) -> Result<Json<models::Handler>> {
-    let uuid = hdl_id.into_inner();
-    let handler = handlers
-        .find(uuid)
-        .get_result::<models::Handler>(&*conn)
-        .map_err(Error::Database)?;
-
-    if handler.user_id != user.id {
-        Err(Error::LackPermissions)
-    } else {
-        Ok(Json(handler))
-    }
+    owned_handler(&*conn, &user, hdl_id.into_inner()).map(Json)
 }
 
 #[delete("/handler/<hdl_id>")]
@@ -75,6 +207,7 @@ pub fn delete(user: models::User, hdl_id: Uuid, conn: MainDatabase) -> Result {
 
     let hdl: models::Handler = handlers
         .find(uuid.clone())
+        .filter(deleted_at.is_null())
         .get_result(&*conn)
         .map_err(Error::Database)?;
 
@@ -89,6 +222,67 @@ pub fn delete(user: models::User, hdl_id: Uuid, conn: MainDatabase) -> Result {
     Ok(())
 }
 
+/// Restores a deleted handler, as long as that was within the undelete
+/// window.
+#[post("/handler/<hdl_id>/undelete")]
+#[instrument(skip(conn), err)]
+pub fn undelete(
+    user: models::User,
+    hdl_id: Uuid,
+    conn: MainDatabase,
+) -> Result<Json<models::Handler>> {
+    let hdl: models::Handler = handlers
+        .find(hdl_id.into_inner())
+        .get_result(&*conn)
+        .map_err(Error::Database)?;
+
+    if hdl.user_id != user.id && !user.is_admin {
+        return Err(Error::LackPermissions);
+    }
+    match hdl.deleted_at {
+        None => return Err(Error::Conflict("handler is not deleted".into())),
+        Some(at) if !purge::restorable(at) => {
+            return Err(Error::NotFound("handler (it is being purged)".into()))
+        }
+        Some(_) => {}
+    }
+
+    Ok(Json(
+        diesel::update(handlers.find(hdl.id))
+            .set(deleted_at.eq(None::<NaiveDateTime>))
+            .get_result::<models::Handler>(&*conn)?,
+    ))
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize)]
+pub struct SetVisibility {
+    pub visibility: Visibility,
+}
+
+#[put("/handler/<hdl_id>/visibility", format = "json", data = "<input>")]
+#[instrument(skip(conn), err)]
+pub fn set_visibility(
+    user: models::User,
+    hdl_id: Uuid,
+    input: Json<SetVisibility>,
+    conn: MainDatabase,
+) -> Result<Json<models::Handler>> {
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    let handler = diesel::update(handlers.find(handler.id))
+        .set(visibility.eq(input.visibility.as_str()))
+        .get_result::<models::Handler>(&*conn)
+        .map_err(Error::Database)?;
+
+    info!(
+        handler.id = &handler.id.to_string()[..],
+        visibility = &handler.visibility[..],
+        "set handler visibility"
+    );
+
+    Ok(Json(handler))
+}
+
 #[get("/handler/<handler_id_str>/config")]
 #[instrument(skip(conn), err)]
 pub fn get_config(
@@ -96,32 +290,48 @@ pub fn get_config(
     handler_id_str: Uuid,
     conn: MainDatabase,
 ) -> Result<Json<Vec<models::HandlerConfig>>> {
-    let uuid = handler_id_str.into_inner();
     {
         use schema::handler_config::dsl::{handler_config, handler_id};
 
-        let handler = handlers
-            .find(uuid)
-            .get_result::<models::Handler>(&*conn)
-            .map_err(Error::Database)?;
-
-        if handler.user_id != user.id {
-            return Err(Error::LackPermissions);
-        }
+        let handler = owned_handler(&*conn, &user, handler_id_str.into_inner())?;
 
         let config = handler_config
             .filter(handler_id.eq(handler.id))
             .load::<models::HandlerConfig>(&*conn)
-            .map_err(Error::Database)?;
+            .map_err(Error::Database)?
+            .into_iter()
+            .map(models::HandlerConfig::masked)
+            .collect();
 
         Ok(Json(config))
     }
 }
 
+#[get("/handler/<hdl_id>/config/effective")]
+#[instrument(skip(conn), err)]
+pub fn get_effective_config(
+    user: models::User,
+    hdl_id: Uuid,
+    conn: MainDatabase,
+) -> Result<Json<Vec<config::Entry>>> {
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    let config = config::effective(&*conn, handler.id)
+        .map_err(Error::Database)?
+        .into_iter()
+        .map(config::Entry::masked)
+        .collect();
+
+    Ok(Json(config))
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Cfg {
     pub key: String,
     pub value: String,
+    /// Secret values are encrypted at rest and never shown again.
+    #[serde(default)]
+    pub secret: bool,
 }
 
 #[post("/handler/<hdl_id>/config", format = "json", data = "<cfg>")]
@@ -132,38 +342,199 @@ pub fn create_config(
     cfg: Json<Vec<Cfg>>,
     conn: MainDatabase,
 ) -> Result {
-    use schema::handler_config::table;
-    let uuid = hdl_id.into_inner();
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
 
-    let handler = handlers
-        .find(uuid)
-        .get_result::<models::Handler>(&*conn)
+    let cfg = cfg
+        .into_inner()
+        .into_iter()
+        .map(|kv| {
+            Ok(models::NewHandlerConfig {
+                value_contents: if kv.secret {
+                    secrets::seal(secrets::Owner::Handler(handler.id), &kv.key, &kv.value)?
+                } else {
+                    kv.value
+                },
+                key_name: kv.key,
+                handler_id: handler.id.clone(),
+                is_secret: kv.secret,
+            })
+        })
+        .collect::<Result<Vec<models::NewHandlerConfig>>>()?;
+
+    for kv in cfg.iter() {
+        info!(name = kv.key_name.as_str(), "config created");
+    }
+
+    config::set(&*conn, user.id, cfg)?;
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormValue)]
+pub enum ImportMode {
+    /// Keys in the file are added or overwritten, other keys are kept.
+    Merge,
+    /// The file becomes the entire config of the handler. Secrets that are
+    /// missing from it are kept unless the import asks for them to be removed,
+    /// as exports leave their values out.
+    Replace,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ConfigDiff {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+    pub unchanged: Vec<String>,
+    pub dry_run: bool,
+}
+
+/// Reads an uploaded config file, which has to be UTF-8 and no larger than
+/// CONFIG_IMPORT_LIMIT.
+fn read_config(body: impl Read) -> Result<String> {
+    let mut bytes = vec![];
+    body.take(CONFIG_IMPORT_LIMIT + 1).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 > CONFIG_IMPORT_LIMIT {
+        return Err(Error::BadRequest(format!(
+            "config files can't be larger than {} bytes",
+            CONFIG_IMPORT_LIMIT
+        )));
+    }
+
+    String::from_utf8(bytes).map_err(|_| Error::BadRequest("config files must be UTF-8".into()))
+}
+
+#[post(
+    "/handler/<hdl_id>/config/import?<mode>&<dry_run>&<remove_secrets>",
+    data = "<body>"
+)]
+#[instrument(skip(conn, body), err)]
+pub fn import_config(
+    user: models::User,
+    hdl_id: Uuid,
+    mode: Option<ImportMode>,
+    dry_run: Option<bool>,
+    remove_secrets: Option<bool>,
+    ct: &ContentType,
+    body: Data,
+    conn: MainDatabase,
+) -> Result<Json<ConfigDiff>> {
+    use schema::handler_config::dsl::{handler_config, handler_id};
+    let mode = mode.unwrap_or(ImportMode::Merge);
+    let dry_run = dry_run.unwrap_or(false);
+    let remove_secrets = remove_secrets.unwrap_or(false);
+
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    let format = config::Format::from_content_type(ct)
+        .ok_or_else(|| Error::InvalidConfig(format!("unsupported content type {}", ct)))?;
+    let text = read_config(body.open())?;
+    let mut incoming = config::parse(format, &text)?;
+
+    let existing = handler_config
+        .filter(handler_id.eq(handler.id))
+        .load::<models::HandlerConfig>(&*conn)
         .map_err(Error::Database)?;
 
-    if handler.user_id != user.id {
-        return Err(Error::LackPermissions);
+    let mut diff = ConfigDiff {
+        dry_run,
+        ..ConfigDiff::default()
+    };
+    let mut upserts = vec![];
+
+    for kv in existing.iter() {
+        match incoming.remove(&kv.key_name) {
+            Some(value) => {
+                let old = if kv.is_secret {
+                    secrets::open(
+                        secrets::Owner::Handler(handler.id),
+                        &kv.key_name,
+                        &kv.value_contents,
+                    )?
+                } else {
+                    kv.value_contents.clone()
+                };
+
+                if old == value {
+                    diff.unchanged.push(kv.key_name.clone());
+                    continue;
+                }
+
+                diff.changed.push(kv.key_name.clone());
+                upserts.push(models::NewHandlerConfig {
+                    key_name: kv.key_name.clone(),
+                    value_contents: if kv.is_secret {
+                        secrets::seal(secrets::Owner::Handler(handler.id), &kv.key_name, &value)?
+                    } else {
+                        value
+                    },
+                    handler_id: handler.id.clone(),
+                    is_secret: kv.is_secret,
+                });
+            }
+            None if mode == ImportMode::Replace && (!kv.is_secret || remove_secrets) => {
+                diff.removed.push(kv.key_name.clone())
+            }
+            None => diff.unchanged.push(kv.key_name.clone()),
+        }
     }
 
-    let cfg: Vec<models::NewHandlerConfig> = cfg
-        .into_inner()
-        .into_iter()
-        .map(|kv| models::NewHandlerConfig {
-            key_name: kv.key,
-            value_contents: kv.value,
+    for (key, value) in incoming.into_iter() {
+        diff.added.push(key.clone());
+        upserts.push(models::NewHandlerConfig {
+            key_name: key,
+            value_contents: value,
             handler_id: handler.id.clone(),
-        })
-        .collect();
+            is_secret: false,
+        });
+    }
 
-    diesel::insert_into(table)
-        .values(&cfg)
-        .get_result::<models::HandlerConfig>(&*conn)
-        .map_err(Error::Database)?;
+    if dry_run {
+        return Ok(Json(diff));
+    }
 
-    let _ = cfg
-        .iter()
-        .inspect(|kv| info!(name = kv.key_name.as_str(), "config created"));
+    conn.transaction(|| {
+        config::remove(&*conn, user.id, handler.id, &diff.removed)?;
+        config::set(&*conn, user.id, upserts)
+    })?;
 
-    Ok(())
+    info!(
+        added = diff.added.len(),
+        changed = diff.changed.len(),
+        removed = diff.removed.len(),
+        "config imported"
+    );
+
+    Ok(Json(diff))
+}
+
+#[get("/handler/<hdl_id>/config/export?<format>")]
+#[instrument(skip(conn), err)]
+pub fn export_config(
+    user: models::User,
+    hdl_id: Uuid,
+    format: Option<config::Format>,
+    conn: MainDatabase,
+) -> Result<Content<String>> {
+    use schema::handler_config::dsl::{handler_config, handler_id, is_secret};
+    let format = format.unwrap_or(config::Format::Dotenv);
+
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    // Secret values never leave the server once they are set.
+    let cfg: config::Map = handler_config
+        .filter(handler_id.eq(handler.id))
+        .filter(is_secret.eq(false))
+        .load::<models::HandlerConfig>(&*conn)
+        .map_err(Error::Database)?
+        .into_iter()
+        .map(|kv| (kv.key_name, kv.value_contents))
+        .collect();
+
+    Ok(Content(
+        format.content_type(),
+        config::render(format, &cfg)?,
+    ))
 }
 
 #[post("/handler/<hdl_id>/upload", data = "<data>")]
@@ -174,34 +545,655 @@ pub fn upload_version(
     data: MultipartDatas,
     conn: MainDatabase,
 ) -> Result<Json<models::Handler>> {
-    let uuid = hdl_id.into_inner();
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
 
-    let handler = handlers
-        .find(uuid)
-        .get_result::<models::Handler>(&*conn)
-        .map_err(Error::Database)?;
-
-    if handler.user_id != user.id {
-        return Err(Error::LackPermissions);
-    }
-
-    if data.files.len() != 1 {
+    // The module can come with an asset bundle in a field named assets.
+    let (assets, modules): (Vec<_>, Vec<_>) = data.files.iter().partition(|f| f.name == "assets");
+    if modules.len() != 1 || assets.len() > 1 {
         return Err(Error::IncorrectFilecount(1));
     }
 
-    let file = data.files.get(0).ok_or(Error::IncorrectFilecount(1))?;
+    let file = modules[0];
     let ct = file
         .content_type
         .clone()
         .ok_or(Error::IncorrectFilecount(1))?;
+    let assets_url = match assets.get(0) {
+        Some(bundle) => {
+            assets::validate(fs::File::open(&bundle.path)?)
+                .map_err(|why| Error::BadRequest(format!("invalid asset archive: {}", why)))?;
+            let ct = bundle
+                .content_type
+                .clone()
+                .unwrap_or_else(|| "application/x-tar".parse().unwrap());
+            Some(b2::upload_as(bundle.path.clone().into(), ct, "tar")?)
+        }
+        None => None,
+    };
     let upload_url = b2::upload(file.path.clone().into(), ct)?;
 
-    let handler = diesel::update(handlers.filter(id.eq(handler.id)))
-        .set(current_version.eq(Some(upload_url.clone())))
-        .get_result(&*conn)
+    let handler = conn
+        .transaction(|| {
+            let version = diesel::insert_into(schema::handler_versions::table)
+                .values(&models::NewHandlerVersion {
+                    handler_id: handler.id,
+                    module_url: upload_url.clone(),
+                    created_by: user.id,
+                    assets_url: assets_url.clone(),
+                })
+                .get_result::<models::HandlerVersion>(&*conn)?;
+
+            diesel::update(handlers.filter(id.eq(handler.id)))
+                .set((
+                    current_version.eq(Some(upload_url.clone())),
+                    current_version_id.eq(Some(version.id)),
+                ))
+                .get_result(&*conn)
+        })
         .map_err(Error::Database)?;
 
-    info!(url = upload_url.as_str(), "uploaded new version of handler");
+    info!(
+        url = upload_url.as_str(),
+        assets = ?assets_url,
+        "uploaded new version of handler"
+    );
 
     Ok(Json(handler))
 }
+
+#[get("/handler/<hdl_id>/versions?<params..>")]
+#[instrument(skip(conn), err)]
+pub fn list_versions(
+    user: models::User,
+    hdl_id: Uuid,
+    params: LenientForm<page::Params>,
+    conn: MainDatabase,
+) -> Result<Page<models::HandlerVersion>> {
+    use schema::handler_versions::dsl::{
+        created_at as version_created_at, handler_id, handler_versions, id as version_id,
+    };
+    let page = params.into_inner().query(&[Sort::CreatedAt], false)?;
+    let uuid = hdl_id.into_inner();
+
+    let handler = owned_handler(&*conn, &user, uuid)?;
+
+    let filtered = || {
+        handler_versions
+            .filter(handler_id.eq(handler.id))
+            .into_boxed()
+    };
+    let total = filtered().count().get_result::<i64>(&*conn)?;
+    let rows = paginate!(filtered(), &page, version_id => uuid::Uuid, {
+        Sort::CreatedAt => version_created_at => NaiveDateTime,
+    })
+    .load::<models::HandlerVersion>(&*conn)?;
+
+    Ok(Page::new(rows, &page, total, |v| {
+        Cursor::new(&v.created_at, &v.id)
+    }))
+}
+
+#[get("/handler/<hdl_id>/config/history?<params..>")]
+#[instrument(skip(conn), err)]
+pub fn get_config_history(
+    user: models::User,
+    hdl_id: Uuid,
+    params: LenientForm<page::Params>,
+    conn: MainDatabase,
+) -> Result<Page<models::HandlerConfigHistory>> {
+    use schema::handler_config_history::dsl::{
+        created_at as changed_at, handler_config_history, handler_id, seq,
+    };
+    let page = params.into_inner().query(&[Sort::CreatedAt], false)?;
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    // Changes made in one transaction share a timestamp, seq keeps them in
+    // the order they were made.
+    let filtered = || {
+        handler_config_history
+            .filter(handler_id.eq(handler.id))
+            .into_boxed()
+    };
+    let total = filtered().count().get_result::<i64>(&*conn)?;
+    let rows = paginate!(filtered(), &page, seq => i64, {
+        Sort::CreatedAt => changed_at => NaiveDateTime,
+    })
+    .load::<models::HandlerConfigHistory>(&*conn)?;
+
+    Ok(Page::new(rows, &page, total, |h| {
+        Cursor::new(&h.created_at, &h.seq)
+    }))
+}
+
+fn parse_timestamp(at: &str) -> Result<NaiveDateTime> {
+    DateTime::parse_from_rfc3339(at)
+        .map(|dt| dt.naive_utc())
+        .map_err(|why| Error::BadRequest(format!("can't parse timestamp {:?}: {}", at, why)))
+}
+
+#[get("/handler/<hdl_id>/config/snapshot?<at>")]
+#[instrument(skip(conn), err)]
+pub fn get_config_snapshot(
+    user: models::User,
+    hdl_id: Uuid,
+    at: String,
+    conn: MainDatabase,
+) -> Result<Json<Vec<models::HandlerConfig>>> {
+    let at = parse_timestamp(&at)?;
+
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    let config = config::as_of(&*conn, handler.id, at)
+        .map_err(Error::Database)?
+        .into_iter()
+        .map(|kv| {
+            models::HandlerConfig {
+                key_name: kv.key_name,
+                value_contents: kv.value_contents,
+                handler_id: kv.handler_id,
+                created_at: at,
+                updated_at: at,
+                is_secret: kv.is_secret,
+            }
+            .masked()
+        })
+        .collect();
+
+    Ok(Json(config))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Restore {
+    /// An RFC 3339 timestamp to restore config from.
+    pub at: String,
+    /// Also roll the handler back to the version it was running at that time.
+    #[serde(default)]
+    pub with_version: bool,
+}
+
+#[post("/handler/<hdl_id>/config/restore", format = "json", data = "<input>")]
+#[instrument(skip(conn), err)]
+pub fn restore_config(
+    user: models::User,
+    hdl_id: Uuid,
+    input: Json<Restore>,
+    conn: MainDatabase,
+) -> Result<Json<models::Handler>> {
+    use schema::handler_config::dsl::{handler_config, handler_id};
+    let input = input.into_inner();
+    let at = parse_timestamp(&input.at)?;
+
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    let handler = conn.transaction(|| {
+        let snapshot = config::as_of(&*conn, handler.id, at)?;
+        let stale: Vec<String> = handler_config
+            .filter(handler_id.eq(handler.id))
+            .load::<models::HandlerConfig>(&*conn)?
+            .into_iter()
+            .map(|kv| kv.key_name)
+            .filter(|key| !snapshot.iter().any(|kv| &kv.key_name == key))
+            .collect();
+
+        config::remove(&*conn, user.id, handler.id, &stale)?;
+        config::set(&*conn, user.id, snapshot)?;
+
+        if !input.with_version {
+            return Ok(handler.clone());
+        }
+
+        use schema::handler_versions::dsl::{
+            created_at as version_created_at, handler_id as version_handler_id, handler_versions,
+        };
+        let version = handler_versions
+            .filter(version_handler_id.eq(handler.id))
+            .filter(version_created_at.le(at))
+            .order(version_created_at.desc())
+            .first::<models::HandlerVersion>(&*conn)
+            .optional()?
+            .ok_or_else(|| Error::BadRequest(format!("handler had no version at {}", at)))?;
+
+        Ok(diesel::update(handlers.find(handler.id))
+            .set((
+                current_version.eq(Some(version.module_url)),
+                current_version_id.eq(Some(version.id)),
+            ))
+            .get_result::<models::Handler>(&*conn)?)
+    })?;
+
+    info!(
+        at = &input.at[..],
+        with_version = input.with_version,
+        "restored handler config"
+    );
+
+    Ok(Json(handler))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Rename {
+    pub name: String,
+    /// How long the old name keeps redirecting to the new one.
+    #[serde(default = "default_rename_grace_hours")]
+    pub grace_period_hours: i64,
+}
+
+fn default_rename_grace_hours() -> i64 {
+    24 * 7
+}
+
+/// Makes sure a handler can take a new name, and keeps its old one around as
+/// an alias that expires after the grace period. This has to run in the same
+/// transaction as the rename. Keeping the current name changes nothing.
+fn take_name(
+    conn: &PgConnection,
+    handler: &models::Handler,
+    new_name: &str,
+    grace_period_hours: i64,
+) -> Result {
+    use schema::handler_aliases::dsl::{handler_aliases, handler_id as alias_handler_id, name};
+
+    if new_name == handler.human_name {
+        return Ok(());
+    }
+
+    // Taking back one of the handler's own aliases is fine.
+    diesel::delete(
+        handler_aliases
+            .filter(name.eq(new_name))
+            .filter(alias_handler_id.eq(handler.id)),
+    )
+    .execute(conn)?;
+
+    if routing::name_taken(conn, new_name)? {
+        return Err(Error::Conflict(format!(
+            "name {:?} is already taken",
+            new_name
+        )));
+    }
+
+    diesel::insert_into(handler_aliases)
+        .values(&models::NewHandlerAlias {
+            name: handler.human_name.clone(),
+            handler_id: handler.id,
+            expires_at: Some(Utc::now().naive_utc() + chrono::Duration::hours(grace_period_hours)),
+        })
+        .execute(conn)?;
+
+    Ok(())
+}
+
+#[post("/handler/<hdl_id>/rename", format = "json", data = "<input>")]
+#[instrument(skip(conn), err)]
+pub fn rename(
+    user: models::User,
+    hdl_id: Uuid,
+    input: Json<Rename>,
+    conn: MainDatabase,
+) -> Result<Json<models::Handler>> {
+    let input = input.into_inner();
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    if !routing::valid_name(&input.name) {
+        return Err(Error::BadRequest(format!(
+            "invalid handler name {:?}",
+            input.name
+        )));
+    }
+    if input.grace_period_hours < 0 {
+        return Err(Error::BadRequest("grace period can't be negative".into()));
+    }
+    // The handler already has its name, there is nothing to do.
+    if input.name == handler.human_name {
+        return Ok(Json(handler));
+    }
+
+    let handler = conn.transaction(|| {
+        take_name(&*conn, &handler, &input.name, input.grace_period_hours)?;
+
+        Ok(diesel::update(handlers.find(handler.id))
+            .set(human_name.eq(&input.name))
+            .get_result::<models::Handler>(&*conn)?)
+    })?;
+
+    info!(
+        handler.id = &handler.id.to_string()[..],
+        handler.name = &handler.human_name[..],
+        "renamed handler"
+    );
+
+    Ok(Json(handler))
+}
+
+/// The longest description a handler can have, and how many tags of what
+/// length.
+const MAX_DESCRIPTION_LEN: usize = 1024;
+const MAX_TAGS: usize = 16;
+const MAX_TAG_LEN: usize = 32;
+
+/// The longest an execution can be allowed to run, 15 minutes.
+const MAX_TIMEOUT_MS: i32 = 15 * 60 * 1000;
+
+/// Tags are short and lowercase, made of letters, digits and dashes.
+pub fn valid_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.len() <= MAX_TAG_LEN
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+fn check_description(description: &str) -> Result {
+    if description.chars().count() > MAX_DESCRIPTION_LEN {
+        return Err(Error::BadRequest(format!(
+            "description is longer than {} characters",
+            MAX_DESCRIPTION_LEN
+        )));
+    }
+    Ok(())
+}
+
+fn check_tags(tags: &[String]) -> Result {
+    if tags.len() > MAX_TAGS {
+        return Err(Error::BadRequest(format!(
+            "a handler can have at most {} tags",
+            MAX_TAGS
+        )));
+    }
+    if let Some(tag) = tags.iter().find(|t| !valid_tag(t)) {
+        return Err(Error::BadRequest(format!("invalid tag {:?}", tag)));
+    }
+    Ok(())
+}
+
+/// Tags are kept sorted and without duplicates.
+fn normalize_tags(mut tags: Vec<String>) -> Vec<String> {
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+/// Lets a JSON `null` be told apart from a field that is missing.
+fn nullable<'de, D, T>(de: D) -> std::result::Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(de).map(Some)
+}
+
+/// Changes to a handler. Everything is optional, fields that are left out
+/// stay as they are.
+#[derive(Debug, Default, Deserialize)]
+pub struct Update {
+    pub name: Option<String>,
+    /// How long the old name keeps redirecting to the new one when the name
+    /// changes.
+    #[serde(default = "default_rename_grace_hours")]
+    pub grace_period_hours: i64,
+    pub async_impl: Option<bool>,
+    pub visibility: Option<Visibility>,
+    pub description: Option<String>,
+    pub tags: Option<Vec<String>>,
+    /// `null` removes the timeout.
+    #[serde(default, deserialize_with = "nullable")]
+    pub timeout_ms: Option<Option<i32>>,
+}
+
+impl Update {
+    fn validate(&self) -> Result {
+        if let Some(name) = self.name.as_ref() {
+            if !routing::valid_name(name) {
+                return Err(Error::BadRequest(format!(
+                    "invalid handler name {:?}",
+                    name
+                )));
+            }
+        }
+        if self.grace_period_hours < 0 {
+            return Err(Error::BadRequest("grace period can't be negative".into()));
+        }
+        if let Some(description) = self.description.as_ref() {
+            check_description(description)?;
+        }
+        if let Some(tags) = self.tags.as_ref() {
+            check_tags(tags)?;
+        }
+        if let Some(Some(ms)) = self.timeout_ms {
+            if ms <= 0 || ms > MAX_TIMEOUT_MS {
+                return Err(Error::BadRequest(format!(
+                    "timeout must be between 1 and {} milliseconds",
+                    MAX_TIMEOUT_MS
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[patch("/handler/<hdl_id>", format = "json", data = "<input>")]
+#[instrument(skip(conn), err)]
+pub fn update(
+    user: models::User,
+    hdl_id: Uuid,
+    input: Json<Update>,
+    conn: MainDatabase,
+) -> Result<Json<models::Handler>> {
+    let input = input.into_inner();
+    input.validate()?;
+
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    let renamed = input
+        .name
+        .clone()
+        .filter(|new_name| *new_name != handler.human_name);
+    let changes = models::HandlerChanges {
+        human_name: renamed.clone(),
+        async_impl: input.async_impl,
+        visibility: input.visibility.map(|v| v.as_str().to_string()),
+        description: input.description,
+        tags: input.tags.map(normalize_tags),
+        timeout_ms: input.timeout_ms,
+    };
+
+    let handler = conn.transaction(|| {
+        if let Some(new_name) = renamed.as_ref() {
+            take_name(&*conn, &handler, new_name, input.grace_period_hours)?;
+        }
+
+        // Diesel refuses to make an UPDATE without anything to set.
+        if changes.human_name.is_none()
+            && changes.async_impl.is_none()
+            && changes.visibility.is_none()
+            && changes.description.is_none()
+            && changes.tags.is_none()
+            && changes.timeout_ms.is_none()
+        {
+            return Ok(handler.clone());
+        }
+
+        Ok(diesel::update(handlers.find(handler.id))
+            .set(&changes)
+            .get_result::<models::Handler>(&*conn)?)
+    })?;
+
+    info!(
+        handler.id = &handler.id.to_string()[..],
+        handler.name = &handler.human_name[..],
+        "updated handler"
+    );
+
+    Ok(Json(handler))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(json: &str) -> Update {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn validates_tags() {
+        assert!(valid_tag("billing"));
+        assert!(valid_tag("v2-beta"));
+        assert!(!valid_tag(""));
+        assert!(!valid_tag("Billing"));
+        assert!(!valid_tag("two words"));
+        assert!(!valid_tag(&"a".repeat(MAX_TAG_LEN + 1)));
+    }
+
+    #[test]
+    fn normalizes_tags() {
+        let tags = vec!["web", "billing", "web", "api"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(normalize_tags(tags), vec!["api", "billing", "web"]);
+        assert!(normalize_tags(vec![]).is_empty());
+    }
+
+    #[test]
+    fn reads_config_files() {
+        assert_eq!(read_config(&b"A=1\n"[..]).unwrap(), "A=1\n");
+        assert!(matches!(
+            read_config(&b"A=\xff\xfe\n"[..]),
+            Err(Error::BadRequest(_))
+        ));
+
+        let big = vec![b'a'; CONFIG_IMPORT_LIMIT as usize + 1];
+        assert!(matches!(read_config(&big[..]), Err(Error::BadRequest(_))));
+        let limit = vec![b'a'; CONFIG_IMPORT_LIMIT as usize];
+        assert!(read_config(&limit[..]).is_ok());
+    }
+
+    #[test]
+    fn parses_tag_filters() {
+        assert_eq!(parse_tags("").unwrap(), Vec::<String>::new());
+        assert_eq!(parse_tags("web, api,,").unwrap(), vec!["web", "api"]);
+        assert!(matches!(parse_tags("web,Nope"), Err(Error::BadRequest(_))));
+    }
+
+    #[test]
+    #[ignore]
+    fn searches_names_descriptions_and_tags() {
+        use crate::testing;
+
+        let conn = testing::conn();
+        let user = testing::user(&conn);
+        let describe = |description: &str, new_tags: &[&str]| {
+            let hdl = testing::handler(&conn, &user);
+            diesel::update(handlers.find(hdl.id))
+                .set((
+                    schema::handlers::description.eq(description),
+                    schema::handlers::tags.eq(normalize_tags(
+                        new_tags.iter().map(|t| t.to_string()).collect(),
+                    )),
+                ))
+                .get_result::<models::Handler>(&conn)
+                .unwrap()
+        };
+
+        let invoices = describe("Sends monthly invoices", &["billing"]);
+        let refunds = describe("Handles refunds", &["billing", "stripe"]);
+        let deleted = describe("Old invoices", &[]);
+        diesel::update(handlers.find(deleted.id))
+            .set(deleted_at.eq(Some(Utc::now().naive_utc())))
+            .execute(&conn)
+            .unwrap();
+
+        let ids = |q: &str| -> Vec<uuid::Uuid> {
+            search_handlers(&conn, &user, q, MAX_SEARCH_RESULTS)
+                .unwrap()
+                .into_iter()
+                .map(|h| h.id)
+                .collect()
+        };
+        assert_eq!(ids("invoices"), vec![invoices.id]);
+        assert_eq!(ids("stripe"), vec![refunds.id]);
+        assert_eq!(ids("billing").len(), 2);
+        assert_eq!(ids("billing -refunds"), vec![invoices.id]);
+        assert!(ids("nothing-like-this").is_empty());
+
+        let stranger = testing::user(&conn);
+        assert!(search_handlers(&conn, &stranger, "billing", 10)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    #[ignore]
+    fn keeping_the_name_is_a_no_op() {
+        use crate::testing;
+        use schema::handler_aliases::dsl::{handler_aliases, handler_id as alias_handler_id};
+
+        let conn = testing::conn();
+        let user = testing::user(&conn);
+        let hdl = testing::handler(&conn, &user);
+        let aliases = || {
+            handler_aliases
+                .filter(alias_handler_id.eq(hdl.id))
+                .count()
+                .get_result::<i64>(&conn)
+                .unwrap()
+        };
+
+        take_name(&conn, &hdl, &hdl.human_name, 24).unwrap();
+        assert_eq!(aliases(), 0);
+
+        let new_name = format!("{}-renamed", hdl.human_name);
+        take_name(&conn, &hdl, &new_name, 24).unwrap();
+        assert_eq!(aliases(), 1);
+    }
+
+    #[test]
+    fn tells_null_from_missing() {
+        assert_eq!(update("{}").timeout_ms, None);
+        assert_eq!(update(r#"{"timeout_ms": null}"#).timeout_ms, Some(None));
+        assert_eq!(
+            update(r#"{"timeout_ms": 5000}"#).timeout_ms,
+            Some(Some(5000))
+        );
+        assert_eq!(
+            update("{}").grace_period_hours,
+            default_rename_grace_hours()
+        );
+    }
+
+    #[test]
+    fn validates_updates() {
+        assert!(update("{}").validate().is_ok());
+        assert!(update(r#"{"name": "fine-name", "tags": ["a", "b"]}"#)
+            .validate()
+            .is_ok());
+
+        for json in &[
+            r#"{"name": "Not Valid"}"#,
+            r#"{"grace_period_hours": -1}"#,
+            r#"{"tags": ["UPPER"]}"#,
+            r#"{"timeout_ms": 0}"#,
+            r#"{"timeout_ms": 900001}"#,
+        ] {
+            assert!(
+                matches!(update(json).validate(), Err(Error::BadRequest(_))),
+                "{}",
+                json
+            );
+        }
+
+        let too_long = Update {
+            description: Some("x".repeat(MAX_DESCRIPTION_LEN + 1)),
+            ..Update::default()
+        };
+        assert!(too_long.validate().is_err());
+
+        let too_many = Update {
+            tags: Some((0..=MAX_TAGS).map(|n| format!("t{}", n)).collect()),
+            ..Update::default()
+        };
+        assert!(too_many.validate().is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use super::{
+    owned_handler,
+    page::{self, Cursor, Page, Sort},
+    Error, Result,
+};
+use crate::{auth, models, schema, MainDatabase};
+use chrono::prelude::*;
+use diesel::prelude::*;
+use rocket::request::LenientForm;
+use rocket_contrib::{json::Json, uuid::Uuid};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Eq, PartialEq, Deserialize)]
+pub struct New {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Created {
+    #[serde(flatten)]
+    pub record: models::InvocationKey,
+    /// The key itself. It can't be looked up again later.
+    pub key: String,
+}
+
+#[post("/handler/<hdl_id>/key", format = "json", data = "<input>")]
+#[instrument(skip(conn), err)]
+pub fn create(
+    user: models::User,
+    hdl_id: Uuid,
+    input: Json<New>,
+    conn: MainDatabase,
+) -> Result<Json<Created>> {
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let key = auth::new_key();
+
+    let record = diesel::insert_into(schema::invocation_keys::table)
+        .values(&models::NewInvocationKey {
+            handler_id: handler.id,
+            name: input.into_inner().name,
+            key_hash: auth::hash_key(&key),
+        })
+        .get_result::<models::InvocationKey>(&*conn)
+        .map_err(Error::Database)?;
+
+    info!(
+        handler.id = &handler.id.to_string()[..],
+        key.id = &record.id.to_string()[..],
+        "created invocation key"
+    );
+
+    Ok(Json(Created { record, key }))
+}
+
+#[get("/handler/<hdl_id>/key?<params..>")]
+#[instrument(skip(conn), err)]
+pub fn list(
+    user: models::User,
+    hdl_id: Uuid,
+    params: LenientForm<page::Params>,
+    conn: MainDatabase,
+) -> Result<Page<models::InvocationKey>> {
+    use schema::invocation_keys::dsl::{
+        created_at, deleted_at, handler_id, id, invocation_keys, name,
+    };
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let page = params
+        .into_inner()
+        .query(&[Sort::CreatedAt, Sort::Name], true)?;
+    let filtered = || {
+        let mut query = invocation_keys
+            .filter(handler_id.eq(handler.id))
+            .into_boxed();
+        if !page.include_deleted {
+            query = query.filter(deleted_at.is_null());
+        }
+        if let Some(pattern) = page.prefix_pattern() {
+            query = query.filter(name.like(pattern));
+        }
+        query
+    };
+
+    let total = filtered().count().get_result::<i64>(&*conn)?;
+    let rows = paginate!(filtered(), &page, id => uuid::Uuid, {
+        Sort::CreatedAt => created_at => NaiveDateTime,
+        Sort::Name => name => String,
+    })
+    .load::<models::InvocationKey>(&*conn)?;
+
+    Ok(Page::new(rows, &page, total, |k| match page.sort {
+        Sort::Name => Cursor::new(&k.name, &k.id),
+        Sort::CreatedAt => Cursor::new(&k.created_at, &k.id),
+    }))
+}
+
+#[delete("/handler/<hdl_id>/key/<key_id>")]
+#[instrument(skip(conn), err)]
+pub fn delete(user: models::User, hdl_id: Uuid, key_id: Uuid, conn: MainDatabase) -> Result {
+    use schema::invocation_keys::dsl::{deleted_at, invocation_keys};
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    let key = invocation_keys
+        .find(key_id.into_inner())
+        .get_result::<models::InvocationKey>(&*conn)
+        .map_err(Error::Database)?;
+
+    if key.handler_id != handler.id {
+        return Err(Error::LackPermissions);
+    }
+
+    diesel::update(invocation_keys.find(key.id))
+        .set(deleted_at.eq(Utc::now().naive_utc()))
+        .get_result::<models::InvocationKey>(&*conn)?;
+
+    info!(
+        handler.id = &handler.id.to_string()[..],
+        key.id = &key.id.to_string()[..],
+        "revoked invocation key"
+    );
+
+    Ok(())
+}
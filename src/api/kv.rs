@@ -0,0 +1,101 @@
+use super::{owned_handler, Error, Result};
+use crate::{kv, models, MainDatabase};
+use chrono::NaiveDateTime;
+use rocket::Data;
+use rocket_contrib::{json::Json, uuid::Uuid};
+use serde::Serialize;
+use std::io::Read;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Key {
+    pub key_name: String,
+    pub size: usize,
+    pub expires_at: Option<NaiveDateTime>,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Store {
+    pub keys: Vec<Key>,
+    /// How many bytes of keys and values the handler stores.
+    pub used: i64,
+    /// How many bytes the handler may store with the tier of its user.
+    pub quota: i64,
+}
+
+#[get("/handler/<hdl_id>/kv?<prefix>")]
+#[instrument(skip(conn), err)]
+pub fn list(
+    user: models::User,
+    hdl_id: Uuid,
+    prefix: Option<String>,
+    conn: MainDatabase,
+) -> Result<Json<Store>> {
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    let keys = kv::list(&*conn, handler.id, prefix.as_deref())?
+        .into_iter()
+        .map(|kv| Key {
+            size: kv.value.len(),
+            key_name: kv.key_name,
+            expires_at: kv.expires_at,
+            updated_at: kv.updated_at,
+        })
+        .collect();
+
+    Ok(Json(Store {
+        keys,
+        used: kv::usage(&*conn, handler.id)?,
+        quota: kv::quota(user.tier),
+    }))
+}
+
+#[get("/handler/<hdl_id>/kv/<key>")]
+#[instrument(skip(conn), err)]
+pub fn get(user: models::User, hdl_id: Uuid, key: String, conn: MainDatabase) -> Result<Vec<u8>> {
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    kv::get(&*conn, handler.id, &key)?
+        .map(|kv| kv.value)
+        .ok_or_else(|| Error::NotFound(format!("key {:?}", key)))
+}
+
+/// Stores the request body under a key, for `ttl` seconds if given.
+#[put("/handler/<hdl_id>/kv/<key>?<ttl>", data = "<body>")]
+#[instrument(skip(conn, body), err)]
+pub fn set(
+    user: models::User,
+    hdl_id: Uuid,
+    key: String,
+    ttl: Option<u32>,
+    body: Data,
+    conn: MainDatabase,
+) -> Result<Json<Key>> {
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    let mut value = vec![];
+    body.open()
+        .take(kv::quota(user.tier) as u64 + 1)
+        .read_to_end(&mut value)?;
+    let ttl = ttl.map(|secs| chrono::Duration::seconds(secs as i64));
+    let kv = kv::put(&*conn, handler.id, user.tier, &key, value, ttl)?;
+
+    Ok(Json(Key {
+        size: kv.value.len(),
+        key_name: kv.key_name,
+        expires_at: kv.expires_at,
+        updated_at: kv.updated_at,
+    }))
+}
+
+#[delete("/handler/<hdl_id>/kv/<key>")]
+#[instrument(skip(conn), err)]
+pub fn delete(user: models::User, hdl_id: Uuid, key: String, conn: MainDatabase) -> Result {
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    if !kv::delete(&*conn, handler.id, &key)? {
+        return Err(Error::NotFound(format!("key {:?}", key)));
+    }
+
+    Ok(())
+}
@@ -1,15 +1,30 @@
-use crate::{jwt, models, MainDatabase};
+use crate::{jwt, models, request_id::RequestId, schema, MainDatabase};
 use color_eyre::eyre::Report;
+use diesel::{pg::PgConnection, prelude::*};
 use rocket::{
     http::{ContentType, Status},
     request::{self, FromRequest, Request},
-    response::Responder,
-    Outcome, Response,
+    response::{self, Responder},
+    Catcher, Outcome, Response,
 };
 use std::io::{self, Cursor};
 
+// Defines paginate!, so it has to come before the modules using it.
+#[macro_use]
+pub mod page;
+
+pub mod admin;
+pub mod alias;
+pub mod config_group;
+pub mod domain;
+pub mod egress;
+pub mod environment;
+pub mod execution;
 pub mod handler;
+pub mod invocation_key;
+pub mod kv;
 pub mod token;
+pub mod traffic;
 pub mod user;
 
 #[derive(thiserror::Error, Debug)]
@@ -35,40 +50,187 @@ pub enum Error {
     #[error("incorrect number of files uploaded (wanted {0})")]
     IncorrectFilecount(usize),
 
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+
+    #[error("bad request: {0}")]
+    BadRequest(String),
+
+    #[error("not found: {0}")]
+    NotFound(String),
+
+    #[error("conflict: {0}")]
+    Conflict(String),
+
     #[error("subcommand execution failed: {0}")]
     Subcommand(#[from] io::Error),
 
+    #[error("too busy: {0}")]
+    Busy(String),
+
     #[error("this should be impossible")]
     Impossible,
 }
 
-impl<'a> Responder<'a> for Error {
-    fn respond_to(self, _: &Request) -> ::std::result::Result<Response<'a>, Status> {
+impl Error {
+    /// The HTTP status this error is answered with.
+    pub fn status(&self) -> Status {
+        use diesel::result::{DatabaseErrorKind, Error as Diesel};
+
         match self {
-            Error::BadOrNoAuth | Error::LackPermissions => Response::build()
-                .header(ContentType::Plain)
-                .status(Status::Unauthorized)
-                .sized_body(Cursor::new(format!("{}", self)))
-                .ok(),
-            Error::Backblaze(why) => Response::build()
-                .header(ContentType::Plain)
-                .status(Status::InternalServerError)
-                .sized_body(Cursor::new(format!("b2 error: {:?}", why)))
-                .ok(),
-            Error::IncorrectFilecount(_) => Response::build()
-                .header(ContentType::Plain)
-                .status(Status::BadRequest)
-                .sized_body(Cursor::new(format!("{}", self)))
-                .ok(),
-            _ => Response::build()
-                .header(ContentType::Plain)
-                .status(Status::InternalServerError)
-                .sized_body(Cursor::new(format!("{}", self)))
-                .ok(),
+            Error::Database(Diesel::NotFound) => Status::NotFound,
+            Error::Database(Diesel::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                Status::Conflict
+            }
+            Error::Database(Diesel::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _)) => {
+                Status::Conflict
+            }
+            Error::BadOrNoAuth => Status::Unauthorized,
+            Error::LackPermissions => Status::Forbidden,
+            Error::IncorrectFilecount(_) | Error::InvalidConfig(_) | Error::BadRequest(_) => {
+                Status::BadRequest
+            }
+            Error::NotFound(_) => Status::NotFound,
+            Error::Conflict(_) => Status::Conflict,
+            Error::ExternalDependencyFailed(_) | Error::Backblaze(_) => Status::BadGateway,
+            Error::Busy(_) => Status::ServiceUnavailable,
+            Error::Database(_)
+            | Error::InternalServerError(_)
+            | Error::Subcommand(_)
+            | Error::Impossible => Status::InternalServerError,
         }
     }
+
+    /// A machine-readable name for this kind of error. These don't change, so
+    /// clients can match on them.
+    pub fn code(&self) -> &'static str {
+        use diesel::result::Error as Diesel;
+
+        match self {
+            Error::Database(Diesel::NotFound) | Error::NotFound(_) => "not_found",
+            Error::Database(Diesel::DatabaseError(..)) if self.status() == Status::Conflict => {
+                "conflict"
+            }
+            Error::Conflict(_) => "conflict",
+            Error::Database(_) => "database_error",
+            Error::BadOrNoAuth => "unauthorized",
+            Error::LackPermissions => "forbidden",
+            Error::InternalServerError(_) => "internal_error",
+            Error::ExternalDependencyFailed(_) => "external_dependency_failed",
+            Error::Backblaze(_) => "storage_error",
+            Error::IncorrectFilecount(_) => "incorrect_file_count",
+            Error::InvalidConfig(_) => "invalid_config",
+            Error::BadRequest(_) => "bad_request",
+            Error::Subcommand(_) => "subcommand_failed",
+            Error::Busy(_) => "busy",
+            Error::Impossible => "impossible",
+        }
+    }
+}
+
+/// Answers with the JSON body every error gets:
+/// `{"error": {"code": ..., "message": ..., "request_id": ...}}`.
+pub fn error_response<'a>(
+    req: &Request,
+    status: Status,
+    code: &str,
+    message: String,
+) -> response::Result<'a> {
+    let body = json!({
+        "error": {
+            "code": code,
+            "message": message,
+            "request_id": RequestId::of(req),
+        }
+    });
+
+    Response::build()
+        .header(ContentType::JSON)
+        .status(status)
+        .sized_body(Cursor::new(body.to_string()))
+        .ok()
+}
+
+impl<'a> Responder<'a> for Error {
+    fn respond_to(self, req: &Request) -> response::Result<'a> {
+        let status = self.status();
+        let message = match &self {
+            Error::Backblaze(why) => format!("b2 error: {:?}", why),
+            _ => format!("{}", self),
+        };
+        if status.code >= 500 {
+            error!(request_id = RequestId::of(req).as_str(), "{}", message);
+        }
+
+        error_response(req, status, self.code(), message)
+    }
 }
 
+/// Errors Rocket answers by itself, like for requests no route matched or
+/// that a request guard refused, get the same body as the ones from routes.
+pub fn catchers() -> Vec<Catcher> {
+    catchers![
+        bad_request,
+        unauthorized,
+        forbidden,
+        not_found,
+        conflict,
+        payload_too_large,
+        unprocessable_entity,
+        internal_error,
+        service_unavailable,
+    ]
+}
+
+macro_rules! catcher {
+    ($name:ident, $status:literal, $code:expr, $message:expr) => {
+        #[catch($status)]
+        fn $name(req: &Request) -> response::Result<'static> {
+            error_response(
+                req,
+                Status::from_code($status).unwrap(),
+                $code,
+                $message.into(),
+            )
+        }
+    };
+}
+
+catcher!(bad_request, 400, "bad_request", "the request is malformed");
+catcher!(unauthorized, 401, "unauthorized", "bad or no authorization");
+catcher!(forbidden, 403, "forbidden", "you lack needed permissions");
+catcher!(not_found, 404, "not_found", "there is nothing here");
+catcher!(
+    conflict,
+    409,
+    "conflict",
+    "the request conflicts with what exists"
+);
+catcher!(
+    payload_too_large,
+    413,
+    "payload_too_large",
+    "the request is too large"
+);
+catcher!(
+    unprocessable_entity,
+    422,
+    "unprocessable_entity",
+    "the request body is invalid"
+);
+catcher!(
+    internal_error,
+    500,
+    "internal_error",
+    "something went wrong"
+);
+catcher!(
+    service_unavailable,
+    503,
+    "unavailable",
+    "the service is unavailable"
+);
+
 #[derive(Debug)]
 pub enum AuthError {
     BadCount,
@@ -78,6 +240,28 @@ pub enum AuthError {
 
 pub type Result<T = ()> = std::result::Result<T, Error>;
 
+/// Looks up a handler that hasn't been deleted, making sure it belongs to
+/// user.
+pub fn owned_handler(
+    conn: &PgConnection,
+    user: &models::User,
+    uuid: uuid::Uuid,
+) -> Result<models::Handler> {
+    use schema::handlers::dsl::{deleted_at, handlers};
+
+    let handler = handlers
+        .find(uuid)
+        .filter(deleted_at.is_null())
+        .get_result::<models::Handler>(conn)
+        .map_err(Error::Database)?;
+
+    if handler.user_id != user.id {
+        return Err(Error::LackPermissions);
+    }
+
+    Ok(handler)
+}
+
 impl<'a, 'r> FromRequest<'a, 'r> for models::User {
     type Error = ();
 
@@ -117,3 +301,78 @@ impl<'a, 'r> FromRequest<'a, 'r> for models::User {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request_id::{RequestIds, REQUEST_ID_HEADER};
+    use diesel::result::{DatabaseErrorKind, Error as Diesel};
+    use rocket::{http::Header, local::Client};
+
+    fn db(kind: DatabaseErrorKind) -> Error {
+        Error::Database(Diesel::DatabaseError(kind, Box::new(String::new())))
+    }
+
+    #[test]
+    fn maps_errors_to_statuses_and_codes() {
+        for (error, status, code) in vec![
+            (Error::Database(Diesel::NotFound), 404, "not_found"),
+            (db(DatabaseErrorKind::UniqueViolation), 409, "conflict"),
+            (db(DatabaseErrorKind::ForeignKeyViolation), 409, "conflict"),
+            (
+                db(DatabaseErrorKind::UnableToSendCommand),
+                500,
+                "database_error",
+            ),
+            (Error::BadOrNoAuth, 401, "unauthorized"),
+            (Error::LackPermissions, 403, "forbidden"),
+            (Error::BadRequest("x".into()), 400, "bad_request"),
+            (Error::InvalidConfig("x".into()), 400, "invalid_config"),
+            (Error::IncorrectFilecount(1), 400, "incorrect_file_count"),
+            (Error::NotFound("x".into()), 404, "not_found"),
+            (Error::Conflict("x".into()), 409, "conflict"),
+            (
+                Error::ExternalDependencyFailed(Report::msg("x")),
+                502,
+                "external_dependency_failed",
+            ),
+            (
+                Error::Subcommand(io::Error::new(io::ErrorKind::Other, "x")),
+                500,
+                "subcommand_failed",
+            ),
+            (Error::Impossible, 500, "impossible"),
+        ] {
+            assert_eq!(error.status().code, status, "{}", error);
+            assert_eq!(error.code(), code, "{}", error);
+        }
+    }
+
+    #[get("/fail")]
+    fn fail() -> Result<()> {
+        Err(Error::NotFound("handler".into()))
+    }
+
+    #[test]
+    fn answers_errors_with_an_envelope() {
+        let rocket = rocket::ignite()
+            .attach(RequestIds)
+            .register(catchers())
+            .mount("/", routes![fail]);
+        let client = Client::new(rocket).unwrap();
+
+        for path in &["/fail", "/nowhere"] {
+            let mut resp = client
+                .get(*path)
+                .header(Header::new(REQUEST_ID_HEADER, "abc-123"))
+                .dispatch();
+            assert_eq!(resp.status(), Status::NotFound);
+            assert_eq!(resp.content_type(), Some(ContentType::JSON));
+
+            let body: serde_json::Value =
+                serde_json::from_str(&resp.body_string().unwrap()).unwrap();
+            assert_eq!(body["error"]["code"], "not_found");
+            assert_eq!(body["error"]["request_id"], "abc-123");
+        }
+    }
+}
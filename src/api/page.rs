@@ -0,0 +1,392 @@
+use super::{Error, Result};
+use chrono::NaiveDateTime;
+use rocket::{
+    http::{Header, Status},
+    request::Request,
+    response::{self, Responder, Response},
+};
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+
+/// How many items a page has unless asked for otherwise, and at most.
+pub const DEFAULT_LIMIT: i64 = 50;
+pub const MAX_LIMIT: i64 = 200;
+
+/// The header the total number of items (on all pages) is sent in.
+pub static TOTAL_COUNT_HEADER: &str = "X-Total-Count";
+
+/// What a client can ask of a list endpoint, in the query string.
+#[derive(FromForm, Debug, Default)]
+pub struct Params {
+    /// Where the previous page ended, from its `Link` header.
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+    /// `created_at` or `name`, where there is a name.
+    pub sort: Option<String>,
+    /// `asc` or `desc`.
+    pub order: Option<String>,
+    /// Only lists items whose name starts with this.
+    pub prefix: Option<String>,
+    pub include_deleted: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Sort {
+    CreatedAt,
+    Name,
+}
+
+impl Sort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Sort::CreatedAt => "created_at",
+            Sort::Name => "name",
+        }
+    }
+
+    /// Names read alphabetically, everything else newest first.
+    fn default_order(&self) -> Order {
+        match self {
+            Sort::CreatedAt => Order::Desc,
+            Sort::Name => Order::Asc,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// A page that was asked for, checked against what an endpoint supports.
+#[derive(Debug)]
+pub struct Query {
+    pub limit: i64,
+    pub sort: Sort,
+    pub order: Order,
+    pub after: Option<Cursor>,
+    pub prefix: Option<String>,
+    pub include_deleted: bool,
+}
+
+impl Params {
+    /// Checks the parameters against the sort orders an endpoint supports, the
+    /// first of which is the default. Endpoints without names can't be
+    /// searched.
+    pub fn query(self, sorts: &[Sort], searchable: bool) -> Result<Query> {
+        let sort = match self.sort.as_deref() {
+            None => sorts[0],
+            Some(name) => *sorts
+                .iter()
+                .find(|s| s.as_str() == name)
+                .ok_or_else(|| Error::BadRequest(format!("can't sort by {:?}", name)))?,
+        };
+        let order = match self.order.as_deref() {
+            None => sort.default_order(),
+            Some("asc") => Order::Asc,
+            Some("desc") => Order::Desc,
+            Some(other) => return Err(Error::BadRequest(format!("unknown order {:?}", other))),
+        };
+        if self.prefix.is_some() && !searchable {
+            return Err(Error::BadRequest("this list can't be searched".into()));
+        }
+
+        Ok(Query {
+            limit: self.limit.unwrap_or(DEFAULT_LIMIT).max(1).min(MAX_LIMIT),
+            sort,
+            order,
+            after: self.cursor.as_deref().map(Cursor::decode).transpose()?,
+            prefix: self.prefix,
+            include_deleted: self.include_deleted.unwrap_or(false),
+        })
+    }
+}
+
+impl Query {
+    /// A LIKE pattern that matches names starting with the prefix.
+    pub fn prefix_pattern(&self) -> Option<String> {
+        self.prefix.as_ref().map(|prefix| {
+            format!(
+                "{}%",
+                prefix
+                    .replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_")
+            )
+        })
+    }
+}
+
+/// A value a list can be sorted by, as it is kept in a cursor.
+pub trait CursorKey: Sized {
+    fn encode(&self) -> String;
+    fn decode(s: &str) -> Result<Self>;
+}
+
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.f";
+
+impl CursorKey for NaiveDateTime {
+    fn encode(&self) -> String {
+        self.format(TIMESTAMP_FORMAT).to_string()
+    }
+
+    fn decode(s: &str) -> Result<Self> {
+        NaiveDateTime::parse_from_str(s, TIMESTAMP_FORMAT)
+            .map_err(|_| Error::BadRequest("invalid cursor".into()))
+    }
+}
+
+impl CursorKey for String {
+    fn encode(&self) -> String {
+        self.clone()
+    }
+
+    fn decode(s: &str) -> Result<Self> {
+        Ok(s.to_string())
+    }
+}
+
+impl CursorKey for i64 {
+    fn encode(&self) -> String {
+        self.to_string()
+    }
+
+    fn decode(s: &str) -> Result<Self> {
+        s.parse()
+            .map_err(|_| Error::BadRequest("invalid cursor".into()))
+    }
+}
+
+impl CursorKey for uuid::Uuid {
+    fn encode(&self) -> String {
+        self.to_string()
+    }
+
+    fn decode(s: &str) -> Result<Self> {
+        s.parse()
+            .map_err(|_| Error::BadRequest("invalid cursor".into()))
+    }
+}
+
+/// The last item of a page: its sort key and a unique key that breaks ties.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cursor {
+    pub key: String,
+    pub id: String,
+}
+
+impl Cursor {
+    pub fn new(key: &impl CursorKey, id: &impl CursorKey) -> Self {
+        Cursor {
+            key: key.encode(),
+            id: id.encode(),
+        }
+    }
+
+    fn encode(&self) -> String {
+        hex::encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(s: &str) -> Result<Self> {
+        hex::decode(s)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or_else(|| Error::BadRequest("invalid cursor".into()))
+    }
+}
+
+/// Sorts a boxed query as a page asks and skips to where the last page ended.
+/// Every sort order maps to a column and its Rust type, the ID column breaks
+/// ties. One more item than fits is loaded to tell if there is a next page.
+macro_rules! paginate {
+    ($query:expr, $page:expr, $id:expr => $id_ty:ty, { $($sort:path => $col:expr => $ty:ty),+ $(,)? }) => {{
+        use $crate::api::page::{CursorKey, Order};
+
+        let page: &$crate::api::page::Query = $page;
+        let mut query = $query;
+        match page.sort {
+            $($sort => {
+                if let Some(after) = page.after.as_ref() {
+                    let key = <$ty as CursorKey>::decode(&after.key)?;
+                    let id = <$id_ty as CursorKey>::decode(&after.id)?;
+                    query = match page.order {
+                        Order::Asc => query
+                            .filter($col.gt(key.clone()).or($col.eq(key).and($id.gt(id)))),
+                        Order::Desc => query
+                            .filter($col.lt(key.clone()).or($col.eq(key).and($id.lt(id)))),
+                    };
+                }
+                query = match page.order {
+                    Order::Asc => query.order(($col.asc(), $id.asc())),
+                    Order::Desc => query.order(($col.desc(), $id.desc())),
+                };
+            })+
+            #[allow(unreachable_patterns)]
+            _ => {
+                return Err($crate::api::Error::BadRequest(format!(
+                    "can't sort by {}",
+                    page.sort.as_str()
+                )))
+            }
+        }
+        query.limit(page.limit + 1)
+    }};
+}
+
+/// One page of a list. It is sent as a JSON array, with the total count in a
+/// header and a `Link` to the next page if there is one.
+#[derive(Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<Cursor>,
+    pub total: i64,
+}
+
+impl<T> Page<T> {
+    /// Makes a page out of what paginate! loaded.
+    pub fn new(mut items: Vec<T>, page: &Query, total: i64, cursor: impl Fn(&T) -> Cursor) -> Self {
+        let next = if items.len() as i64 > page.limit {
+            items.truncate(page.limit as usize);
+            items.last().map(cursor)
+        } else {
+            None
+        };
+
+        Page { items, next, total }
+    }
+
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            next: self.next,
+            total: self.total,
+        }
+    }
+}
+
+impl<'a, T: Serialize> Responder<'a> for Page<T> {
+    fn respond_to(self, req: &Request) -> response::Result<'a> {
+        let mut resp = Response::build_from(Json(self.items).respond_to(req)?);
+        resp.header(Header::new(TOTAL_COUNT_HEADER, self.total.to_string()));
+
+        if let Some(next) = self.next {
+            let uri = req.uri();
+            let mut query: Vec<&str> = uri
+                .query()
+                .unwrap_or("")
+                .split('&')
+                .filter(|kv| !kv.is_empty() && !kv.starts_with("cursor="))
+                .collect();
+            let cursor = format!("cursor={}", next.encode());
+            query.push(&cursor);
+            resp.header(Header::new(
+                "Link",
+                format!("<{}?{}>; rel=\"next\"", uri.path(), query.join("&")),
+            ));
+        }
+
+        resp.status(Status::Ok).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn query(prefix: &str) -> Query {
+        Params {
+            prefix: Some(prefix.into()),
+            ..Params::default()
+        }
+        .query(&[Sort::Name], true)
+        .unwrap()
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let at = NaiveDate::from_ymd(2020, 11, 5).and_hms_micro(12, 30, 1, 250);
+        let id = uuid::Uuid::new_v4();
+        let cursor = Cursor::decode(&Cursor::new(&at, &id).encode()).unwrap();
+
+        assert_eq!(NaiveDateTime::decode(&cursor.key).unwrap(), at);
+        assert_eq!(uuid::Uuid::decode(&cursor.id).unwrap(), id);
+
+        let cursor = Cursor::decode(&Cursor::new(&at, &42i64).encode()).unwrap();
+        assert_eq!(i64::decode(&cursor.id).unwrap(), 42);
+    }
+
+    #[test]
+    fn rejects_bad_cursors() {
+        assert!(matches!(Cursor::decode("zz"), Err(Error::BadRequest(_))));
+        assert!(matches!(
+            Cursor::decode(&hex::encode("{}")),
+            Err(Error::BadRequest(_))
+        ));
+        assert!(NaiveDateTime::decode("yesterday").is_err());
+        assert!(uuid::Uuid::decode("not-a-uuid").is_err());
+        assert!(i64::decode("4.2").is_err());
+    }
+
+    #[test]
+    fn escapes_prefix_patterns() {
+        assert_eq!(query("hello").prefix_pattern().unwrap(), "hello%");
+        assert_eq!(query("50%_off").prefix_pattern().unwrap(), "50\\%\\_off%");
+        assert_eq!(query("a\\b").prefix_pattern().unwrap(), "a\\\\b%");
+    }
+
+    #[test]
+    fn checks_params() {
+        let page = Params::default()
+            .query(&[Sort::CreatedAt, Sort::Name], true)
+            .unwrap();
+        assert_eq!(page.sort, Sort::CreatedAt);
+        assert_eq!(page.order, Order::Desc);
+        assert_eq!(page.limit, DEFAULT_LIMIT);
+
+        let page = Params {
+            limit: Some(10_000),
+            sort: Some("name".into()),
+            ..Params::default()
+        }
+        .query(&[Sort::CreatedAt, Sort::Name], true)
+        .unwrap();
+        assert_eq!(page.order, Order::Asc);
+        assert_eq!(page.limit, MAX_LIMIT);
+
+        let unsearchable = Params {
+            prefix: Some("a".into()),
+            ..Params::default()
+        };
+        assert!(unsearchable.query(&[Sort::CreatedAt], false).is_err());
+        let unsortable = Params {
+            sort: Some("name".into()),
+            ..Params::default()
+        };
+        assert!(unsortable.query(&[Sort::CreatedAt], false).is_err());
+    }
+
+    #[test]
+    fn points_at_the_next_page() {
+        let page = Params {
+            limit: Some(2),
+            ..Params::default()
+        }
+        .query(&[Sort::Name], true)
+        .unwrap();
+        let cursor = |name: &String| Cursor::new(name, name);
+
+        let full = Page::new(
+            vec!["a".to_string(), "b".into(), "c".into()],
+            &page,
+            3,
+            cursor,
+        );
+        assert_eq!(full.items, vec!["a", "b"]);
+        assert_eq!(full.next.unwrap().key, "b");
+
+        let last = Page::new(vec!["c".to_string()], &page, 3, cursor);
+        assert!(last.next.is_none());
+    }
+}
@@ -1,20 +1,39 @@
-use super::{Error, Result};
-use crate::{jwt, models, schema, MainDatabase};
+use super::{
+    page::{self, Cursor, Page, Sort},
+    Error, Result,
+};
+use crate::{jwt, models, purge, schema, MainDatabase};
 use chrono::prelude::*;
 use diesel::prelude::*;
-use rocket_contrib::{json::Json, uuid::Uuid};
+use rocket::request::LenientForm;
+use rocket_contrib::uuid::Uuid;
 
-#[get("/token")]
+#[get("/token?<params..>")]
 #[instrument(skip(conn), err)]
-pub fn list(user: models::User, conn: MainDatabase) -> Result<Json<Vec<models::Token>>> {
+pub fn list(
+    user: models::User,
+    params: LenientForm<page::Params>,
+    conn: MainDatabase,
+) -> Result<Page<models::Token>> {
     use schema::tokens::dsl::*;
+    let page = params.into_inner().query(&[Sort::CreatedAt], false)?;
+    let filtered = || {
+        let mut query = tokens.filter(user_id.eq(user.id)).into_boxed();
+        if !page.include_deleted {
+            query = query.filter(deleted_at.is_null());
+        }
+        query
+    };
 
-    Ok(Json(
-        tokens
-            .filter(user_id.eq(user.id))
-            .load::<models::Token>(&*conn)
-            .map_err(Error::Database)?,
-    ))
+    let total = filtered().count().get_result::<i64>(&*conn)?;
+    let rows = paginate!(filtered(), &page, id => uuid::Uuid, {
+        Sort::CreatedAt => created_at => NaiveDateTime,
+    })
+    .load::<models::Token>(&*conn)?;
+
+    Ok(Page::new(rows, &page, total, |t| {
+        Cursor::new(&t.created_at, &t.id)
+    }))
 }
 
 #[delete("/token/<uuid>")]
@@ -39,14 +58,53 @@ pub fn delete(user: models::User, conn: MainDatabase, uuid: Uuid) -> Result {
     Ok(())
 }
 
-#[post("/token")]
+/// Restores a deleted token, as long as that was within the undelete window.
+#[post("/token/<uuid>/undelete")]
+#[instrument(skip(conn), err)]
+pub fn undelete(user: models::User, conn: MainDatabase, uuid: Uuid) -> Result {
+    use schema::tokens::dsl::*;
+
+    let tok: models::Token = tokens
+        .find(uuid.into_inner())
+        .get_result(&*conn)
+        .map_err(Error::Database)?;
+
+    if tok.user_id != user.id && !user.is_admin {
+        return Err(Error::LackPermissions);
+    }
+    match tok.deleted_at {
+        None => return Err(Error::Conflict("token is not deleted".into())),
+        Some(at) if !purge::restorable(at) => {
+            return Err(Error::NotFound("token (it is being purged)".into()))
+        }
+        Some(_) => {}
+    }
+
+    diesel::update(tokens.find(tok.id))
+        .set(deleted_at.eq(None::<NaiveDateTime>))
+        .get_result::<models::Token>(&*conn)?;
+
+    Ok(())
+}
+
+/// Creates a token with a comma-separated list of scopes, `api` if none are
+/// given.
+#[post("/token?<scope>")]
 #[instrument(skip(conn), err)]
-pub fn create(user: models::User, conn: MainDatabase) -> Result<String> {
+pub fn create(user: models::User, scope: Option<String>, conn: MainDatabase) -> Result<String> {
     use schema::tokens;
 
+    let scopes = scope.unwrap_or_else(|| jwt::SCOPE_API.to_string());
+    for s in scopes.split(',') {
+        if !jwt::SCOPES.contains(&s) {
+            return Err(Error::BadRequest(format!("unknown scope {:?}", s)));
+        }
+    }
+
     let tok: models::Token = diesel::insert_into(tokens::table)
         .values(&models::NewToken {
             user_id: user.id.clone(),
+            scopes,
         })
         .get_result(&*conn)
         .map_err(Error::Database)?;
@@ -0,0 +1,264 @@
+use super::{owned_handler, Error, Result};
+use crate::{models, schema, traffic, MainDatabase};
+use diesel::{pg::PgConnection, prelude::*};
+use rocket_contrib::{json::Json, uuid::Uuid};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Weight {
+    pub version_id: uuid::Uuid,
+    pub weight: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CanaryPolicy {
+    /// The version all traffic goes back to when a canary fails.
+    pub stable_version_id: uuid::Uuid,
+    /// The share of failed executions (0.0 to 1.0) a canary may have.
+    pub failure_threshold: f64,
+    /// How many executions a canary needs before it can be rolled back.
+    pub min_executions: i32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SetTraffic {
+    pub split: Vec<Weight>,
+    pub canary: Option<CanaryPolicy>,
+}
+
+impl SetTraffic {
+    fn validate(&self) -> Result {
+        if self.split.iter().any(|w| w.weight < 0) {
+            return Err(Error::BadRequest("weights can't be negative".into()));
+        }
+        if self.split.iter().map(|w| w.weight as i64).sum::<i64>() == 0 {
+            return Err(Error::BadRequest(
+                "at least one weight must be positive".into(),
+            ));
+        }
+        if let Some(canary) = self.canary.as_ref() {
+            if canary.failure_threshold < 0.0 || canary.failure_threshold > 1.0 {
+                return Err(Error::BadRequest(
+                    "failure_threshold must be between 0.0 and 1.0".into(),
+                ));
+            }
+            if canary.min_executions < 0 {
+                return Err(Error::BadRequest("min_executions can't be negative".into()));
+            }
+        }
+
+        let mut seen = HashSet::new();
+        if let Some(w) = self.split.iter().find(|w| !seen.insert(w.version_id)) {
+            return Err(Error::BadRequest(format!(
+                "version {} is in the split more than once",
+                w.version_id
+            )));
+        }
+        if let Some(canary) = self.canary.as_ref() {
+            if !seen.contains(&canary.stable_version_id) {
+                return Err(Error::BadRequest(format!(
+                    "stable version {} is not in the split",
+                    canary.stable_version_id
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Traffic {
+    pub split: Vec<Weight>,
+    pub canary: Option<CanaryPolicy>,
+    /// How every version in the split has done since its weight last changed.
+    pub stats: Vec<traffic::VersionStats>,
+}
+
+fn load(conn: &PgConnection, hdl_id: uuid::Uuid) -> Result<Traffic> {
+    use schema::handler_canary_policies::dsl::handler_canary_policies;
+
+    let split = traffic::split(conn, hdl_id).map_err(Error::Database)?;
+    let stats = split
+        .iter()
+        .map(|t| traffic::stats(conn, hdl_id, t.version_id, t.updated_at))
+        .collect::<QueryResult<Vec<_>>>()
+        .map_err(Error::Database)?;
+    let canary = handler_canary_policies
+        .find(hdl_id)
+        .get_result::<models::HandlerCanaryPolicy>(conn)
+        .optional()
+        .map_err(Error::Database)?
+        .map(|p| CanaryPolicy {
+            stable_version_id: p.stable_version_id,
+            failure_threshold: p.failure_threshold,
+            min_executions: p.min_executions,
+        });
+
+    Ok(Traffic {
+        split: split
+            .into_iter()
+            .map(|t| Weight {
+                version_id: t.version_id,
+                weight: t.weight,
+            })
+            .collect(),
+        canary,
+        stats,
+    })
+}
+
+#[get("/handler/<hdl_id>/traffic")]
+#[instrument(skip(conn), err)]
+pub fn get(user: models::User, hdl_id: Uuid, conn: MainDatabase) -> Result<Json<Traffic>> {
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    Ok(Json(load(&*conn, handler.id)?))
+}
+
+#[put("/handler/<hdl_id>/traffic", format = "json", data = "<input>")]
+#[instrument(skip(conn), err)]
+pub fn set(
+    user: models::User,
+    hdl_id: Uuid,
+    input: Json<SetTraffic>,
+    conn: MainDatabase,
+) -> Result<Json<Traffic>> {
+    use schema::{
+        handler_canary_policies::dsl::{handler_canary_policies, handler_id as policy_handler_id},
+        handler_traffic::dsl::{handler_id, handler_traffic},
+        handler_versions::dsl::handler_versions,
+    };
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+    let input = input.into_inner();
+    input.validate()?;
+
+    // The stable version of a canary is one of the split's.
+    for w in input.split.iter() {
+        let version = handler_versions
+            .find(w.version_id)
+            .get_result::<models::HandlerVersion>(&*conn)
+            .map_err(Error::Database)?;
+        if version.handler_id != handler.id {
+            return Err(Error::BadRequest(format!(
+                "version {} does not belong to this handler",
+                version.id
+            )));
+        }
+    }
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::delete(handler_traffic.filter(handler_id.eq(handler.id))).execute(&*conn)?;
+        diesel::insert_into(handler_traffic)
+            .values(
+                &input
+                    .split
+                    .iter()
+                    .map(|w| models::NewHandlerTraffic {
+                        handler_id: handler.id,
+                        version_id: w.version_id,
+                        weight: w.weight,
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .execute(&*conn)?;
+
+        diesel::delete(handler_canary_policies.filter(policy_handler_id.eq(handler.id)))
+            .execute(&*conn)?;
+        if let Some(canary) = input.canary.as_ref() {
+            diesel::insert_into(handler_canary_policies)
+                .values(&models::NewHandlerCanaryPolicy {
+                    handler_id: handler.id,
+                    stable_version_id: canary.stable_version_id,
+                    failure_threshold: canary.failure_threshold,
+                    min_executions: canary.min_executions,
+                })
+                .execute(&*conn)?;
+        }
+
+        Ok(())
+    })
+    .map_err(Error::Database)?;
+
+    info!(
+        handler.id = &handler.id.to_string()[..],
+        versions = input.split.len(),
+        "set traffic split"
+    );
+
+    Ok(Json(load(&*conn, handler.id)?))
+}
+
+#[delete("/handler/<hdl_id>/traffic")]
+#[instrument(skip(conn), err)]
+pub fn delete(user: models::User, hdl_id: Uuid, conn: MainDatabase) -> Result {
+    use schema::{
+        handler_canary_policies::dsl::{handler_canary_policies, handler_id as policy_handler_id},
+        handler_traffic::dsl::{handler_id, handler_traffic},
+    };
+    let handler = owned_handler(&*conn, &user, hdl_id.into_inner())?;
+
+    // Without a split, every invocation goes to the current version again.
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::delete(handler_traffic.filter(handler_id.eq(handler.id))).execute(&*conn)?;
+        diesel::delete(handler_canary_policies.filter(policy_handler_id.eq(handler.id)))
+            .execute(&*conn)?;
+        Ok(())
+    })
+    .map_err(Error::Database)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u8) -> uuid::Uuid {
+        uuid::Uuid::from_slice(&[n; 16]).unwrap()
+    }
+
+    fn split(weights: &[(u8, i32)], stable: Option<u8>) -> SetTraffic {
+        SetTraffic {
+            split: weights
+                .iter()
+                .map(|(n, weight)| Weight {
+                    version_id: id(*n),
+                    weight: *weight,
+                })
+                .collect(),
+            canary: stable.map(|n| CanaryPolicy {
+                stable_version_id: id(n),
+                failure_threshold: 0.5,
+                min_executions: 10,
+            }),
+        }
+    }
+
+    fn rejected(input: SetTraffic) -> bool {
+        matches!(input.validate(), Err(Error::BadRequest(_)))
+    }
+
+    #[test]
+    fn validates_splits() {
+        assert!(split(&[(1, 90), (2, 10)], Some(1)).validate().is_ok());
+        assert!(split(&[(1, 100), (2, 0)], None).validate().is_ok());
+
+        assert!(rejected(split(&[(1, -1), (2, 10)], None)));
+        assert!(rejected(split(&[(1, 0), (2, 0)], None)));
+        assert!(rejected(split(&[(1, 50), (1, 50)], None)));
+        assert!(rejected(split(&[(1, 90), (2, 10)], Some(3))));
+    }
+
+    #[test]
+    fn validates_canaries() {
+        let mut input = split(&[(1, 90), (2, 10)], Some(1));
+        input.canary.as_mut().unwrap().failure_threshold = 1.5;
+        assert!(rejected(input));
+
+        let mut input = split(&[(1, 90), (2, 10)], Some(1));
+        input.canary.as_mut().unwrap().min_executions = -1;
+        assert!(rejected(input));
+    }
+}
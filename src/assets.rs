@@ -0,0 +1,216 @@
+use color_eyre::eyre::{eyre, Result};
+use std::{
+    fs,
+    io::Read,
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path},
+};
+
+/// The largest asset bundle a version can have, unpacked.
+pub const MAX_UNPACKED_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Where guests find their asset bundle and scratch directory.
+pub const GUEST_ASSETS_DIR: &str = "/assets";
+pub const GUEST_SCRATCH_DIR: &str = "/tmp";
+
+fn safe_path(path: &Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Checks that an asset archive only holds plain files and directories with
+/// relative paths that stay inside of it, and that it isn't too big.
+pub fn validate(archive: impl Read) -> Result<()> {
+    let mut total = 0;
+    for entry in tar::Archive::new(archive).entries()? {
+        let entry = entry?;
+        let path = entry.path()?;
+        if !safe_path(&path) {
+            return Err(eyre!("{} is outside of the archive", path.display()));
+        }
+
+        let kind = entry.header().entry_type();
+        if !kind.is_file() && !kind.is_dir() {
+            return Err(eyre!("{} is not a file or directory", path.display()));
+        }
+
+        total += entry.header().size()?;
+        if total > MAX_UNPACKED_BYTES {
+            return Err(eyre!("assets are larger than {} bytes", MAX_UNPACKED_BYTES));
+        }
+    }
+
+    Ok(())
+}
+
+/// Unpacks an asset archive into a directory nobody can write to. Bundles are
+/// content-addressed, so a directory that already exists is left as-is.
+#[instrument(skip(archive), err)]
+pub fn unpack(archive: &Path, dest: &Path) -> Result<()> {
+    if dest.exists() {
+        return Ok(());
+    }
+
+    validate(fs::File::open(archive)?)?;
+
+    // Unpacking next to the destination and renaming it into place keeps
+    // concurrent executions from seeing half of a bundle.
+    let staging = dest.with_extension(format!("partial-{}", uuid::Uuid::new_v4()));
+    tar::Archive::new(fs::File::open(archive)?).unpack(&staging)?;
+    read_only(&staging)?;
+    if let Err(why) = fs::rename(&staging, dest) {
+        let _ = fs::remove_dir_all(&staging);
+        if !dest.exists() {
+            return Err(why.into());
+        }
+    }
+
+    Ok(())
+}
+
+fn read_only(path: &Path) -> Result<()> {
+    let meta = fs::symlink_metadata(path)?;
+    if meta.is_dir() {
+        for entry in fs::read_dir(path)? {
+            read_only(&entry?.path())?;
+        }
+        fs::set_permissions(path, fs::Permissions::from_mode(0o555))?;
+    } else {
+        fs::set_permissions(path, fs::Permissions::from_mode(0o444))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn archive(entries: &[(&str, tar::EntryType, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, kind, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            // Paths are written as-is, set_path refuses the dangerous ones.
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_entry_type(*kind);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn scratch() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("assets-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn remove(dir: &Path) {
+        fn writable(path: &Path) {
+            if fs::symlink_metadata(path).unwrap().is_dir() {
+                fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+                for entry in fs::read_dir(path).unwrap() {
+                    writable(&entry.unwrap().path());
+                }
+            }
+        }
+        writable(dir);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn accepts_files_and_directories() {
+        let tar = archive(&[
+            ("static/", tar::EntryType::Directory, b""),
+            ("static/index.html", tar::EntryType::Regular, b"<h1>hi</h1>"),
+            ("./robots.txt", tar::EntryType::Regular, b""),
+        ]);
+        validate(&tar[..]).unwrap();
+    }
+
+    #[test]
+    fn rejects_paths_outside_of_the_archive() {
+        for path in &["../escape", "static/../../escape", "/etc/passwd"] {
+            let tar = archive(&[(path, tar::EntryType::Regular, b"")]);
+            assert!(validate(&tar[..]).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_links_and_devices() {
+        for kind in &[
+            tar::EntryType::Symlink,
+            tar::EntryType::Link,
+            tar::EntryType::Char,
+            tar::EntryType::Fifo,
+        ] {
+            let tar = archive(&[("thing", *kind, b"")]);
+            assert!(validate(&tar[..]).is_err(), "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn rejects_large_bundles() {
+        let half = vec![0; (MAX_UNPACKED_BYTES / 2) as usize];
+        let fits = archive(&[("a", tar::EntryType::Regular, &half)]);
+        validate(&fits[..]).unwrap();
+
+        let too_big = archive(&[
+            ("a", tar::EntryType::Regular, &half),
+            ("b", tar::EntryType::Regular, &half),
+            ("c", tar::EntryType::Regular, b"!"),
+        ]);
+        assert!(validate(&too_big[..]).is_err());
+    }
+
+    #[test]
+    fn unpacks_read_only() {
+        let dir = scratch();
+        let tar_path = dir.join("bundle.tar");
+        fs::write(
+            &tar_path,
+            archive(&[
+                ("static/", tar::EntryType::Directory, b""),
+                ("static/index.html", tar::EntryType::Regular, b"<h1>hi</h1>"),
+            ]),
+        )
+        .unwrap();
+        let dest = dir.join("bundle");
+
+        unpack(&tar_path, &dest).unwrap();
+        let index = dest.join("static/index.html");
+        assert_eq!(fs::read(&index).unwrap(), b"<h1>hi</h1>");
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&index), 0o444);
+        assert_eq!(mode(&dest.join("static")), 0o555);
+        assert_eq!(mode(&dest), 0o555);
+
+        // Unpacking again leaves the bundle alone and nothing half-done behind.
+        fs::write(&tar_path, archive(&[])).unwrap();
+        unpack(&tar_path, &dest).unwrap();
+        assert!(index.exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        remove(&dir);
+    }
+
+    #[test]
+    fn refuses_to_unpack_bad_bundles() {
+        let dir = scratch();
+        let tar_path = dir.join("bundle.tar");
+        fs::write(
+            &tar_path,
+            archive(&[("../escape", tar::EntryType::Regular, b"")]),
+        )
+        .unwrap();
+
+        assert!(unpack(&tar_path, &dir.join("bundle")).is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert!(!dir.parent().unwrap().join("escape").exists());
+
+        remove(&dir);
+    }
+}
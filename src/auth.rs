@@ -0,0 +1,273 @@
+use crate::{
+    api::{Error, Result},
+    jwt, models, schema,
+};
+use diesel::{pg::PgConnection, prelude::*};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Who may run a handler.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    /// Anyone who knows how to reach the handler.
+    Public,
+    /// Anyone with one of the invocation keys of the handler.
+    ApiKey,
+    /// The owner of the handler with a token that has the invoke scope.
+    Jwt,
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Visibility::Public
+    }
+}
+
+impl Visibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::ApiKey => "api_key",
+            Visibility::Jwt => "jwt",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "public" => Some(Visibility::Public),
+            "api_key" => Some(Visibility::ApiKey),
+            "jwt" => Some(Visibility::Jwt),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Visibility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+const KEY_PREFIX: &str = "wck_";
+
+/// Makes a new invocation key. Only its hash is stored.
+pub fn new_key() -> String {
+    let mut buf = [0u8; 32];
+    OsRng.fill_bytes(&mut buf);
+    format!("{}{}", KEY_PREFIX, hex::encode(buf))
+}
+
+pub fn hash_key(key: &str) -> String {
+    blake3::hash(key.as_bytes()).to_hex().to_string()
+}
+
+/// What a client presented to run a handler.
+#[derive(Default)]
+pub struct Credentials {
+    pub invocation_key: Option<String>,
+    pub token: Option<String>,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("invocation_key", &self.invocation_key.is_some())
+            .field("token", &self.token.is_some())
+            .finish()
+    }
+}
+
+/// Checks whether the credentials of a request allow it to run a handler.
+#[instrument(skip(conn, hdl), fields(handler.id = &hdl.id.to_string()[..]), err)]
+pub fn authorize(conn: &PgConnection, hdl: &models::Handler, creds: &Credentials) -> Result {
+    let visibility = Visibility::parse(&hdl.visibility).ok_or(Error::Impossible)?;
+
+    match visibility {
+        Visibility::Public => Ok(()),
+        Visibility::ApiKey => {
+            use schema::invocation_keys::dsl::{deleted_at, handler_id, invocation_keys, key_hash};
+            let key = creds.invocation_key.as_ref().ok_or(Error::BadOrNoAuth)?;
+
+            let found = invocation_keys
+                .filter(handler_id.eq(hdl.id))
+                .filter(key_hash.eq(hash_key(key)))
+                .filter(deleted_at.is_null())
+                .first::<models::InvocationKey>(conn)
+                .optional()?;
+
+            match found {
+                Some(_) => Ok(()),
+                None => Err(Error::LackPermissions),
+            }
+        }
+        Visibility::Jwt => {
+            let token = creds.token.clone().ok_or(Error::BadOrNoAuth)?;
+            let user = jwt::verify_scoped(token, conn, jwt::SCOPE_INVOKE).map_err(|why| {
+                debug!("JWT verification error: {}", why);
+                Error::BadOrNoAuth
+            })?;
+
+            if user.id != hdl.user_id {
+                return Err(Error::LackPermissions);
+            }
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::env;
+
+    #[test]
+    fn visibilities_round_trip() {
+        for v in &[Visibility::Public, Visibility::ApiKey, Visibility::Jwt] {
+            assert_eq!(Visibility::parse(v.as_str()), Some(*v));
+            assert_eq!(
+                serde_json::to_string(v).unwrap(),
+                format!("{:?}", v.as_str())
+            );
+        }
+        assert_eq!(Visibility::parse("private"), None);
+        assert_eq!(Visibility::default(), Visibility::Public);
+    }
+
+    #[test]
+    fn keys_are_unique_and_hashed() {
+        let key = new_key();
+        assert!(key.starts_with(KEY_PREFIX));
+        assert_eq!(key.len(), KEY_PREFIX.len() + 64);
+        assert_ne!(key, new_key());
+
+        assert_eq!(hash_key(&key), hash_key(&key));
+        assert_ne!(hash_key(&key), hash_key(&new_key()));
+        assert!(!hash_key(&key).contains(&key[KEY_PREFIX.len()..]));
+    }
+
+    #[test]
+    fn credentials_are_not_logged() {
+        let creds = Credentials {
+            invocation_key: Some("wck_secret".into()),
+            token: Some("secret".into()),
+        };
+        assert!(!format!("{:?}", creds).contains("secret"));
+    }
+
+    fn with_visibility(conn: &PgConnection, visibility: Visibility) -> models::Handler {
+        let user = testing::user(conn);
+        let hdl = testing::handler(conn, &user);
+        diesel::update(schema::handlers::table.find(hdl.id))
+            .set(schema::handlers::visibility.eq(visibility.as_str()))
+            .get_result(conn)
+            .unwrap()
+    }
+
+    fn key(invocation_key: Option<&str>) -> Credentials {
+        Credentials {
+            invocation_key: invocation_key.map(str::to_string),
+            token: None,
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn public_handlers_need_nothing() {
+        let conn = testing::conn();
+        let hdl = with_visibility(&conn, Visibility::Public);
+        assert!(authorize(&conn, &hdl, &Credentials::default()).is_ok());
+    }
+
+    #[test]
+    #[ignore]
+    fn checks_invocation_keys() {
+        let conn = testing::conn();
+        let hdl = with_visibility(&conn, Visibility::ApiKey);
+        let other = with_visibility(&conn, Visibility::ApiKey);
+
+        let issue = |hdl: &models::Handler, name: &str| {
+            let k = new_key();
+            diesel::insert_into(schema::invocation_keys::table)
+                .values(&models::NewInvocationKey {
+                    handler_id: hdl.id,
+                    name: name.to_string(),
+                    key_hash: hash_key(&k),
+                })
+                .get_result::<models::InvocationKey>(&conn)
+                .unwrap();
+            k
+        };
+        let good = issue(&hdl, "good");
+        let revoked = issue(&hdl, "revoked");
+        let foreign = issue(&other, "foreign");
+        {
+            use schema::invocation_keys::dsl::{deleted_at, invocation_keys, name};
+            diesel::update(invocation_keys.filter(name.eq("revoked")))
+                .set(deleted_at.eq(Some(chrono::Utc::now().naive_utc())))
+                .execute(&conn)
+                .unwrap();
+        }
+
+        assert!(authorize(&conn, &hdl, &key(Some(&good))).is_ok());
+        assert!(matches!(
+            authorize(&conn, &hdl, &key(None)),
+            Err(Error::BadOrNoAuth)
+        ));
+        for k in &[&revoked, &foreign, &new_key()] {
+            assert!(matches!(
+                authorize(&conn, &hdl, &key(Some(k))),
+                Err(Error::LackPermissions)
+            ));
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn jwt_handlers_need_an_invoke_token_of_the_owner() {
+        if env::var("JWT_SECRET").is_err() {
+            env::set_var("JWT_SECRET", "hunter2");
+        }
+        let conn = testing::conn();
+        let hdl = with_visibility(&conn, Visibility::Jwt);
+        let stranger = testing::user(&conn);
+        let token = |user_id: uuid::Uuid, scopes: &str| {
+            let tok = diesel::insert_into(schema::tokens::table)
+                .values(&models::NewToken {
+                    user_id,
+                    scopes: scopes.to_string(),
+                })
+                .get_result::<models::Token>(&conn)
+                .unwrap();
+            Credentials {
+                invocation_key: None,
+                token: Some(jwt::make(user_id, tok.id).unwrap()),
+            }
+        };
+
+        assert!(authorize(&conn, &hdl, &token(hdl.user_id, jwt::SCOPE_INVOKE)).is_ok());
+        assert!(matches!(
+            authorize(&conn, &hdl, &token(hdl.user_id, jwt::SCOPE_API)),
+            Err(Error::BadOrNoAuth)
+        ));
+        assert!(matches!(
+            authorize(&conn, &hdl, &token(stranger.id, jwt::SCOPE_INVOKE)),
+            Err(Error::LackPermissions)
+        ));
+        assert!(matches!(
+            authorize(&conn, &hdl, &key(None)),
+            Err(Error::BadOrNoAuth)
+        ));
+        let garbage = Credentials {
+            invocation_key: None,
+            token: Some("not-a-jwt".into()),
+        };
+        assert!(matches!(
+            authorize(&conn, &hdl, &garbage),
+            Err(Error::BadOrNoAuth)
+        ));
+    }
+}
@@ -56,6 +56,13 @@ fn copy_wide(mut reader: impl Read, hasher: &mut blake3::Hasher) -> io::Result<u
 
 #[instrument(err)]
 pub fn upload(filename: PathBuf, content_type: Mime) -> Result<String> {
+    upload_as(filename, content_type, "wasm")
+}
+
+/// Uploads a file under its hash with the given extension, so the same file is
+/// only ever stored once.
+#[instrument(err)]
+pub fn upload_as(filename: PathBuf, content_type: Mime, extension: &str) -> Result<String> {
     let client = ClientBuilder::new()
         .timeout(None)
         .user_agent(crate::APP_USER_AGENT)
@@ -66,7 +73,7 @@ pub fn upload(filename: PathBuf, content_type: Mime) -> Result<String> {
     let fin = fs::File::open(filename.clone())?;
     let meta = fin.metadata()?;
     let (hash, size) = hash(&filename)?;
-    let hash = format!("{}.wasm", hash);
+    let hash = format!("{}.{}", hash, extension);
     let modf = meta
         .modified()
         .unwrap()
@@ -75,6 +82,9 @@ pub fn upload(filename: PathBuf, content_type: Mime) -> Result<String> {
         * 1000;
     let ct = content_type.to_string();
     debug!(hash = hash.as_str(), size = size, "uploading to b2");
+    crate::metrics::UPLOAD_BYTES
+        .with_label_values(&[extension])
+        .observe(size as f64);
 
     let param = FileParameters {
         file_path: hash.as_str(),
@@ -91,3 +101,69 @@ pub fn upload(filename: PathBuf, content_type: Mime) -> Result<String> {
 
     Ok(format!("b2://{}", hash))
 }
+
+/// A file stored in the module bucket.
+#[derive(Debug, Clone)]
+pub struct Blob {
+    pub name: String,
+    pub id: String,
+    pub size: u64,
+    /// Milliseconds since the Unix epoch.
+    pub uploaded_at: u64,
+}
+
+impl Blob {
+    /// The URL versions refer to this file by.
+    pub fn url(&self) -> String {
+        format!("b2://{}", self.name)
+    }
+}
+
+fn client() -> Result<reqwest::blocking::Client> {
+    Ok(ClientBuilder::new()
+        .timeout(None)
+        .user_agent(crate::APP_USER_AGENT)
+        .build()?)
+}
+
+/// Lists every file in the module bucket.
+#[instrument(err)]
+pub fn list() -> Result<Vec<Blob>> {
+    let client = client()?;
+    let auth = util::authenticate_from_file(&client, CREDS.clone()).map_err(Backblaze)?;
+    let files =
+        util::list_all_files(&client, &auth, BUCKET_ID.as_str(), 1000).map_err(Backblaze)?;
+
+    Ok(files
+        .into_iter()
+        .filter_map(|f| {
+            Some(Blob {
+                id: f.file_id?,
+                name: f.file_name,
+                size: f.content_length,
+                uploaded_at: f.upload_timestamp,
+            })
+        })
+        .collect())
+}
+
+/// Deletes a file from the module bucket for good.
+#[instrument(err)]
+pub fn delete(blob: &Blob) -> Result<()> {
+    let client = client()?;
+    let auth = util::authenticate_from_file(&client, CREDS.clone()).map_err(Backblaze)?;
+    b2_delete_file_version(&client, &auth, &blob.name, &blob.id).map_err(Backblaze)?;
+
+    Ok(())
+}
+
+/// Makes sure the storage credentials still work. This is a B2 transaction,
+/// so it shouldn't be made on every readiness probe, see health::readyz.
+pub fn reachable(timeout: std::time::Duration) -> Result<()> {
+    let client = ClientBuilder::new()
+        .timeout(Some(timeout))
+        .user_agent(crate::APP_USER_AGENT)
+        .build()?;
+    util::authenticate_from_file(&client, CREDS.clone()).map_err(Backblaze)?;
+    Ok(())
+}
@@ -2,45 +2,132 @@
 
 #[macro_use]
 extern crate rocket;
+#[macro_use]
+extern crate tracing;
 
 use color_eyre::eyre::Result;
-use rocket_contrib::helmet::SpaceHelmet;
+use rocket::fairing::AdHoc;
+use rocket_contrib::{databases::database_config, helmet::SpaceHelmet};
 use rocket_oauth2::OAuth2;
 
-use ::wasmcloud_api::{api, b2, gitea, jwt, Gitea, MainDatabase};
+use ::wasmcloud_api::{
+    api, b2, domains, events, gitea, health, jwt, metrics, request_id, secrets, telemetry, Gitea,
+    MainDatabase,
+};
 
 fn main() -> Result<()> {
     color_eyre::install()?;
-    tracing_subscriber::fmt::init();
+    let _telemetry = telemetry::init("wasmcloud-api")?;
 
     // XXX(Xe): This looks ineffectual, however it forces jwt::SECRET to be
     // evaluated and will kill the program if JWT_SECRET is not found.
     let _ = *jwt::SECRET;
     let _ = *b2::CREDS;
     let _ = *b2::BUCKET_ID;
+    let _ = *secrets::ENVELOPE_KEY;
+
+    let resolver = domains::Resolver::new(domains::SystemResolver::new()?);
 
     rocket::ignite()
         .attach(OAuth2::<Gitea>::fairing("gitea"))
         .attach(MainDatabase::fairing())
         .attach(SpaceHelmet::default())
+        .attach(metrics::Metrics)
+        .attach(request_id::RequestIds)
+        .attach(AdHoc::on_attach(
+            "Execution events",
+            |rocket| match database_config("main_data", rocket.config()) {
+                Ok(cfg) => {
+                    events::listen(cfg.url.to_string());
+                    Ok(rocket)
+                }
+                Err(why) => {
+                    error!("can't find database config for events: {:?}", why);
+                    Err(rocket)
+                }
+            },
+        ))
+        .manage(resolver)
         .mount(
             "/api",
             routes![
                 api::handler::create,
                 api::handler::list,
+                api::handler::search,
                 api::handler::get,
                 api::handler::delete,
+                api::handler::undelete,
                 api::handler::get_config,
                 api::handler::create_config,
+                api::handler::import_config,
+                api::handler::export_config,
+                api::handler::get_effective_config,
+                api::handler::get_config_history,
+                api::handler::get_config_snapshot,
+                api::handler::restore_config,
                 api::handler::upload_version,
+                api::handler::list_versions,
+                api::handler::rename,
+                api::handler::update,
+                api::handler::set_visibility,
+                api::admin::collect_garbage,
+                api::alias::create,
+                api::alias::list,
+                api::alias::delete,
+                api::domain::create,
+                api::domain::list,
+                api::domain::verify,
+                api::domain::delete,
+                api::egress::get,
+                api::egress::set,
+                api::invocation_key::create,
+                api::invocation_key::list,
+                api::invocation_key::delete,
+                api::kv::list,
+                api::kv::get,
+                api::kv::set,
+                api::kv::delete,
+                api::config_group::create,
+                api::config_group::list,
+                api::config_group::get,
+                api::config_group::delete,
+                api::config_group::get_config,
+                api::config_group::set_config,
+                api::config_group::delete_config,
+                api::config_group::attach,
+                api::config_group::detach,
+                api::config_group::list_attached,
+                api::environment::create,
+                api::environment::list,
+                api::environment::get,
+                api::environment::delete,
+                api::environment::set_version,
+                api::environment::promote,
+                api::environment::get_config,
+                api::environment::set_config,
+                api::environment::delete_config,
+                api::environment::get_effective_config,
+                api::execution::list,
+                api::execution::get,
+                api::execution::logs,
+                api::execution::stream,
+                api::traffic::get,
+                api::traffic::set,
+                api::traffic::delete,
                 api::user::whoami,
                 api::user::get,
                 api::token::list,
                 api::token::delete,
+                api::token::undelete,
                 api::token::create,
             ],
         )
+        .mount(
+            "/",
+            routes![health::healthz, health::readyz, metrics::metrics],
+        )
         .mount("/login/gitea", routes![gitea::login, gitea::callback])
+        .register(api::catchers())
         .launch();
 
     Ok(())
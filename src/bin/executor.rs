@@ -5,21 +5,36 @@ extern crate rocket;
 #[macro_use]
 extern crate tracing;
 
-use diesel::prelude::*;
+use diesel::{pg::PgConnection, prelude::*};
+use lazy_static::lazy_static;
+use rocket::{
+    fairing::{AdHoc, Fairing, Info, Kind},
+    http::{uri::Origin, ContentType, Status},
+    request::{self, FromRequest, Request},
+    response::{self, Redirect, Responder, Response},
+    Data, Outcome, State,
+};
+use rocket_contrib::{databases::database_config, json::Json};
 use std::{
-    env, fs, io,
+    env, fs,
+    io::{self, Cursor, Read},
     path::PathBuf,
-    process::{self, Output},
-    time,
+    process::{self, ExitStatus, Stdio},
+    sync::mpsc,
+    thread, time,
 };
 use uuid::Uuid;
 use wasmcloud_api::api::Error::InternalServerError;
 use wasmcloud_api::{
     api::{
-        Error::{Database, Impossible, Subcommand},
+        Error::{Database, Impossible, NotFound, Subcommand},
         Result,
     },
-    models, schema, MainDatabase,
+    assets, auth, config, domains, egress,
+    events::{self, Event},
+    health, host, jwt, kv, logs, metrics, models, request_id, retention,
+    routing::{self, Resolved},
+    schema, secrets, telemetry, traffic, MainDatabase, MainDatabasePool,
 };
 
 // Name your user agent after your app?
@@ -38,102 +53,1289 @@ pub static TEMP_FOLDER: &str = concat!(
     "/"
 );
 
-#[instrument(skip(config), err)]
+/// Where uploaded modules and asset bundles are downloaded from.
+pub static CDN_URL: &str = "https://cdn.christine.website/file/wasmcloud-modules/";
+
+lazy_static! {
+    /// The port host calls are served on, apart from everything else.
+    static ref HOST_PORT: u16 = env::var("EXECUTOR_HOST_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(8002);
+    /// Guests make host calls and outbound requests through the executor,
+    /// this is where they can reach it.
+    static ref HOST_URL: String = env::var("EXECUTOR_HOST_URL")
+        .unwrap_or_else(|_| format!("http://127.0.0.1:{}", *HOST_PORT));
+    static ref HOST_CALLS: host::Sessions = host::Sessions::default();
+}
+
+#[instrument(skip(config, host_token, on_output), err)]
 fn execute(
     handler_id: Uuid,
-    config: Vec<models::HandlerConfig>,
+    environment: Option<String>,
+    trace: &telemetry::TraceContext,
+    config: Vec<config::Entry>,
     handler_path: PathBuf,
-) -> Result<(Output, time::Duration)> {
+    assets_path: Option<PathBuf>,
+    host_token: &str,
+    timeout: Option<time::Duration>,
+    on_output: impl FnMut(Output),
+) -> Result<Finished> {
+    // The policy keeps the guest from reaching anything but its host calls on
+    // the executor. Outbound HTTP goes through a proxy there, which checks the
+    // allow-list of the handler.
+    let host_url = format!("{}/host/{}/", *HOST_URL, host_token);
+    let policy_path = format!("{}{}.policy", TEMP_FOLDER, host_token);
+
+    // Every execution gets an empty scratch directory of its own, the asset
+    // bundle of the version (if any) is shared and read-only.
+    let scratch_path = PathBuf::from(format!("{}scratch/{}", TEMP_FOLDER, host_token));
+
     let mut child = process::Command::new("/usr/bin/env");
     let child = child.arg("pahi");
+    let child = child.arg("--policy").arg(&policy_path);
+    let mut child = child.arg("--mapdir").arg(format!(
+        "{}::{}",
+        assets::GUEST_SCRATCH_DIR,
+        scratch_path.display()
+    ));
+    if let Some(assets_path) = assets_path.as_ref() {
+        child = child.arg("--mapdir").arg(format!(
+            "{}::{}",
+            assets::GUEST_ASSETS_DIR,
+            assets_path.display()
+        ));
+    }
     let child = child.arg(handler_path);
-    let mut child = child.env("HANDLER_ID", handler_id.to_string());
+    let mut child = child
+        .env("HANDLER_ID", handler_id.to_string())
+        .env("TRACEPARENT", trace.to_string())
+        .env("WASMCLOUD_HTTP_PROXY", format!("{}http", host_url))
+        .env("WASMCLOUD_KV_URL", format!("{}kv", host_url));
+
+    if let Some(environment) = environment {
+        child = child.env("HANDLER_ENVIRONMENT", environment);
+    }
+    if assets_path.is_some() {
+        child = child.env("WASMCLOUD_ASSETS_DIR", assets::GUEST_ASSETS_DIR);
+    }
+    child = child.env("WASMCLOUD_SCRATCH_DIR", assets::GUEST_SCRATCH_DIR);
 
     for kv in config.into_iter() {
-        child = child.env(kv.key_name, kv.value_contents);
+        let value = if kv.is_secret {
+            secrets::open(
+                kv.source.owner(handler_id),
+                &kv.key_name,
+                &kv.value_contents,
+            )
+            .map_err(InternalServerError)?
+        } else {
+            kv.value_contents
+        };
+        child = child.env(kv.key_name, value);
     }
 
+    fs::write(&policy_path, host::policy(&host_url))?;
+    fs::create_dir_all(&scratch_path)?;
+
     debug!("running");
     let start = time::Instant::now();
-    let output = child.output().map_err(Subcommand)?;
+    let finished = wait(
+        child.stdout(Stdio::piped()).stderr(Stdio::piped()),
+        timeout,
+        on_output,
+    );
     let duration = start.elapsed();
+    let _ = fs::remove_file(&policy_path);
+    if let Err(why) = fs::remove_dir_all(&scratch_path) {
+        error!("can't clean up scratch directory: {}", why);
+    }
+
+    let (status, stdout, stderr) = finished.map_err(Subcommand)?;
+    Ok(Finished {
+        status,
+        stdout,
+        stderr,
+        duration,
+    })
+}
+
+#[derive(Debug)]
+struct Finished {
+    status: ExitStatus,
+    stdout: logs::Captured,
+    stderr: logs::Captured,
+    duration: time::Duration,
+}
+
+/// What a running module did, as wait reports it.
+enum Output<'a> {
+    Line(logs::Stream, &'a logs::Line),
+    /// Nothing was written for a while.
+    Quiet,
+}
+
+/// Runs a module and reads both of its output streams as they are written.
+/// Every line is passed to on_output on the calling thread as it comes in, and
+/// when the module has been quiet for events::FLUSH_INTERVAL. A module that is
+/// still running when the timeout is up gets killed.
+fn wait(
+    cmd: &mut process::Command,
+    timeout: Option<time::Duration>,
+    mut on_output: impl FnMut(Output),
+) -> io::Result<(ExitStatus, logs::Captured, logs::Captured)> {
+    let mut child = cmd.spawn()?;
+    let stdout = child.stdout.take().ok_or(io::ErrorKind::BrokenPipe)?;
+    let stderr = child.stderr.take().ok_or(io::ErrorKind::BrokenPipe)?;
+
+    let (tx, rx) = mpsc::channel();
+    let stdout = read_lines(logs::Stream::Stdout, stdout, tx.clone());
+    let stderr = read_lines(logs::Stream::Stderr, stderr, tx);
+
+    // This ends once both readers are done and have dropped their senders.
+    let deadline = timeout.map(|timeout| time::Instant::now() + timeout);
+    let mut killed = false;
+    loop {
+        let now = time::Instant::now();
+        let timed_out = match deadline {
+            Some(deadline) => !killed && now >= deadline,
+            None => false,
+        };
+        if timed_out {
+            info!("execution timed out, killing it");
+            child.kill()?;
+            killed = true;
+        }
+
+        let wait_for = match deadline {
+            Some(deadline) if !killed => deadline
+                .saturating_duration_since(now)
+                .min(events::FLUSH_INTERVAL),
+            _ => events::FLUSH_INTERVAL,
+        };
+        match rx.recv_timeout(wait_for) {
+            Ok((stream, line)) => on_output(Output::Line(stream, &line)),
+            Err(mpsc::RecvTimeoutError::Timeout) => on_output(Output::Quiet),
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    let status = child.wait()?;
+    let stdout = stdout
+        .join()
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "stdout reader panicked"))?;
+    let stderr = stderr
+        .join()
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "stderr reader panicked"))?;
+
+    Ok((status, stdout, stderr))
+}
+
+fn read_lines(
+    stream: logs::Stream,
+    pipe: impl Read + Send + 'static,
+    tx: mpsc::Sender<(logs::Stream, logs::Line)>,
+) -> thread::JoinHandle<logs::Captured> {
+    thread::spawn(move || {
+        logs::capture(stream, pipe, *logs::STREAM_LIMIT, |line| {
+            let _ = tx.send((stream, line.clone()));
+        })
+    })
+}
+
+/// The headers a guest describes its outbound request with. The body of the
+/// request to the proxy is sent as-is.
+pub static EGRESS_URL_HEADER: &str = "X-Wasmcloud-Url";
+pub static EGRESS_METHOD_HEADER: &str = "X-Wasmcloud-Method";
+
+#[derive(Debug)]
+struct Outbound {
+    method: String,
+    url: String,
+    content_type: Option<String>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Outbound {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        match headers.get_one(EGRESS_URL_HEADER) {
+            None => Outcome::Failure((Status::BadRequest, ())),
+            Some(url) => Outcome::Success(Outbound {
+                method: headers
+                    .get_one(EGRESS_METHOD_HEADER)
+                    .unwrap_or("GET")
+                    .to_ascii_uppercase(),
+                url: url.to_string(),
+                content_type: headers.get_one("Content-Type").map(str::to_string),
+            }),
+        }
+    }
+}
+
+/// The answer to an outbound request of a guest. Requests the executor refused
+/// to make or that failed get a 502 with the reason.
+#[derive(Debug)]
+enum Proxied {
+    Response(egress::Response),
+    Refused(String),
+}
+
+impl<'a> Responder<'a> for Proxied {
+    fn respond_to(self, _: &Request) -> response::Result<'a> {
+        match self {
+            Proxied::Response(resp) => {
+                let mut build = Response::build();
+                build
+                    .status(Status::raw(resp.status))
+                    .sized_body(Cursor::new(resp.body));
+                if let Some(ct) = resp
+                    .content_type
+                    .and_then(|ct| ContentType::parse_flexible(&ct))
+                {
+                    build.header(ct);
+                }
+                build.ok()
+            }
+            Proxied::Refused(why) => Response::build()
+                .header(ContentType::Plain)
+                .status(Status::BadGateway)
+                .sized_body(Cursor::new(why))
+                .ok(),
+        }
+    }
+}
+
+#[post("/host/<token>/http", data = "<body>")]
+#[instrument(skip(token, body), err)]
+fn host_http(token: String, outbound: Outbound, body: Data) -> Result<Proxied> {
+    let mut buf = vec![];
+    body.open()
+        .take(*egress::MAX_REQUEST_BYTES + 1)
+        .read_to_end(&mut buf)?;
+    if buf.len() as u64 > *egress::MAX_REQUEST_BYTES {
+        return Ok(Proxied::Refused(format!(
+            "request is larger than {} bytes",
+            *egress::MAX_REQUEST_BYTES
+        )));
+    }
+
+    let req = egress::Request {
+        method: outbound.method,
+        url: outbound.url,
+        content_type: outbound.content_type,
+        body: buf,
+    };
+
+    let caller = HOST_CALLS
+        .caller(&token)
+        .ok_or_else(|| NotFound("execution".into()))?;
+    let (result, log) = egress::fetch(&caller.allow, req);
+    HOST_CALLS.record(&token, log);
+
+    match result {
+        Ok(resp) => Ok(Proxied::Response(resp)),
+        Err(why) => Ok(Proxied::Refused(why)),
+    }
+}
+
+fn host_caller(token: &str) -> Result<host::Caller> {
+    HOST_CALLS
+        .caller(token)
+        .ok_or_else(|| NotFound("execution".into()))
+}
+
+#[get("/host/<token>/kv?<prefix>")]
+#[instrument(skip(token, conn), err)]
+fn host_kv_list(
+    token: String,
+    prefix: Option<String>,
+    conn: MainDatabase,
+) -> Result<Json<Vec<String>>> {
+    let caller = host_caller(&token)?;
+
+    Ok(Json(
+        kv::list(&*conn, caller.handler_id, prefix.as_deref())
+            .map_err(Database)?
+            .into_iter()
+            .map(|kv| kv.key_name)
+            .collect(),
+    ))
+}
+
+#[get("/host/<token>/kv/<key>")]
+#[instrument(skip(token, conn), err)]
+fn host_kv_get(token: String, key: String, conn: MainDatabase) -> Result<Vec<u8>> {
+    let caller = host_caller(&token)?;
+
+    kv::get(&*conn, caller.handler_id, &key)
+        .map_err(Database)?
+        .map(|kv| kv.value)
+        .ok_or_else(|| NotFound(format!("key {:?}", key)))
+}
+
+/// Stores the request body under a key, for `ttl` seconds if given.
+#[put("/host/<token>/kv/<key>?<ttl>", data = "<body>")]
+#[instrument(skip(token, body, conn), err)]
+fn host_kv_put(
+    token: String,
+    key: String,
+    ttl: Option<u32>,
+    body: Data,
+    conn: MainDatabase,
+) -> Result {
+    let caller = host_caller(&token)?;
+    let quota = kv::quota(caller.tier) as u64;
+
+    let mut value = vec![];
+    body.open().take(quota + 1).read_to_end(&mut value)?;
+    let ttl = ttl.map(|secs| chrono::Duration::seconds(secs as i64));
+    kv::put(&*conn, caller.handler_id, caller.tier, &key, value, ttl)?;
+
+    Ok(())
+}
 
-    Ok((output, duration))
+#[delete("/host/<token>/kv/<key>")]
+#[instrument(skip(token, conn), err)]
+fn host_kv_delete(token: String, key: String, conn: MainDatabase) -> Result {
+    let caller = host_caller(&token)?;
+
+    kv::delete(&*conn, caller.handler_id, &key).map_err(Database)?;
+    Ok(())
+}
+
+/// The header that keeps a client on the same version of a handler while its
+/// traffic is split between versions.
+pub static ROUTE_KEY_HEADER: &str = "X-Wasmcloud-Route-Key";
+
+#[derive(Debug)]
+struct RouteKey(Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for RouteKey {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(RouteKey(
+            request
+                .headers()
+                .get_one(ROUTE_KEY_HEADER)
+                .map(|key| key.to_string()),
+        ))
+    }
+}
+
+/// The header that carries an invocation key for handlers that need one.
+pub static INVOKE_KEY_HEADER: &str = "X-Wasmcloud-Invoke-Key";
+
+/// Whatever a request brought to prove it may run a private handler. This is
+/// only checked once the handler is known, public handlers ignore it.
+#[derive(Debug)]
+struct Invoker(auth::Credentials);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Invoker {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        Outcome::Success(Invoker(auth::Credentials {
+            invocation_key: headers.get_one(INVOKE_KEY_HEADER).map(str::to_string),
+            token: headers.get_one("Authorization").map(|tok| {
+                tok.trim_start_matches("Bearer ")
+                    .trim_start_matches("bearer ")
+                    .to_string()
+            }),
+        }))
+    }
+}
+
+/// The trace an invocation is part of, from its `traceparent` header. Without
+/// one the execution starts a new trace.
+#[derive(Debug)]
+struct Traced(Option<telemetry::TraceContext>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Traced {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(Traced(
+            request
+                .headers()
+                .get_one(telemetry::TRACEPARENT_HEADER)
+                .and_then(telemetry::TraceContext::parse),
+        ))
+    }
+}
+
+/// What invoking a handler resulted in.
+#[derive(Responder, Debug)]
+enum Invoked {
+    Ran(()),
+    /// The handler was renamed, the client should use its new name.
+    Moved(Redirect),
 }
 
 #[get("/run/<handler_name>")]
 #[instrument(skip(conn), err)]
-fn schedule(handler_name: String, conn: MainDatabase) -> Result {
-    fs::create_dir_all(TEMP_FOLDER)?;
-    let hdl = {
-        use schema::handlers::dsl::{handlers, human_name};
-        handlers
-            .filter(human_name.eq(handler_name))
-            .first::<models::Handler>(&*conn)
-            .map_err(Database)
-    }?;
-
-    let cfg = {
-        use schema::handler_config::dsl::{handler_config, handler_id};
-        handler_config
-            .filter(handler_id.eq(hdl.id.clone()))
-            .load::<models::HandlerConfig>(&*conn)
-            .map_err(Database)
-    }?;
-
-    let u = url::Url::parse(&hdl.current_version.ok_or(Impossible)?).map_err(|_| Impossible)?;
-    debug!("{:?}", u.host_str().ok_or(Impossible)?);
+fn schedule(
+    handler_name: String,
+    route_key: RouteKey,
+    invoker: Invoker,
+    trace: Traced,
+    conn: MainDatabase,
+) -> Result<Invoked> {
+    match routing::by_name(&*conn, &handler_name)? {
+        Resolved::Handler(hdl) => {
+            run(&*conn, hdl, None, route_key, invoker, trace).map(Invoked::Ran)
+        }
+        Resolved::Moved(hdl) => Ok(Invoked::Moved(Redirect::permanent(format!(
+            "/run/{}",
+            hdl.human_name
+        )))),
+    }
+}
+
+#[get("/run/<handler_name>/env/<env_name>")]
+#[instrument(skip(conn), err)]
+fn schedule_environment(
+    handler_name: String,
+    env_name: String,
+    invoker: Invoker,
+    trace: Traced,
+    conn: MainDatabase,
+) -> Result<Invoked> {
+    match routing::by_name(&*conn, &handler_name)? {
+        Resolved::Handler(hdl) => {
+            let env = routing::environment(&*conn, &hdl, &env_name)?;
+            run(&*conn, hdl, Some(env), RouteKey(None), invoker, trace).map(Invoked::Ran)
+        }
+        Resolved::Moved(hdl) => Ok(Invoked::Moved(Redirect::permanent(format!(
+            "/run/{}/env/{}",
+            hdl.human_name, env_name
+        )))),
+    }
+}
+
+#[get("/run/id/<hdl_id>")]
+#[instrument(skip(conn), err)]
+fn schedule_id(
+    hdl_id: rocket_contrib::uuid::Uuid,
+    route_key: RouteKey,
+    invoker: Invoker,
+    trace: Traced,
+    conn: MainDatabase,
+) -> Result {
+    let hdl = routing::by_id(&*conn, hdl_id.into_inner())?;
+    run(&*conn, hdl, None, route_key, invoker, trace)
+}
+
+#[get("/run/id/<hdl_id>/env/<env_name>")]
+#[instrument(skip(conn), err)]
+fn schedule_id_environment(
+    hdl_id: rocket_contrib::uuid::Uuid,
+    env_name: String,
+    invoker: Invoker,
+    trace: Traced,
+    conn: MainDatabase,
+) -> Result {
+    let hdl = routing::by_id(&*conn, hdl_id.into_inner())?;
+    let env = routing::environment(&*conn, &hdl, &env_name)?;
+    run(&*conn, hdl, Some(env), RouteKey(None), invoker, trace)
+}
+
+/// Aliases can be custom paths with several segments. This route is ranked
+/// below the others so that it only catches paths they don't match.
+#[get("/run/<path..>", rank = 10)]
+#[instrument(skip(conn), err)]
+fn schedule_path(
+    path: PathBuf,
+    route_key: RouteKey,
+    invoker: Invoker,
+    trace: Traced,
+    conn: MainDatabase,
+) -> Result<Invoked> {
+    let path = path.to_string_lossy();
+    match routing::by_name(&*conn, &path)? {
+        Resolved::Handler(hdl) => {
+            run(&*conn, hdl, None, route_key, invoker, trace).map(Invoked::Ran)
+        }
+        Resolved::Moved(hdl) => Ok(Invoked::Moved(Redirect::permanent(format!(
+            "/run/{}",
+            hdl.human_name
+        )))),
+    }
+}
+
+/// Where requests for custom domains and wildcard subdomains are sent, see
+/// HostRouting.
+static HOST_ROUTES: &str = "/_host";
+
+/// What the `Host` header of a request resolved to, kept in its local cache.
+/// None means the request is for the executor itself.
+struct HostLookup(Option<Resolved>);
+
+/// Wildcard subdomains without a handler are left to the executor's own
+/// routes, as are requests whose host can't be looked up, so that it can still
+/// tell it is alive without its database.
+fn lookup_host(request: &Request) -> HostLookup {
+    let host = match request.headers().get_one("Host") {
+        Some(host) => host,
+        None => return HostLookup(None),
+    };
+    let conn = match request.guard::<MainDatabase>() {
+        Outcome::Success(conn) => conn,
+        _ => {
+            error!("can't resolve host {}: no database connection", host);
+            return HostLookup(None);
+        }
+    };
+
+    match domains::by_host(&*conn, host) {
+        Ok(resolved) => HostLookup(resolved),
+        Err(NotFound(_)) => HostLookup(None),
+        Err(why) => {
+            error!("can't resolve host {}: {}", host, why);
+            HostLookup(None)
+        }
+    }
+}
+
+/// Sends requests for custom domains and wildcard subdomains to their handler,
+/// whatever their path. Otherwise the executor's own routes, like `/healthz`
+/// or `/run/...`, would answer them in place of the handler.
+struct HostRouting;
+
+impl Fairing for HostRouting {
+    fn info(&self) -> Info {
+        Info {
+            name: "Host routing",
+            kind: Kind::Request,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let lookup = lookup_host(request);
+        let routed = lookup.0.is_some();
+        request.local_cache(|| lookup);
+        if !routed {
+            return;
+        }
+
+        let routed = {
+            let uri = request.uri();
+            let path = if uri.path() == "/" { "" } else { uri.path() };
+            match uri.query() {
+                Some(query) => format!("{}{}?{}", HOST_ROUTES, path, query),
+                None => format!("{}{}", HOST_ROUTES, path),
+            }
+        };
+        match Origin::parse_owned(routed) {
+            Ok(uri) => request.set_uri(uri),
+            Err(why) => error!("can't route request by host: {}", why),
+        }
+    }
+}
+
+/// The handler a request is for according to its `Host` header. Requests for
+/// hosts that are neither verified custom domains nor handlers under the
+/// wildcard domain are forwarded to the other routes.
+#[derive(Debug)]
+struct HostHandler(Resolved);
+
+impl<'a, 'r> FromRequest<'a, 'r> for HostHandler {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match &request.local_cache(|| lookup_host(request)).0 {
+            Some(resolved) => Outcome::Success(HostHandler(resolved.clone())),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+/// Custom domains and wildcard subdomains serve their handler at every path,
+/// HostRouting sends their requests here.
+#[get("/_host")]
+#[instrument(skip(conn), err)]
+fn schedule_host_root(
+    handler: HostHandler,
+    route_key: RouteKey,
+    invoker: Invoker,
+    trace: Traced,
+    conn: MainDatabase,
+) -> Result<Invoked> {
+    schedule_host(handler, PathBuf::new(), route_key, invoker, trace, conn)
+}
+
+#[get("/_host/<path..>")]
+#[instrument(skip(conn), err)]
+fn schedule_host(
+    handler: HostHandler,
+    path: PathBuf,
+    route_key: RouteKey,
+    invoker: Invoker,
+    trace: Traced,
+    conn: MainDatabase,
+) -> Result<Invoked> {
+    match handler.0 {
+        Resolved::Handler(hdl) => {
+            run(&*conn, hdl, None, route_key, invoker, trace).map(Invoked::Ran)
+        }
+        // Only wildcard subdomains can move, custom domains point at a handler
+        // by its ID.
+        Resolved::Moved(hdl) => {
+            let wildcard = domains::WILDCARD_DOMAIN.as_ref().ok_or(Impossible)?;
+            Ok(Invoked::Moved(Redirect::permanent(format!(
+                "https://{}.{}/{}",
+                hdl.human_name,
+                wildcard,
+                path.to_string_lossy()
+            ))))
+        }
+    }
+}
+
+/// Whether the executor can run handlers: it needs its database, the CDN
+/// modules are downloaded from, the wasm runtime and somewhere to put the
+/// modules.
+#[get("/readyz")]
+fn readyz(pool: State<MainDatabasePool>) -> health::Readiness {
+    health::Readiness::default()
+        .check("database", health::database(&pool))
+        .check("storage", health::cdn(CDN_URL))
+        .check("runtime", health::executable("pahi"))
+        .check("temp_dir", health::writable(TEMP_FOLDER.as_ref()))
+}
+
+/// Whether a file that was already downloaded (or unpacked) could be used.
+fn count_cache(kind: &str, hit: bool) {
+    metrics::DOWNLOAD_CACHE
+        .with_label_values(&[kind, if hit { "hit" } else { "miss" }])
+        .inc();
+}
+
+/// Downloads an uploaded file by its `b2://` URL into the temporary folder.
+/// Uploads are content-addressed, a file that was downloaded before is used
+/// as-is. Files are downloaded next to where they go and renamed into place,
+/// so a half-done download is never mistaken for a whole one. The file is
+/// kept from being cleaned up until what this returns is dropped.
+#[instrument(err)]
+fn download(u: &url::Url) -> Result<retention::InUse> {
+    let name = u.host_str().ok_or(Impossible)?;
+    let kind = if name.ends_with(".tar") {
+        "assets"
+    } else {
+        "module"
+    };
     // https://cdn.christine.website/file/christine-static/stickers/mara/hacker.png
-    let hdl_url = format!(
-        "https://cdn.christine.website/file/wasmcloud-modules/{}",
-        u.host_str().ok_or(Impossible)?
-    );
-    let fname = format!("{}{}", TEMP_FOLDER, u.host_str().unwrap());
+    let hdl_url = format!("{}{}", CDN_URL, name);
+    let fname = format!("{}{}", TEMP_FOLDER, name);
 
-    debug!(url = &hdl_url[..], fname = &fname[..], "downloading module");
+    let held = retention::InUse::new(&fname);
+    let hit = held.path().exists();
+    count_cache(kind, hit);
+    if hit {
+        return Ok(held);
+    }
+
+    debug!(url = &hdl_url[..], fname = &fname[..], "downloading");
     let resp = ureq::get(&hdl_url).set("User-Agent", APP_USER_AGENT).call();
     if resp.ok() {
-        let mut fout = fs::File::create(&fname).map_err(|why| {
+        let partial = format!("{}.partial-{}", fname, Uuid::new_v4());
+        let mut fout = fs::File::create(&partial).map_err(|why| {
             error!("can't make file: {}", why);
             Subcommand(why)
         })?;
-        io::copy(&mut resp.into_reader(), &mut fout).map_err(|why| Subcommand(why))?;
+        let size = match io::copy(&mut resp.into_reader(), &mut fout) {
+            Ok(size) => size,
+            Err(why) => {
+                let _ = fs::remove_file(&partial);
+                return Err(Subcommand(why));
+            }
+        };
+        fs::rename(&partial, &fname)?;
+
+        metrics::DOWNLOADS.with_label_values(&[kind]).inc();
+        metrics::DOWNLOAD_BYTES
+            .with_label_values(&[kind])
+            .inc_by(size as i64);
     } else {
         error!("while fetching url: {}", resp.status_line());
         return Err(Impossible);
     }
 
-    let (output, duration) = execute(hdl.id, cfg, fname.into()).map_err(|why| {
-        error!("error running module: {}", why);
-        InternalServerError(why.into())
-    })?;
-    info!(
-        duration = duration.as_millis() as i64,
-        module = u.path(),
-        "execution finished"
-    );
+    Ok(held)
+}
+
+/// Runs a handler, either as-is or in one of its environments, which brings
+/// its own version and config overlay. Outside of environments, the traffic
+/// split of the handler (if any) decides which version runs. Private handlers
+/// are refused before anything is downloaded.
+#[instrument(skip(conn), err)]
+fn run(
+    conn: &PgConnection,
+    hdl: models::Handler,
+    env: Option<models::HandlerEnvironment>,
+    route_key: RouteKey,
+    invoker: Invoker,
+    trace: Traced,
+) -> Result {
+    // The execution is a span of its own within the trace of the request. The
+    // guest is handed the context of that span, so that its spans are
+    // children of it.
+    let span = tracing::Span::current();
+    if let Some(parent) = trace.0.as_ref() {
+        telemetry::adopt(&span, parent);
+    }
+    let trace = telemetry::current(&span).unwrap_or_else(|| match trace.0 {
+        Some(parent) => parent.child(),
+        None => telemetry::TraceContext::new(),
+    });
+    info!(trace_id = trace.trace_id.as_str(), "running handler");
+
+    auth::authorize(conn, &hdl, &invoker.0)?;
+    fs::create_dir_all(TEMP_FOLDER)?;
+
+    // Versions are looked up by ID, they can share a module. Only modules
+    // uploaded before versions were kept have none.
+    let (module_url, version_id, cfg) = match env.as_ref() {
+        Some(env) => (
+            env.current_version.clone(),
+            env.version_id,
+            config::effective_in(conn, env).map_err(Database)?,
+        ),
+        None => {
+            let split = traffic::split(conn, hdl.id).map_err(Database)?;
+            let (module_url, version_id) =
+                match traffic::pick(&split, hdl.id, route_key.0.as_deref()) {
+                    Some(version_id) => (None, Some(version_id)),
+                    None => (hdl.current_version.clone(), hdl.current_version_id),
+                };
 
-    diesel::insert_into(schema::executions::table)
+            (
+                module_url,
+                version_id,
+                config::effective(conn, hdl.id).map_err(Database)?,
+            )
+        }
+    };
+    let version_rec = match version_id {
+        Some(version_id) => {
+            use schema::handler_versions::dsl::handler_versions;
+            Some(
+                handler_versions
+                    .find(version_id)
+                    .get_result::<models::HandlerVersion>(conn)
+                    .map_err(Database)?,
+            )
+        }
+        None => None,
+    };
+    let version = version_rec
+        .as_ref()
+        .map(|v| v.module_url.clone())
+        .or(module_url)
+        .ok_or_else(|| NotFound("handler has no uploaded version".into()))?;
+
+    let u = url::Url::parse(&version).map_err(|_| Impossible)?;
+    let module = download(&u)?;
+
+    let bundle = match version_rec.and_then(|v| v.assets_url) {
+        Some(assets_url) => {
+            let u = url::Url::parse(&assets_url).map_err(|_| Impossible)?;
+            let name = u.host_str().ok_or(Impossible)?.trim_end_matches(".tar");
+            let dest = retention::InUse::new(format!("{}assets/{}", TEMP_FOLDER, name));
+            // Bundles that are unpacked already don't need their archive.
+            if dest.path().exists() {
+                count_cache("bundle", true);
+            } else {
+                count_cache("bundle", false);
+                let archive = download(&u)?;
+                fs::create_dir_all(format!("{}assets", TEMP_FOLDER))?;
+                assets::unpack(archive.path(), dest.path()).map_err(InternalServerError)?;
+            }
+            Some(dest)
+        }
+        None => None,
+    };
+
+    let owner = {
+        use schema::users::dsl::users;
+        users
+            .find(hdl.user_id)
+            .get_result::<models::User>(conn)
+            .map_err(Database)?
+    };
+    let allow = egress::allow_list(conn, hdl.id).map_err(Database)?;
+
+    // The execution is recorded before it runs so that anyone following the
+    // handler can tell which execution the log lines belong to.
+    let execution = diesel::insert_into(schema::executions::table)
         .values(&models::NewExecution {
             handler_id: hdl.id,
-            finished: true,
-            stderr: Some(String::from_utf8(output.stderr).map_err(|_| Impossible)?), // XXX(Cadey): this is not impossible
-            execution_time: duration.as_millis() as i32,
+            finished: false,
+            stderr: None,
+            execution_time: None,
+            version_id,
+            exit_code: None,
+            stdout: None,
+            trace_id: Some(trace.trace_id.clone()),
         })
-        .execute(&*conn)
+        .get_result::<models::Execution>(conn)
         .map_err(Database)?;
+    events::notify(
+        conn,
+        Event::Started {
+            handler_id: hdl.id,
+            execution_id: execution.id,
+            version_id,
+        },
+    );
+
+    let host_token = HOST_CALLS.open(host::Caller {
+        handler_id: hdl.id,
+        tier: owner.tier,
+        allow,
+    });
+    let env_name = env.map(|env| env.name);
+    let (mut stdout_lines, mut stderr_lines) = (0, 0);
+    let mut batch = events::Batch::new(conn);
+    let start = time::Instant::now();
+    let result = execute(
+        hdl.id,
+        env_name,
+        &trace,
+        cfg,
+        module.path().to_path_buf(),
+        bundle.as_ref().map(|b| b.path().to_path_buf()),
+        &host_token,
+        hdl.timeout_ms
+            .map(|ms| time::Duration::from_millis(ms as u64)),
+        |output| match output {
+            Output::Line(stream, line) => {
+                let count = match stream {
+                    logs::Stream::Stdout => &mut stdout_lines,
+                    logs::Stream::Stderr => &mut stderr_lines,
+                };
+                *count += 1;
+                batch.push(Event::Log {
+                    handler_id: hdl.id,
+                    execution_id: execution.id,
+                    stream: stream.as_str().to_string(),
+                    line_number: *count,
+                    message: line.message.clone(),
+                });
+            }
+            Output::Quiet => batch.flush(),
+        },
+    );
+    drop(batch);
+    let requests = HOST_CALLS.close(&host_token);
+    let finished = match result {
+        Ok(finished) => finished,
+        Err(why) => {
+            error!("error running module: {}", why);
+            metrics::EXECUTIONS
+                .with_label_values(&[&hdl.id.to_string(), "error"])
+                .inc();
+            finish(conn, &execution, None, start.elapsed().as_millis() as i32);
+            return Err(InternalServerError(why.into()));
+        }
+    };
+    info!(
+        duration = finished.duration.as_millis() as i64,
+        module = u.path(),
+        "execution finished"
+    );
+    let outcome = if finished.status.success() {
+        "success"
+    } else {
+        "failure"
+    };
+    metrics::EXECUTIONS
+        .with_label_values(&[&hdl.id.to_string(), outcome])
+        .inc();
+    metrics::EXECUTION_DURATION
+        .with_label_values(&[&hdl.id.to_string()])
+        .observe(finished.duration.as_secs_f64());
+
+    {
+        use schema::executions::dsl::{executions, stderr, stdout};
+        diesel::update(executions.find(execution.id))
+            .set((
+                stderr.eq(finished.stderr.text()),
+                stdout.eq(finished.stdout.text()),
+            ))
+            .execute(conn)
+            .map_err(Database)?;
+    }
+
+    let lines: Vec<_> = finished
+        .stdout
+        .into_records(execution.id)
+        .into_iter()
+        .chain(finished.stderr.into_records(execution.id))
+        .collect();
+    // Postgres takes at most 65535 bind parameters per statement.
+    for chunk in lines.chunks(1000) {
+        diesel::insert_into(schema::execution_logs::table)
+            .values(chunk)
+            .execute(conn)
+            .map_err(Database)?;
+    }
+
+    if !requests.is_empty() {
+        diesel::insert_into(schema::execution_http_requests::table)
+            .values(
+                &requests
+                    .into_iter()
+                    .map(|r| r.into_record(execution.id))
+                    .collect::<Vec<_>>(),
+            )
+            .execute(conn)
+            .map_err(Database)?;
+    }
+
+    finish(
+        conn,
+        &execution,
+        finished.status.code(),
+        finished.duration.as_millis() as i32,
+    );
+
+    if version_id.is_some() {
+        if let Err(why) = traffic::check_canary(conn, hdl.id) {
+            error!("can't check canary: {}", why);
+        }
+    }
 
     Ok(())
 }
 
+/// Marks an execution as finished and tells everyone following its handler.
+/// This is done last so that its logs are stored by the time it shows up as
+/// finished.
+fn finish(conn: &PgConnection, execution: &models::Execution, code: Option<i32>, duration_ms: i32) {
+    use schema::executions::dsl::{execution_time, executions, exit_code, finished};
+
+    if let Err(why) = diesel::update(executions.find(execution.id))
+        .set((
+            finished.eq(true),
+            exit_code.eq(code),
+            execution_time.eq(duration_ms),
+        ))
+        .execute(conn)
+    {
+        error!("can't finish execution {}: {}", execution.id, why);
+    }
+
+    events::notify(
+        conn,
+        Event::Finished {
+            handler_id: execution.handler_id,
+            execution_id: execution.id,
+            exit_code: code,
+            execution_time: duration_ms,
+        },
+    );
+}
+
+/// Host calls are served by a listener of their own, with its own workers and
+/// database pool. Guests make them while their execution holds a worker and a
+/// connection of the main listener, so sharing those would deadlock once
+/// enough executions run at the same time.
+fn host_calls(config: rocket::Config) -> rocket::Rocket {
+    rocket::custom(config)
+        .attach(MainDatabase::fairing())
+        .attach(metrics::Metrics)
+        .attach(request_id::RequestIds)
+        .mount(
+            "/",
+            routes![
+                host_http,
+                host_kv_list,
+                host_kv_get,
+                host_kv_put,
+                host_kv_delete,
+            ],
+        )
+        .register(wasmcloud_api::api::catchers())
+}
+
 fn main() -> color_eyre::eyre::Result<()> {
     color_eyre::install()?;
-    tracing_subscriber::fmt::init();
+    let _telemetry = telemetry::init("wasmcloud-executor")?;
     std::env::set_var("ROCKET_PORT", "8001"); // XXX(Cadey): so I can test both on my machine at once
 
-    rocket::ignite()
+    // Secret config values can only be decrypted with the envelope key, so
+    // fail early if it is missing.
+    let _ = *secrets::ENVELOPE_KEY;
+    // Handlers that only accept JWTs can't be run without it.
+    let _ = *jwt::SECRET;
+
+    let rocket = rocket::ignite();
+    let mut config = rocket.config().clone();
+    config.set_port(*HOST_PORT);
+    thread::spawn(move || {
+        let why = host_calls(config).launch();
+        error!("host call listener stopped: {}", why);
+        process::exit(1);
+    });
+
+    rocket
         .attach(MainDatabase::fairing())
-        .mount("/", routes![schedule])
+        .attach(HostRouting)
+        .attach(metrics::Metrics)
+        .attach(request_id::RequestIds)
+        .attach(AdHoc::on_attach(
+            "Retention",
+            |rocket| match database_config("main_data", rocket.config()) {
+                Ok(cfg) => {
+                    retention::spawn(cfg.url.to_string(), TEMP_FOLDER.into());
+                    Ok(rocket)
+                }
+                Err(why) => {
+                    error!("can't find database config for retention: {:?}", why);
+                    Err(rocket)
+                }
+            },
+        ))
+        .mount(
+            "/",
+            routes![
+                schedule,
+                schedule_environment,
+                schedule_id,
+                schedule_id_environment,
+                schedule_path,
+                schedule_host_root,
+                schedule_host,
+                health::healthz,
+                readyz,
+                metrics::metrics,
+            ],
+        )
+        .register(wasmcloud_api::api::catchers())
         .launch();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::config::{Config, Environment, Value};
+    use std::{
+        collections::HashMap,
+        net::TcpStream,
+        sync::{Arc, Barrier},
+    };
+
+    /// How many executions make host calls at the same time, more than the
+    /// listener has workers and connections.
+    const EXECUTIONS: usize = 8;
+
+    fn database_url() -> String {
+        env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL to be populated")
+    }
+
+    fn databases() -> HashMap<&'static str, Value> {
+        let mut database = HashMap::new();
+        database.insert("url", Value::from(database_url()));
+        database.insert("pool_size", Value::from(2));
+        let mut databases = HashMap::new();
+        databases.insert("main_data", Value::from(database));
+        databases
+    }
+
+    /// Makes a handler that the executor can see from connections of its own,
+    /// unlike the fixtures that are never committed.
+    fn committed_handler(conn: &PgConnection) -> models::Handler {
+        let user: models::User = diesel::insert_into(schema::users::table)
+            .values(&models::NewUser {
+                email: format!("executor-{}@example.com", Uuid::new_v4()),
+                salutation: "Tester".to_string(),
+                is_admin: false,
+                is_locked: false,
+                tier: 0,
+            })
+            .get_result(conn)
+            .unwrap();
+        diesel::insert_into(schema::handlers::table)
+            .values(&models::NewHandler {
+                user_id: user.id,
+                human_name: format!("executor-{}", Uuid::new_v4()),
+                current_version: None,
+                async_impl: false,
+                visibility: "public".to_string(),
+                description: String::new(),
+                tags: vec![],
+            })
+            .get_result(conn)
+            .unwrap()
+    }
+
+    fn remove_handler(conn: &PgConnection, hdl: &models::Handler) {
+        use schema::{custom_domains, handler_kv, handlers, users};
+
+        diesel::delete(handler_kv::table.filter(handler_kv::handler_id.eq(hdl.id)))
+            .execute(conn)
+            .unwrap();
+        diesel::delete(custom_domains::table.filter(custom_domains::handler_id.eq(hdl.id)))
+            .execute(conn)
+            .unwrap();
+        diesel::delete(handlers::table.find(hdl.id))
+            .execute(conn)
+            .unwrap();
+        diesel::delete(users::table.find(hdl.user_id))
+            .execute(conn)
+            .unwrap();
+    }
+
+    /// Starts a host call listener on the test database.
+    fn listen() -> String {
+        let port = 20_000 + (rand::random::<u16>() % 20_000);
+        let config = Config::build(Environment::Development)
+            .address("127.0.0.1")
+            .port(port)
+            .workers(2)
+            .extra("databases", databases())
+            .finalize()
+            .unwrap();
+        thread::spawn(move || host_calls(config).launch());
+
+        for _ in 0..50 {
+            if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                return format!("http://127.0.0.1:{}", port);
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+        panic!("host call listener didn't start");
+    }
+
+    /// Runs EXECUTIONS fake executions of a handler at once. Like real ones,
+    /// each holds a database connection while it makes host calls.
+    fn concurrently(hdl_id: Uuid, calls: impl Fn(usize, &str) + Send + Sync + 'static) {
+        let base = listen();
+        let calls = Arc::new(calls);
+        let barrier = Arc::new(Barrier::new(EXECUTIONS));
+        let threads: Vec<_> = (0..EXECUTIONS)
+            .map(|n| {
+                let (base, calls, barrier) = (base.clone(), calls.clone(), barrier.clone());
+                thread::spawn(move || {
+                    let _held = PgConnection::establish(&database_url()).unwrap();
+                    let token = HOST_CALLS.open(host::Caller {
+                        handler_id: hdl_id,
+                        tier: 0,
+                        allow: vec![],
+                    });
+                    barrier.wait();
+                    calls(n, &format!("{}/host/{}", base, token));
+                    HOST_CALLS.close(&token);
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn serves_concurrent_outbound_requests() {
+        concurrently(Uuid::new_v4(), |_, base| {
+            let resp = ureq::post(&format!("{}/http", base))
+                .set(EGRESS_URL_HEADER, "https://example.com/")
+                .timeout(time::Duration::from_secs(10))
+                .call();
+            assert_eq!(resp.status(), 502, "{}", resp.status_line());
+            assert!(resp.into_string().unwrap().contains("allow-list"));
+        });
+    }
+
+    #[test]
+    #[ignore]
+    fn serves_concurrent_kv_calls() {
+        let conn = PgConnection::establish(&database_url()).unwrap();
+        let hdl = committed_handler(&conn);
+
+        let result = std::panic::catch_unwind(|| {
+            concurrently(hdl.id, |n, base| {
+                let key = format!("{}/kv/key-{}", base, n);
+                let put = ureq::put(&key)
+                    .timeout(time::Duration::from_secs(10))
+                    .send_bytes(n.to_string().as_bytes());
+                assert_eq!(put.status(), 200, "{}", put.status_line());
+                let got = ureq::get(&key)
+                    .timeout(time::Duration::from_secs(10))
+                    .call()
+                    .into_string()
+                    .unwrap();
+                assert_eq!(got, n.to_string());
+            })
+        });
+
+        remove_handler(&conn, &hdl);
+        result.unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn custom_domains_reach_their_handler_at_every_path() {
+        use rocket::{http::Header, local::Client};
+
+        let conn = PgConnection::establish(&database_url()).unwrap();
+        let hdl = committed_handler(&conn);
+        let domain = format!("{}.example.com", Uuid::new_v4());
+        diesel::insert_into(schema::custom_domains::table)
+            .values(&models::NewCustomDomain {
+                handler_id: hdl.id,
+                domain: domain.clone(),
+                verification_token: "token".to_string(),
+            })
+            .execute(&conn)
+            .unwrap();
+        diesel::update(schema::custom_domains::table)
+            .filter(schema::custom_domains::domain.eq(&domain))
+            .set(schema::custom_domains::verified_at.eq(chrono::Utc::now().naive_utc()))
+            .execute(&conn)
+            .unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            let config = Config::build(Environment::Development)
+                .extra("databases", databases())
+                .finalize()
+                .unwrap();
+            let rocket = rocket::custom(config)
+                .attach(MainDatabase::fairing())
+                .attach(HostRouting)
+                .mount(
+                    "/",
+                    routes![schedule_host_root, schedule_host, health::healthz],
+                );
+            let client = Client::new(rocket).unwrap();
+
+            // The handler has no version yet, so running it fails.
+            for path in &["/", "/healthz", "/run/anything", "/_host/healthz"] {
+                let resp = client
+                    .get(*path)
+                    .header(Header::new("Host", domain.clone()))
+                    .dispatch();
+                assert_eq!(resp.status(), Status::NotFound, "{}", path);
+            }
+            let resp = client
+                .get("/healthz")
+                .header(Header::new("Host", "executor.example.com"))
+                .dispatch();
+            assert_eq!(resp.status(), Status::Ok);
+            let resp = client
+                .get("/_host/healthz")
+                .header(Header::new("Host", "executor.example.com"))
+                .dispatch();
+            assert_eq!(resp.status(), Status::NotFound);
+        });
+
+        remove_handler(&conn, &hdl);
+        result.unwrap();
+    }
+}
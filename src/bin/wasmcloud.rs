@@ -0,0 +1,806 @@
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+use wasmcloud_api::{events::Event, jwt, APP_USER_AGENT};
+
+/// How long to wait for the server, in milliseconds. Log streams send
+/// keep-alives more often than this. Uploads and invocations can take longer.
+const CONNECT_TIMEOUT: u64 = 10_000;
+const READ_TIMEOUT: u64 = 30_000;
+const SLOW_READ_TIMEOUT: u64 = 300_000;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "wasmcloud", about = "Manages handlers on wasmcloud")]
+struct Opt {
+    /// Prints what the API answered as JSON, for scripts.
+    #[structopt(long, global = true)]
+    json: bool,
+
+    /// The API server, instead of the one from the config file.
+    #[structopt(long, global = true, env = "WASMCLOUD_API_URL")]
+    api_url: Option<String>,
+
+    /// The token to use, instead of the one from the config file.
+    #[structopt(long, global = true, env = "WASMCLOUD_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    #[structopt(subcommand)]
+    cmd: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Stores a token for the CLI, made with the one from the web login.
+    Login {
+        /// The token from the web login, read from stdin if left out.
+        #[structopt(long)]
+        with_token: Option<String>,
+        /// Where handlers are invoked.
+        #[structopt(long)]
+        executor_url: Option<String>,
+    },
+    /// Forgets the stored token and deletes it.
+    Logout,
+    /// Shows who the token belongs to.
+    Whoami,
+    Handler(HandlerCmd),
+    Config(ConfigCmd),
+    Token(TokenCmd),
+    /// Lists the latest executions of a handler.
+    Executions {
+        handler: String,
+        #[structopt(long, default_value = "20")]
+        limit: i64,
+    },
+    /// Shows the output of an execution.
+    Logs {
+        handler: String,
+        execution: String,
+    },
+    /// Follows the executions of a handler as they happen.
+    Tail {
+        handler: String,
+    },
+    /// Runs a handler and prints what it answered.
+    Invoke {
+        /// The name of the handler, or an alias.
+        name: String,
+        #[structopt(long)]
+        env: Option<String>,
+        /// An invocation key, for `api_key` handlers.
+        #[structopt(long)]
+        key: Option<String>,
+        /// A token with the `invoke` scope for `jwt` handlers, instead of the
+        /// one made at login.
+        #[structopt(long, env = "WASMCLOUD_INVOKE_TOKEN", hide_env_values = true)]
+        invoke_token: Option<String>,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum HandlerCmd {
+    Create {
+        /// A random name is picked if left out.
+        #[structopt(long)]
+        name: Option<String>,
+        #[structopt(long = "async")]
+        async_impl: bool,
+        /// Who may run the handler.
+        #[structopt(
+            long,
+            default_value = "public",
+            possible_values = &["public", "api_key", "jwt"]
+        )]
+        visibility: String,
+        #[structopt(long, default_value = "")]
+        description: String,
+        #[structopt(long = "tag")]
+        tags: Vec<String>,
+    },
+    List {
+        /// Only lists handlers with this tag, can be repeated.
+        #[structopt(long = "tag")]
+        tags: Vec<String>,
+        /// Searches names, descriptions and tags instead.
+        #[structopt(long)]
+        search: Option<String>,
+    },
+    Get {
+        handler: String,
+    },
+    Delete {
+        handler: String,
+    },
+    /// Uploads a new version of a handler and makes it current.
+    Upload {
+        handler: String,
+        module: PathBuf,
+        /// A tar archive of files the module can read.
+        #[structopt(long)]
+        assets: Option<PathBuf>,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum ConfigCmd {
+    /// Shows the config of a handler, secrets are masked.
+    Get { handler: String },
+    /// Sets config values, as KEY=VALUE.
+    Set {
+        handler: String,
+        #[structopt(required = true)]
+        values: Vec<String>,
+        /// Stores the values encrypted, they can't be read back.
+        #[structopt(long)]
+        secret: bool,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum TokenCmd {
+    List,
+    /// Makes a token and prints it.
+    Create {
+        /// Comma-separated, `api` or `invoke`.
+        #[structopt(long)]
+        scope: Option<String>,
+    },
+    Delete {
+        id: String,
+    },
+}
+
+/// What is kept between runs, in the config directory of the user. The API
+/// token is never sent to the executor, handlers are run with a token that
+/// only has the invoke scope.
+#[derive(Debug, Serialize, Deserialize)]
+struct Config {
+    api_url: String,
+    executor_url: String,
+    token: Option<String>,
+    #[serde(default)]
+    invoke_token: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            api_url: "http://localhost:8000".to_string(),
+            executor_url: "http://localhost:8001".to_string(),
+            token: None,
+            invoke_token: None,
+        }
+    }
+}
+
+impl Config {
+    fn path() -> Result<PathBuf> {
+        Ok(dirs::config_dir()
+            .ok_or_else(|| eyre!("can't find the config directory"))?
+            .join("wasmcloud")
+            .join("config.toml"))
+    }
+
+    fn load() -> Result<Self> {
+        let path = Self::path()?;
+        match fs::read_to_string(&path) {
+            Ok(data) => toml::from_str(&data).wrap_err_with(|| format!("can't read {:?}", path)),
+            Err(why) if why.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(why) => Err(why.into()),
+        }
+    }
+
+    /// The file has a token in it, so only the user can read it.
+    fn save(&self) -> Result<()> {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        let path = Self::path()?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)?;
+        // The mode only applies to new files.
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(toml::to_string(self)?.as_bytes())?;
+        Ok(())
+    }
+}
+
+struct Client {
+    api_url: String,
+    token: Option<String>,
+}
+
+impl Client {
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        self.request_url(
+            method,
+            &format!("{}/api{}", self.api_url.trim_end_matches('/'), path),
+        )
+    }
+
+    fn request_url(&self, method: &str, url: &str) -> ureq::Request {
+        let mut req = ureq::request(method, url);
+        req.set("User-Agent", APP_USER_AGENT);
+        if let Some(token) = self.token.as_ref() {
+            req.set("Authorization", token);
+        }
+        req.timeout_connect(CONNECT_TIMEOUT)
+            .timeout_read(READ_TIMEOUT);
+        req
+    }
+
+    fn get(&self, path: &str) -> Result<Value> {
+        json_body(check(self.request("GET", path).call())?)
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        check(self.request("DELETE", path).call())?;
+        Ok(())
+    }
+
+    fn post(&self, path: &str, body: Value) -> Result<Value> {
+        json_body(check(self.request("POST", path).send_json(body))?)
+    }
+
+    /// Finds a handler by its ID or its name. Names are looked up among the
+    /// handlers they are a prefix of, page by page.
+    fn handler(&self, handler: &str) -> Result<Value> {
+        if uuid::Uuid::parse_str(handler).is_ok() {
+            return self.get(&format!("/handler/{}", handler));
+        }
+
+        let mut req = self.request("GET", "/handler");
+        req.query("prefix", handler)
+            .query("sort", "name")
+            .query("limit", "200");
+        loop {
+            let resp = check(req.call())?;
+            let next = resp.header("Link").and_then(next_link);
+            let found = json_body(resp)?
+                .as_array()
+                .and_then(|hdls| hdls.iter().find(|h| h["human_name"] == handler).cloned());
+            if let Some(found) = found {
+                return Ok(found);
+            }
+
+            req = match next {
+                Some(path) => self.request_url(
+                    "GET",
+                    &format!("{}{}", self.api_url.trim_end_matches('/'), path),
+                ),
+                None => return Err(eyre!("no handler named {:?}", handler)),
+            };
+        }
+    }
+
+    fn handler_id(&self, handler: &str) -> Result<String> {
+        Ok(str_of(&self.handler(handler)?["id"]).to_string())
+    }
+}
+
+/// The next page from a `Link` header, like `</api/handler?cursor=..>;
+/// rel="next"`.
+fn next_link(header: &str) -> Option<String> {
+    header
+        .split(',')
+        .find(|link| link.contains("rel=\"next\""))
+        .and_then(|link| {
+            let start = link.find('<')? + 1;
+            let end = link.find('>')?;
+            link.get(start..end).map(str::to_string)
+        })
+}
+
+/// Turns what went wrong into an error, with the message of the API if it
+/// sent one.
+fn check(resp: ureq::Response) -> Result<ureq::Response> {
+    if let Some(why) = resp.synthetic_error() {
+        return Err(eyre!("can't reach the server: {}", why));
+    }
+    if resp.ok() {
+        return Ok(resp);
+    }
+
+    let status = resp.status();
+    let body = resp.into_string().unwrap_or_default();
+    let message = serde_json::from_str::<Value>(&body)
+        .ok()
+        .and_then(|v| v["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or(body);
+    Err(eyre!("{}: {}", status, message))
+}
+
+/// Empty bodies are read as null.
+fn json_body(resp: ureq::Response) -> Result<Value> {
+    let body = resp.into_string()?;
+    if body.trim().is_empty() {
+        return Ok(Value::Null);
+    }
+    Ok(serde_json::from_str(&body).unwrap_or(Value::String(body)))
+}
+
+fn str_of(v: &Value) -> &str {
+    v.as_str().unwrap_or("-")
+}
+
+/// Prints a list as columns of some of its fields, or all of it as JSON.
+fn print_table(json: bool, items: &Value, fields: &[&str]) -> Result<()> {
+    if json {
+        return print_json(items);
+    }
+
+    let rows: Vec<Vec<String>> = items
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .map(|item| {
+            fields
+                .iter()
+                .map(|field| match &item[*field] {
+                    Value::String(s) => s.clone(),
+                    Value::Null => "-".to_string(),
+                    Value::Array(vs) => vs
+                        .iter()
+                        .map(|v| str_of(v).to_string())
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    other => other.to_string(),
+                })
+                .collect()
+        })
+        .collect();
+    let widths: Vec<usize> = fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            rows.iter()
+                .map(|row| row[i].len())
+                .chain(Some(field.len()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let print_row = |cells: Vec<&str>| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+    print_row(fields.to_vec());
+    for row in rows.iter() {
+        print_row(row.iter().map(String::as_str).collect());
+    }
+    Ok(())
+}
+
+fn print_json(v: &Value) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(v)?);
+    Ok(())
+}
+
+/// Prints a single item as JSON, or a line about it.
+fn print_item(json: bool, v: &Value, line: impl FnOnce(&Value) -> String) -> Result<()> {
+    if json {
+        print_json(v)
+    } else {
+        println!("{}", line(v));
+        Ok(())
+    }
+}
+
+const HANDLER_FIELDS: &[&str] = &["id", "human_name", "visibility", "tags", "description"];
+
+/// A multipart/form-data body, as the upload endpoint wants it. Every part is
+/// (field name, file name, content type, contents).
+fn multipart(parts: &[(&str, String, &str, Vec<u8>)]) -> (String, Vec<u8>) {
+    let boundary = format!("wasmcloud-{}", uuid::Uuid::new_v4().to_simple());
+    let mut body = Vec::new();
+    for (name, filename, content_type, data) in parts {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+                boundary, name, filename, content_type
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
+
+fn file_part<'a>(
+    name: &'a str,
+    path: &Path,
+    content_type: &'a str,
+) -> Result<(&'a str, String, &'a str, Vec<u8>)> {
+    let data = fs::read(path).wrap_err_with(|| format!("can't read {:?}", path))?;
+    let filename = path
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or_else(|| name.to_string());
+    Ok((name, filename, content_type, data))
+}
+
+fn handler_cmd(client: &Client, json: bool, cmd: HandlerCmd) -> Result<()> {
+    match cmd {
+        HandlerCmd::Create {
+            name,
+            async_impl,
+            visibility,
+            description,
+            tags,
+        } => {
+            let hdl = client.post(
+                "/handler",
+                json!({
+                    "name": name,
+                    "async_impl": async_impl,
+                    "visibility": visibility,
+                    "description": description,
+                    "tags": tags,
+                }),
+            )?;
+            print_item(json, &hdl, |h| {
+                format!(
+                    "created {} ({})",
+                    str_of(&h["human_name"]),
+                    str_of(&h["id"])
+                )
+            })
+        }
+        HandlerCmd::List { tags, search } => {
+            let hdls = match search {
+                Some(q) => {
+                    let mut req = client.request("GET", "/handler/search");
+                    req.query("q", &q);
+                    json_body(check(req.call())?)?
+                }
+                None => {
+                    let mut req = client.request("GET", "/handler");
+                    if !tags.is_empty() {
+                        req.query("tags", &tags.join(","));
+                    }
+                    json_body(check(req.call())?)?
+                }
+            };
+            print_table(json, &hdls, HANDLER_FIELDS)
+        }
+        HandlerCmd::Get { handler } => {
+            let hdl = client.handler(&handler)?;
+            if json {
+                print_json(&hdl)
+            } else {
+                print_table(false, &Value::Array(vec![hdl]), HANDLER_FIELDS)
+            }
+        }
+        HandlerCmd::Delete { handler } => {
+            let hdl = client.handler(&handler)?;
+            client.delete(&format!("/handler/{}", str_of(&hdl["id"])))?;
+            print_item(json, &hdl, |h| {
+                format!("deleted {}", str_of(&h["human_name"]))
+            })
+        }
+        HandlerCmd::Upload {
+            handler,
+            module,
+            assets,
+        } => {
+            let id = client.handler_id(&handler)?;
+            let mut parts = vec![file_part("module", &module, "application/wasm")?];
+            if let Some(assets) = assets.as_ref() {
+                parts.push(file_part("assets", assets, "application/x-tar")?);
+            }
+            let (content_type, body) = multipart(&parts);
+
+            let mut req = client.request("POST", &format!("/handler/{}/upload", id));
+            req.set("Content-Type", &content_type)
+                .timeout_read(SLOW_READ_TIMEOUT);
+            let hdl = json_body(check(req.send_bytes(&body))?)?;
+            print_item(json, &hdl, |h| {
+                format!(
+                    "uploaded a new version of {}: {}",
+                    str_of(&h["human_name"]),
+                    str_of(&h["current_version"])
+                )
+            })
+        }
+    }
+}
+
+fn config_cmd(client: &Client, json: bool, cmd: ConfigCmd) -> Result<()> {
+    match cmd {
+        ConfigCmd::Get { handler } => {
+            let id = client.handler_id(&handler)?;
+            let cfg = client.get(&format!("/handler/{}/config", id))?;
+            print_table(json, &cfg, &["key_name", "value_contents", "is_secret"])
+        }
+        ConfigCmd::Set {
+            handler,
+            values,
+            secret,
+        } => {
+            let id = client.handler_id(&handler)?;
+            let cfg = values
+                .iter()
+                .map(|kv| {
+                    let mut kv = kv.splitn(2, '=');
+                    match (kv.next(), kv.next()) {
+                        (Some(key), Some(value)) if !key.is_empty() => Ok(json!({
+                            "key": key,
+                            "value": value,
+                            "secret": secret,
+                        })),
+                        _ => Err(eyre!("config values are set as KEY=VALUE")),
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
+            client.post(&format!("/handler/{}/config", id), Value::Array(cfg))?;
+            if !json {
+                println!("set {} config values", values.len());
+            }
+            Ok(())
+        }
+    }
+}
+
+fn token_cmd(client: &Client, json: bool, cmd: TokenCmd) -> Result<()> {
+    match cmd {
+        TokenCmd::List => {
+            let toks = client.get("/token")?;
+            print_table(json, &toks, &["id", "scopes", "created_at"])
+        }
+        TokenCmd::Create { scope } => {
+            let mut req = client.request("POST", "/token");
+            if let Some(scope) = scope.as_ref() {
+                req.query("scope", scope);
+            }
+            let tok = check(req.call())?.into_string()?;
+            print_item(json, &json!({ "token": tok }), |_| tok.clone())
+        }
+        TokenCmd::Delete { id } => {
+            client.delete(&format!("/token/{}", id))?;
+            print_item(json, &json!({ "id": id }), |_| {
+                format!("deleted token {}", id)
+            })
+        }
+    }
+}
+
+/// Reads the Server-Sent Events of a handler until the server hangs up.
+fn tail(client: &Client, json: bool, handler: &str) -> Result<()> {
+    let id = client.handler_id(handler)?;
+    let resp = check(
+        client
+            .request("GET", &format!("/handler/{}/logs/stream", id))
+            .call(),
+    )?;
+
+    for line in BufReader::new(resp.into_reader()).lines() {
+        let line = line?;
+        let data = match line.strip_prefix("data: ") {
+            Some(data) => data,
+            // Event names, keep-alive comments and blank lines.
+            None => continue,
+        };
+        if json {
+            println!("{}", data);
+            continue;
+        }
+        match serde_json::from_str::<Event>(data) {
+            Ok(Event::Started { execution_id, .. }) => println!("--- {} started", execution_id),
+            Ok(Event::Log {
+                stream, message, ..
+            }) => match stream.as_str() {
+                "stderr" => eprintln!("{}", message),
+                _ => println!("{}", message),
+            },
+            Ok(Event::Finished {
+                execution_id,
+                exit_code,
+                execution_time,
+                ..
+            }) => println!(
+                "--- {} finished with {} in {} ms",
+                execution_id,
+                exit_code.map_or("no exit code".to_string(), |c| format!("exit code {}", c)),
+                execution_time
+            ),
+            Err(why) => eprintln!("can't read event: {}", why),
+        }
+    }
+
+    Ok(())
+}
+
+fn invoke(
+    cfg: &Config,
+    token: Option<&str>,
+    name: &str,
+    env: Option<&str>,
+    key: Option<&str>,
+) -> Result<()> {
+    let path = match env {
+        Some(env) => format!("/run/{}/env/{}", name, env),
+        None => format!("/run/{}", name),
+    };
+    let mut req = ureq::get(&format!(
+        "{}{}",
+        cfg.executor_url.trim_end_matches('/'),
+        path
+    ));
+    req.set("User-Agent", APP_USER_AGENT)
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout_read(SLOW_READ_TIMEOUT);
+    if let Some(token) = token {
+        req.set("Authorization", token);
+    }
+    if let Some(key) = key {
+        req.set("X-Wasmcloud-Invoke-Key", key);
+    }
+
+    let mut body = Vec::new();
+    check(req.call())?.into_reader().read_to_end(&mut body)?;
+    io::stdout().write_all(&body)?;
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    color_eyre::install()?;
+    let opt = Opt::from_args();
+    let mut cfg = Config::load()?;
+    let client = Client {
+        api_url: opt.api_url.clone().unwrap_or_else(|| cfg.api_url.clone()),
+        token: opt.token.clone().or_else(|| cfg.token.clone()),
+    };
+
+    match opt.cmd {
+        Command::Login {
+            with_token,
+            executor_url,
+        } => {
+            let web_token = match with_token {
+                Some(tok) => tok,
+                None => {
+                    eprintln!(
+                        "log in at {}/login/gitea and paste the token here:",
+                        client.api_url
+                    );
+                    let mut tok = String::new();
+                    io::stdin().read_line(&mut tok)?;
+                    tok.trim().to_string()
+                }
+            };
+
+            // The CLI gets a token of its own, so that it can be deleted
+            // without logging out of the web.
+            let web = Client {
+                api_url: client.api_url.clone(),
+                token: Some(web_token),
+            };
+            let user = web.get("/whoami")?;
+            let token = check(web.request("POST", "/token").call())?.into_string()?;
+            let mut req = web.request("POST", "/token");
+            req.query("scope", jwt::SCOPE_INVOKE);
+            let invoke_token = check(req.call())?.into_string()?;
+
+            cfg.api_url = client.api_url.clone();
+            if let Some(url) = executor_url {
+                cfg.executor_url = url;
+            }
+            cfg.token = Some(token);
+            cfg.invoke_token = Some(invoke_token);
+            cfg.save()?;
+            print_item(opt.json, &user, |u| {
+                format!("logged in as {}", str_of(&u["email"]))
+            })
+        }
+        Command::Logout => {
+            if let Some(token) = cfg.token.take() {
+                let client = Client {
+                    api_url: cfg.api_url.clone(),
+                    token: Some(token.clone()),
+                };
+                // The API token deletes itself last.
+                for tok in cfg.invoke_token.take().iter().chain(Some(&token)) {
+                    let deleted =
+                        jwt::token_id(tok).and_then(|id| client.delete(&format!("/token/{}", id)));
+                    if let Err(why) = deleted {
+                        eprintln!("can't delete a token: {}", why);
+                    }
+                }
+            }
+            cfg.invoke_token = None;
+            cfg.save()?;
+            if !opt.json {
+                println!("logged out");
+            }
+            Ok(())
+        }
+        Command::Whoami => {
+            let user = client.get("/whoami")?;
+            print_item(opt.json, &user, |u| str_of(&u["email"]).to_string())
+        }
+        Command::Handler(cmd) => handler_cmd(&client, opt.json, cmd),
+        Command::Config(cmd) => config_cmd(&client, opt.json, cmd),
+        Command::Token(cmd) => token_cmd(&client, opt.json, cmd),
+        Command::Executions { handler, limit } => {
+            let id = client.handler_id(&handler)?;
+            let mut req = client.request("GET", &format!("/handler/{}/execution", id));
+            req.query("limit", &limit.to_string());
+            let execs = json_body(check(req.call())?)?;
+            print_table(
+                opt.json,
+                &execs,
+                &[
+                    "id",
+                    "created_at",
+                    "finished",
+                    "exit_code",
+                    "execution_time",
+                ],
+            )
+        }
+        Command::Logs { handler, execution } => {
+            let id = client.handler_id(&handler)?;
+            let exec = client.get(&format!("/handler/{}/execution/{}", id, execution))?;
+            if opt.json {
+                return print_json(&exec);
+            }
+            print!("{}", exec["stdout"].as_str().unwrap_or(""));
+            eprint!("{}", exec["stderr"].as_str().unwrap_or(""));
+            Ok(())
+        }
+        Command::Tail { handler } => tail(&client, opt.json, &handler),
+        Command::Invoke {
+            name,
+            env,
+            key,
+            invoke_token,
+        } => invoke(
+            &cfg,
+            invoke_token
+                .as_deref()
+                .or_else(|| cfg.invoke_token.as_deref()),
+            &name,
+            env.as_deref(),
+            key.as_deref(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_next_links() {
+        assert_eq!(
+            next_link("</api/handler?prefix=a&cursor=abcd>; rel=\"next\"").as_deref(),
+            Some("/api/handler?prefix=a&cursor=abcd")
+        );
+        assert_eq!(
+            next_link("</prev>; rel=\"prev\", </next>; rel=\"next\"").as_deref(),
+            Some("/next")
+        );
+        assert_eq!(next_link("</prev>; rel=\"prev\""), None);
+        assert_eq!(next_link("garbage; rel=\"next\""), None);
+    }
+}
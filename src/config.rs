@@ -0,0 +1,703 @@
+use crate::{
+    api::{Error, Result},
+    models, schema, secrets,
+};
+use chrono::NaiveDateTime;
+use diesel::{pg::PgConnection, prelude::*};
+use rocket::http::ContentType;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// A flat set of handler config keys and values.
+pub type Map = BTreeMap<String, String>;
+
+/// The file formats handler config can be imported from and exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormValue)]
+pub enum Format {
+    Dotenv,
+    Json,
+    Toml,
+}
+
+impl Format {
+    /// Picks a format based on the content type of an uploaded file.
+    pub fn from_content_type(ct: &ContentType) -> Option<Format> {
+        match (ct.top().as_str(), ct.sub().as_str()) {
+            ("application", "json") => Some(Format::Json),
+            ("application", "toml") | ("text", "toml") => Some(Format::Toml),
+            ("text", "plain") | ("application", "x-dotenv") | ("text", "x-dotenv") => {
+                Some(Format::Dotenv)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        match self {
+            Format::Dotenv => ContentType::Plain,
+            Format::Json => ContentType::JSON,
+            Format::Toml => ContentType::new("application", "toml"),
+        }
+    }
+}
+
+fn invalid<T: std::fmt::Display>(why: T) -> Error {
+    Error::InvalidConfig(why.to_string())
+}
+
+/// Parses a config file in the given format into a flat map. Scalar values
+/// (numbers, booleans) are stringified, nested values are rejected.
+pub fn parse(format: Format, body: &str) -> Result<Map> {
+    match format {
+        Format::Dotenv => parse_dotenv(body),
+        Format::Json => {
+            let obj: serde_json::Map<String, serde_json::Value> =
+                serde_json::from_str(body).map_err(invalid)?;
+            obj.into_iter()
+                .map(|(k, v)| {
+                    let v = match v {
+                        serde_json::Value::String(s) => s,
+                        serde_json::Value::Number(n) => n.to_string(),
+                        serde_json::Value::Bool(b) => b.to_string(),
+                        _ => return Err(invalid(format!("{}: value must be a scalar", k))),
+                    };
+                    Ok((k, v))
+                })
+                .collect()
+        }
+        Format::Toml => {
+            let tbl: toml::value::Table = toml::from_str(body).map_err(invalid)?;
+            tbl.into_iter()
+                .map(|(k, v)| {
+                    let v = match v {
+                        toml::Value::String(s) => s,
+                        toml::Value::Integer(i) => i.to_string(),
+                        toml::Value::Float(f) => f.to_string(),
+                        toml::Value::Boolean(b) => b.to_string(),
+                        toml::Value::Datetime(dt) => dt.to_string(),
+                        _ => return Err(invalid(format!("{}: value must be a scalar", k))),
+                    };
+                    Ok((k, v))
+                })
+                .collect()
+        }
+    }
+}
+
+/// Renders a flat map as a config file in the given format.
+pub fn render(format: Format, map: &Map) -> Result<String> {
+    match format {
+        Format::Dotenv => Ok(map
+            .iter()
+            .map(|(k, v)| format!("{}={}\n", k, quote_dotenv(v)))
+            .collect()),
+        Format::Json => Ok(serde_json::to_string_pretty(map).map_err(invalid)?),
+        Format::Toml => Ok(toml::to_string(map).map_err(invalid)?),
+    }
+}
+
+fn parse_dotenv(body: &str) -> Result<Map> {
+    let mut result = Map::new();
+
+    for (lineno, line) in body.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.trim_start_matches("export ").trim_start();
+
+        let mut split = line.splitn(2, '=');
+        let key = split.next().unwrap_or("").trim();
+        let value = split
+            .next()
+            .ok_or_else(|| invalid(format!("line {}: expected KEY=value", lineno + 1)))?
+            .trim();
+
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(invalid(format!(
+                "line {}: invalid key {:?}",
+                lineno + 1,
+                key
+            )));
+        }
+
+        result.insert(key.to_string(), unquote_dotenv(value, lineno + 1)?);
+    }
+
+    Ok(result)
+}
+
+fn unquote_dotenv(value: &str, lineno: usize) -> Result<String> {
+    let unterminated = || invalid(format!("line {}: unterminated quote", lineno));
+
+    if value.starts_with('\'') {
+        let end = value[1..].find('\'').ok_or_else(unterminated)?;
+        return Ok(value[1..end + 1].to_string());
+    }
+
+    if value.starts_with('"') {
+        let mut out = String::new();
+        let mut chars = value[1..].chars();
+        loop {
+            match chars.next().ok_or_else(unterminated)? {
+                '"' => return Ok(out),
+                '\\' => match chars.next().ok_or_else(unterminated)? {
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    c => out.push(c),
+                },
+                c => out.push(c),
+            }
+        }
+    }
+
+    // Unquoted values run until an inline comment.
+    let value = match value.find(" #") {
+        Some(idx) => &value[..idx],
+        None => value,
+    };
+    Ok(value.trim_end().to_string())
+}
+
+fn quote_dotenv(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Where an effective config value came from.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Source {
+    Handler,
+    Group { id: Uuid, name: String },
+    Environment { id: Uuid, name: String },
+}
+
+impl Source {
+    /// What secret values from here are sealed for, `hdl_id` is the handler
+    /// the config is for.
+    pub fn owner(&self, hdl_id: Uuid) -> secrets::Owner {
+        match self {
+            Source::Handler => secrets::Owner::Handler(hdl_id),
+            Source::Group { id, .. } => secrets::Owner::Group(*id),
+            Source::Environment { id, .. } => secrets::Owner::Environment(*id),
+        }
+    }
+}
+
+/// A config value as a handler sees it once its config groups are merged in.
+#[derive(Serialize, Debug, Clone)]
+pub struct Entry {
+    pub key_name: String,
+    pub value_contents: String,
+    pub is_secret: bool,
+    pub source: Source,
+}
+
+impl Entry {
+    /// See [models::HandlerConfig::masked].
+    pub fn masked(mut self) -> Self {
+        if self.is_secret {
+            self.value_contents = secrets::MASK.to_string();
+        }
+        self
+    }
+}
+
+/// Merges the config groups attached to a handler with its own config. Groups
+/// are applied from the lowest priority to the highest (ties are broken by
+/// group name) and the handler's own config is applied last, so it always wins.
+#[instrument(skip(conn), err)]
+pub fn effective(conn: &PgConnection, hdl_id: Uuid) -> QueryResult<Vec<Entry>> {
+    use schema::{config_group_entries, config_groups, handler_config, handler_config_groups};
+
+    let attached = handler_config_groups::table
+        .filter(handler_config_groups::handler_id.eq(hdl_id))
+        .load::<models::HandlerConfigGroup>(conn)?;
+    let group_ids: Vec<Uuid> = attached.iter().map(|a| a.group_id).collect();
+
+    let mut groups = config_groups::table
+        .filter(config_groups::id.eq_any(&group_ids))
+        .load::<models::ConfigGroup>(conn)?
+        .into_iter()
+        .map(|g| {
+            let priority = attached
+                .iter()
+                .find(|a| a.group_id == g.id)
+                .map(|a| a.priority)
+                .unwrap_or(0);
+            (priority, g)
+        })
+        .collect::<Vec<_>>();
+    groups.sort_by(|(lp, lg), (rp, rg)| lp.cmp(rp).then_with(|| lg.name.cmp(&rg.name)));
+
+    let mut group_entries = config_group_entries::table
+        .filter(config_group_entries::group_id.eq_any(&group_ids))
+        .load::<models::ConfigGroupEntry>(conn)?;
+
+    let mut merged: BTreeMap<String, Entry> = BTreeMap::new();
+
+    for (_, group) in groups.into_iter() {
+        let (entries, rest): (Vec<_>, Vec<_>) = group_entries
+            .into_iter()
+            .partition(|e| e.group_id == group.id);
+        group_entries = rest;
+
+        for e in entries.into_iter() {
+            merged.insert(
+                e.key_name.clone(),
+                Entry {
+                    key_name: e.key_name,
+                    value_contents: e.value_contents,
+                    is_secret: e.is_secret,
+                    source: Source::Group {
+                        id: group.id,
+                        name: group.name.clone(),
+                    },
+                },
+            );
+        }
+    }
+
+    for kv in handler_config::table
+        .filter(handler_config::handler_id.eq(hdl_id))
+        .load::<models::HandlerConfig>(conn)?
+        .into_iter()
+    {
+        merged.insert(
+            kv.key_name.clone(),
+            Entry {
+                key_name: kv.key_name,
+                value_contents: kv.value_contents,
+                is_secret: kv.is_secret,
+                source: Source::Handler,
+            },
+        );
+    }
+
+    Ok(merged.into_iter().map(|(_, e)| e).collect())
+}
+
+/// The effective config of a handler in one of its environments. The config of
+/// the environment is applied on top of [effective].
+#[instrument(skip(conn), err)]
+pub fn effective_in(
+    conn: &PgConnection,
+    env: &models::HandlerEnvironment,
+) -> QueryResult<Vec<Entry>> {
+    use schema::environment_config;
+
+    let mut merged: BTreeMap<String, Entry> = effective(conn, env.handler_id)?
+        .into_iter()
+        .map(|e| (e.key_name.clone(), e))
+        .collect();
+
+    for kv in environment_config::table
+        .filter(environment_config::environment_id.eq(env.id))
+        .load::<models::EnvironmentConfig>(conn)?
+        .into_iter()
+    {
+        merged.insert(
+            kv.key_name.clone(),
+            Entry {
+                key_name: kv.key_name,
+                value_contents: kv.value_contents,
+                is_secret: kv.is_secret,
+                source: Source::Environment {
+                    id: env.id,
+                    name: env.name.clone(),
+                },
+            },
+        );
+    }
+
+    Ok(merged.into_iter().map(|(_, e)| e).collect())
+}
+
+/// A config history entry that sets a key.
+pub const ACTION_SET: &str = "set";
+/// A config history entry that removes a key.
+pub const ACTION_DELETE: &str = "delete";
+
+fn value_hash(hdl_id: Uuid, key: &str, value_contents: &str, is_secret: bool) -> Result<String> {
+    Ok(if is_secret {
+        secrets::fingerprint(&secrets::open(
+            secrets::Owner::Handler(hdl_id),
+            key,
+            value_contents,
+        )?)
+    } else {
+        secrets::fingerprint(value_contents)
+    })
+}
+
+/// Sets config values of a handler, recording each change in its config
+/// history. Values that are set to what they already are are skipped. Secret
+/// values must already be sealed.
+#[instrument(skip(conn, entries), err)]
+pub fn set(
+    conn: &PgConnection,
+    changed_by: Uuid,
+    entries: Vec<models::NewHandlerConfig>,
+) -> Result {
+    use schema::{handler_config, handler_config_history};
+
+    conn.transaction(|| {
+        for kv in entries.into_iter() {
+            let old = handler_config::table
+                .find((kv.key_name.clone(), kv.handler_id))
+                .get_result::<models::HandlerConfig>(conn)
+                .optional()?;
+            let old_value_hash = match old.as_ref() {
+                Some(old) => Some(value_hash(
+                    kv.handler_id,
+                    &kv.key_name,
+                    &old.value_contents,
+                    old.is_secret,
+                )?),
+                None => None,
+            };
+            let new_value_hash = value_hash(
+                kv.handler_id,
+                &kv.key_name,
+                &kv.value_contents,
+                kv.is_secret,
+            )?;
+
+            // Setting a value to what it already is isn't a change.
+            let unchanged = old.map(|old| old.is_secret) == Some(kv.is_secret)
+                && old_value_hash.as_ref() == Some(&new_value_hash);
+            if unchanged {
+                continue;
+            }
+
+            diesel::insert_into(handler_config::table)
+                .values(&kv)
+                .on_conflict((handler_config::key_name, handler_config::handler_id))
+                .do_update()
+                .set((
+                    handler_config::value_contents.eq(&kv.value_contents),
+                    handler_config::is_secret.eq(kv.is_secret),
+                ))
+                .execute(conn)?;
+
+            diesel::insert_into(handler_config_history::table)
+                .values(&models::NewHandlerConfigHistory {
+                    handler_id: kv.handler_id,
+                    key_name: kv.key_name.clone(),
+                    action: ACTION_SET.to_string(),
+                    old_value_hash,
+                    new_value_hash: Some(new_value_hash),
+                    new_value_contents: Some(kv.value_contents),
+                    is_secret: kv.is_secret,
+                    changed_by,
+                })
+                .execute(conn)?;
+        }
+
+        Ok(())
+    })
+}
+
+/// Removes config values from a handler, recording each removal in its config
+/// history. Keys that are not set are ignored.
+#[instrument(skip(conn), err)]
+pub fn remove(conn: &PgConnection, changed_by: Uuid, hdl_id: Uuid, keys: &[String]) -> Result {
+    use schema::{handler_config, handler_config_history};
+
+    conn.transaction(|| {
+        for key in keys.iter() {
+            let old = match handler_config::table
+                .find((key.clone(), hdl_id))
+                .get_result::<models::HandlerConfig>(conn)
+                .optional()?
+            {
+                Some(old) => old,
+                None => continue,
+            };
+
+            diesel::delete(handler_config::table.find((key.clone(), hdl_id))).execute(conn)?;
+
+            diesel::insert_into(handler_config_history::table)
+                .values(&models::NewHandlerConfigHistory {
+                    handler_id: hdl_id,
+                    key_name: key.clone(),
+                    action: ACTION_DELETE.to_string(),
+                    old_value_hash: Some(value_hash(
+                        hdl_id,
+                        key,
+                        &old.value_contents,
+                        old.is_secret,
+                    )?),
+                    new_value_hash: None,
+                    new_value_contents: None,
+                    is_secret: old.is_secret,
+                    changed_by,
+                })
+                .execute(conn)?;
+        }
+
+        Ok(())
+    })
+}
+
+/// Rebuilds the config a handler had at a given time from its config history.
+/// Secret values are returned sealed.
+#[instrument(skip(conn), err)]
+pub fn as_of(
+    conn: &PgConnection,
+    hdl_id: Uuid,
+    at: NaiveDateTime,
+) -> QueryResult<Vec<models::NewHandlerConfig>> {
+    use schema::handler_config_history::dsl::*;
+
+    let mut state: BTreeMap<String, models::NewHandlerConfig> = BTreeMap::new();
+
+    for change in handler_config_history
+        .filter(handler_id.eq(hdl_id))
+        .filter(created_at.le(at))
+        .order((created_at.asc(), seq.asc()))
+        .load::<models::HandlerConfigHistory>(conn)?
+        .into_iter()
+    {
+        match (change.action.as_str(), change.new_value_contents) {
+            (ACTION_SET, Some(value)) => {
+                state.insert(
+                    change.key_name.clone(),
+                    models::NewHandlerConfig {
+                        key_name: change.key_name,
+                        value_contents: value,
+                        handler_id: hdl_id,
+                        is_secret: change.is_secret,
+                    },
+                );
+            }
+            _ => {
+                state.remove(&change.key_name);
+            }
+        }
+    }
+
+    Ok(state.into_iter().map(|(_, kv)| kv).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(pairs: &[(&str, &str)]) -> Map {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_dotenv() {
+        let body = r#"
+# database
+export DATABASE_URL=postgres://localhost/db
+PLAIN = some value # a comment
+EQUALS=a=b
+SINGLE='no \n escapes # here'
+DOUBLE="line\nbreak \"quoted\" # kept"
+EMPTY=
+"#;
+        assert_eq!(
+            parse(Format::Dotenv, body).unwrap(),
+            map(&[
+                ("DATABASE_URL", "postgres://localhost/db"),
+                ("PLAIN", "some value"),
+                ("EQUALS", "a=b"),
+                ("SINGLE", "no \\n escapes # here"),
+                ("DOUBLE", "line\nbreak \"quoted\" # kept"),
+                ("EMPTY", ""),
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_bad_dotenv() {
+        for body in &[
+            "NO_VALUE",
+            "=value",
+            "BAD KEY=value",
+            "OPEN=\"value",
+            "OPEN='value",
+        ] {
+            assert!(
+                matches!(parse(Format::Dotenv, body), Err(Error::InvalidConfig(_))),
+                "{:?}",
+                body
+            );
+        }
+    }
+
+    #[test]
+    fn dotenv_round_trips() {
+        let config = map(&[
+            ("A", "plain"),
+            ("B", "with \"quotes\" and \\ backslashes"),
+            ("C", "tabs\tand\r\nnewlines"),
+            ("D", " padded # not a comment "),
+        ]);
+        let rendered = render(Format::Dotenv, &config).unwrap();
+        assert_eq!(parse(Format::Dotenv, &rendered).unwrap(), config);
+    }
+
+    #[test]
+    fn stringifies_scalars() {
+        let json = r#"{"A": "x", "B": 1.5, "C": true}"#;
+        let toml = "A = \"x\"\nB = 1.5\nC = true\n";
+        let want = map(&[("A", "x"), ("B", "1.5"), ("C", "true")]);
+        assert_eq!(parse(Format::Json, json).unwrap(), want);
+        assert_eq!(parse(Format::Toml, toml).unwrap(), want);
+
+        assert!(parse(Format::Json, r#"{"A": {"B": 1}}"#).is_err());
+        assert!(parse(Format::Toml, "[A]\nB = 1\n").is_err());
+    }
+
+    fn group(
+        conn: &PgConnection,
+        user: &models::User,
+        name: &str,
+        entries: &[(&str, &str)],
+    ) -> Uuid {
+        let group: models::ConfigGroup = diesel::insert_into(schema::config_groups::table)
+            .values(&models::NewConfigGroup {
+                user_id: user.id,
+                name: name.to_string(),
+            })
+            .get_result(conn)
+            .unwrap();
+        for (k, v) in entries {
+            diesel::insert_into(schema::config_group_entries::table)
+                .values(&models::NewConfigGroupEntry {
+                    key_name: k.to_string(),
+                    value_contents: v.to_string(),
+                    group_id: group.id,
+                    is_secret: false,
+                })
+                .execute(conn)
+                .unwrap();
+        }
+        group.id
+    }
+
+    #[test]
+    #[ignore]
+    fn merges_groups_by_priority() {
+        let conn = crate::testing::conn();
+        let user = crate::testing::user(&conn);
+        let hdl = crate::testing::handler(&conn, &user);
+
+        let low = group(
+            &conn,
+            &user,
+            "b-low",
+            &[("A", "low"), ("B", "low"), ("C", "low")],
+        );
+        let tie = group(&conn, &user, "a-tie", &[("A", "tie")]);
+        let high = group(&conn, &user, "high", &[("B", "high")]);
+        for (group_id, priority) in &[(low, 0), (tie, 0), (high, 10)] {
+            diesel::insert_into(schema::handler_config_groups::table)
+                .values(&models::NewHandlerConfigGroup {
+                    handler_id: hdl.id,
+                    group_id: *group_id,
+                    priority: *priority,
+                })
+                .execute(&conn)
+                .unwrap();
+        }
+        diesel::insert_into(schema::handler_config::table)
+            .values(&models::NewHandlerConfig {
+                key_name: "C".to_string(),
+                value_contents: "handler".to_string(),
+                handler_id: hdl.id,
+                is_secret: false,
+            })
+            .execute(&conn)
+            .unwrap();
+
+        let got: Vec<(String, String, Source)> = effective(&conn, hdl.id)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.key_name, e.value_contents, e.source))
+            .collect();
+        let from = |id: Uuid, name: &str| Source::Group {
+            id,
+            name: name.to_string(),
+        };
+        assert_eq!(
+            got,
+            vec![
+                ("A".to_string(), "low".to_string(), from(low, "b-low")),
+                ("B".to_string(), "high".to_string(), from(high, "high")),
+                ("C".to_string(), "handler".to_string(), Source::Handler),
+            ]
+        );
+    }
+
+    fn history(conn: &PgConnection, hdl_id: Uuid) -> Vec<models::HandlerConfigHistory> {
+        use schema::handler_config_history::dsl::{handler_config_history, handler_id, seq};
+        handler_config_history
+            .filter(handler_id.eq(hdl_id))
+            .order(seq.asc())
+            .load(conn)
+            .unwrap()
+    }
+
+    #[test]
+    #[ignore]
+    fn records_only_changes() {
+        if std::env::var("CONFIG_ENVELOPE_KEY").is_err() {
+            std::env::set_var("CONFIG_ENVELOPE_KEY", hex::encode([7u8; 32]));
+        }
+        let conn = crate::testing::conn();
+        let user = crate::testing::user(&conn);
+        let hdl = crate::testing::handler(&conn, &user);
+        let entry = |value: &str, is_secret: bool| models::NewHandlerConfig {
+            key_name: "A".to_string(),
+            value_contents: if is_secret {
+                secrets::seal(secrets::Owner::Handler(hdl.id), "A", value).unwrap()
+            } else {
+                value.to_string()
+            },
+            handler_id: hdl.id,
+            is_secret,
+        };
+
+        set(&conn, user.id, vec![entry("one", false)]).unwrap();
+        set(&conn, user.id, vec![entry("one", false)]).unwrap();
+        assert_eq!(history(&conn, hdl.id).len(), 1);
+
+        set(&conn, user.id, vec![entry("two", false)]).unwrap();
+        // Sealing the same value twice gives different ciphertexts, it is
+        // still the same value.
+        set(&conn, user.id, vec![entry("two", true)]).unwrap();
+        set(&conn, user.id, vec![entry("two", true)]).unwrap();
+
+        let changes = history(&conn, hdl.id);
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[1].old_value_hash, changes[0].new_value_hash);
+        assert_eq!(changes[2].old_value_hash, changes[2].new_value_hash);
+        assert!(changes[2].is_secret);
+
+        remove(&conn, user.id, hdl.id, &["A".to_string(), "B".to_string()]).unwrap();
+        assert_eq!(history(&conn, hdl.id).len(), 4);
+    }
+}
@@ -0,0 +1,255 @@
+use crate::{
+    api::Result,
+    models,
+    routing::{self, Resolved},
+    schema,
+};
+use color_eyre::eyre;
+use diesel::{pg::PgConnection, prelude::*};
+use lazy_static::lazy_static;
+use rand::{rngs::OsRng, RngCore};
+use std::env;
+use trust_dns_resolver::Resolver as DnsResolver;
+
+lazy_static! {
+    /// Handlers are also served at `<name>.<WILDCARD_DOMAIN>` when this is set,
+    /// for example `hello.run.example.com` for `WILDCARD_DOMAIN=run.example.com`.
+    pub static ref WILDCARD_DOMAIN: Option<String> = env::var("WILDCARD_DOMAIN")
+        .ok()
+        .map(|domain| normalize(&domain))
+        .filter(|domain| !domain.is_empty());
+}
+
+/// Where the verification token of a domain has to be published.
+pub const CHALLENGE_PREFIX: &str = "_wasmcloud-challenge.";
+
+/// What the TXT record of a domain has to contain for it to be verified.
+pub const CHALLENGE_VALUE_PREFIX: &str = "wasmcloud-verify=";
+
+/// Looks up DNS TXT records. This is a trait so that domain verification can
+/// be done without going to the network.
+pub trait TxtResolver: Send + Sync {
+    fn txt(&self, name: &str) -> eyre::Result<Vec<String>>;
+}
+
+/// Resolves TXT records with the nameservers of the host.
+pub struct SystemResolver(DnsResolver);
+
+impl SystemResolver {
+    pub fn new() -> eyre::Result<Self> {
+        Ok(SystemResolver(DnsResolver::from_system_conf()?))
+    }
+}
+
+impl TxtResolver for SystemResolver {
+    fn txt(&self, name: &str) -> eyre::Result<Vec<String>> {
+        // Names are looked up as fully qualified so the search domains of the
+        // host don't get appended to them.
+        let lookup = self.0.txt_lookup(format!("{}.", name).as_str())?;
+
+        Ok(lookup
+            .iter()
+            .map(|txt| {
+                txt.txt_data()
+                    .iter()
+                    .map(|part| String::from_utf8_lossy(part))
+                    .collect::<String>()
+            })
+            .collect())
+    }
+}
+
+/// The TXT resolver the API verifies domains with, kept in Rocket state.
+pub struct Resolver(Box<dyn TxtResolver>);
+
+impl Resolver {
+    pub fn new<T: TxtResolver + 'static>(resolver: T) -> Self {
+        Resolver(Box::new(resolver))
+    }
+
+    /// Checks whether the verification token of a domain is published.
+    #[instrument(skip(self, domain), fields(domain = &domain.domain[..]))]
+    pub fn verify(&self, domain: &models::CustomDomain) -> bool {
+        let expected = format!("{}{}", CHALLENGE_VALUE_PREFIX, domain.verification_token);
+
+        match self.0.txt(&challenge_name(&domain.domain)) {
+            Ok(records) => records.iter().any(|r| r.trim() == expected),
+            Err(why) => {
+                debug!("can't look up challenge record: {}", why);
+                false
+            }
+        }
+    }
+}
+
+/// The name of the TXT record the verification token of a domain goes in.
+pub fn challenge_name(domain: &str) -> String {
+    format!("{}{}", CHALLENGE_PREFIX, domain)
+}
+
+pub fn new_token() -> String {
+    let mut buf = [0u8; 16];
+    OsRng.fill_bytes(&mut buf);
+    hex::encode(buf)
+}
+
+/// Lowercases a host name and strips its port and trailing dot.
+pub fn normalize(host: &str) -> String {
+    let host = host.trim();
+    let host = match host.rfind(':') {
+        Some(idx) if !host.ends_with(']') => &host[..idx],
+        _ => host,
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+pub fn valid_domain(domain: &str) -> bool {
+    domain.len() <= 253
+        && domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        })
+}
+
+/// The handler name in a host under the wildcard domain, if it is one.
+fn wildcard_name<'a>(host: &'a str, wildcard: &str) -> Option<&'a str> {
+    host.strip_suffix(wildcard)
+        .and_then(|name| name.strip_suffix('.'))
+        .filter(|name| routing::valid_name(name))
+}
+
+/// Finds the handler a request for a host should go to. Hosts under the
+/// wildcard domain are looked up by handler name, anything else has to be a
+/// verified custom domain.
+pub fn by_host(conn: &PgConnection, host: &str) -> Result<Option<Resolved>> {
+    use schema::custom_domains::dsl::{custom_domains, domain, verified_at};
+    let host = normalize(host);
+
+    if let Some(name) = WILDCARD_DOMAIN
+        .as_ref()
+        .and_then(|wildcard| wildcard_name(&host, wildcard))
+    {
+        return routing::by_name(conn, name).map(Some);
+    }
+
+    match custom_domains
+        .filter(domain.eq(&host))
+        .filter(verified_at.is_not_null())
+        .first::<models::CustomDomain>(conn)
+        .optional()?
+    {
+        Some(dom) => routing::by_id(conn, dom.handler_id)
+            .map(Resolved::Handler)
+            .map(Some),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::prelude::*;
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    /// Answers from a fixed set of records, anything else doesn't exist.
+    struct FakeResolver(HashMap<String, Vec<String>>);
+
+    impl TxtResolver for FakeResolver {
+        fn txt(&self, name: &str) -> eyre::Result<Vec<String>> {
+            self.0
+                .get(name)
+                .cloned()
+                .ok_or_else(|| eyre::eyre!("no records for {}", name))
+        }
+    }
+
+    fn resolver(records: &[(&str, &str)]) -> Resolver {
+        let mut map: HashMap<String, Vec<String>> = HashMap::new();
+        for (name, value) in records {
+            map.entry(name.to_string())
+                .or_default()
+                .push(value.to_string());
+        }
+        Resolver::new(FakeResolver(map))
+    }
+
+    fn domain(name: &str, token: &str) -> models::CustomDomain {
+        let now = Utc::now().naive_utc();
+        models::CustomDomain {
+            id: Uuid::new_v4(),
+            handler_id: Uuid::new_v4(),
+            domain: name.to_string(),
+            verification_token: token.to_string(),
+            verified_at: None,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    #[test]
+    fn verifies_published_token() {
+        let resolver = resolver(&[
+            ("_wasmcloud-challenge.example.com", "v=spf1 -all"),
+            ("_wasmcloud-challenge.example.com", " wasmcloud-verify=abc "),
+        ]);
+        assert!(resolver.verify(&domain("example.com", "abc")));
+    }
+
+    #[test]
+    fn rejects_other_token() {
+        let resolver = resolver(&[("_wasmcloud-challenge.example.com", "wasmcloud-verify=xyz")]);
+        assert!(!resolver.verify(&domain("example.com", "abc")));
+    }
+
+    #[test]
+    fn rejects_token_on_wrong_name() {
+        let resolver = resolver(&[("example.com", "wasmcloud-verify=abc")]);
+        assert!(!resolver.verify(&domain("example.com", "abc")));
+    }
+
+    #[test]
+    fn rejects_when_lookup_fails() {
+        let resolver = resolver(&[]);
+        assert!(!resolver.verify(&domain("example.com", "abc")));
+    }
+
+    #[test]
+    fn normalizes_hosts() {
+        assert_eq!(normalize("Example.COM"), "example.com");
+        assert_eq!(normalize("example.com:8000"), "example.com");
+        assert_eq!(normalize(" example.com. "), "example.com");
+        assert_eq!(normalize("example.com.:443"), "example.com");
+        assert_eq!(normalize("[::1]"), "[::1]");
+        assert_eq!(normalize("[::1]:8000"), "[::1]");
+    }
+
+    #[test]
+    fn finds_names_under_wildcard() {
+        let wildcard = "run.example.com";
+        assert_eq!(
+            wildcard_name("hello.run.example.com", wildcard),
+            Some("hello")
+        );
+        assert_eq!(wildcard_name("run.example.com", wildcard), None);
+        assert_eq!(wildcard_name("a.b.run.example.com", wildcard), None);
+        assert_eq!(wildcard_name("hellorun.example.com", wildcard), None);
+        assert_eq!(wildcard_name("hello.example.com", wildcard), None);
+    }
+
+    #[test]
+    fn validates_domains() {
+        assert!(valid_domain("example.com"));
+        assert!(valid_domain("a-b.example.com"));
+        assert!(!valid_domain("localhost"));
+        assert!(!valid_domain("-a.example.com"));
+        assert!(!valid_domain("Example.com"));
+        assert!(!valid_domain("a..example.com"));
+    }
+}
@@ -0,0 +1,257 @@
+use crate::{models, schema};
+use diesel::{pg::PgConnection, prelude::*};
+use lazy_static::lazy_static;
+use std::{
+    env,
+    io::Read,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+lazy_static! {
+    /// How long an outbound request of a guest may take.
+    pub static ref REQUEST_TIMEOUT: Duration = Duration::from_millis(
+        env::var("EGRESS_TIMEOUT_MS")
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .unwrap_or(10_000)
+    );
+    /// The largest response body a guest gets handed.
+    pub static ref MAX_RESPONSE_BYTES: u64 = env::var("EGRESS_MAX_RESPONSE_BYTES")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(1024 * 1024);
+    /// The largest request body a guest may send.
+    pub static ref MAX_REQUEST_BYTES: u64 = env::var("EGRESS_MAX_REQUEST_BYTES")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(1024 * 1024);
+}
+
+/// Checks an allow-list entry. Entries are host names, `*.example.com` also
+/// allows every subdomain of `example.com` but not `example.com` itself.
+pub fn valid_host(host: &str) -> bool {
+    let host = host.strip_prefix("*.").unwrap_or(host);
+    !host.is_empty()
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        })
+}
+
+pub fn host_allowed(allow: &[String], host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+    allow.iter().any(|entry| match entry.strip_prefix("*.") {
+        Some(suffix) => host
+            .strip_suffix(suffix)
+            .map(|rest| rest.ends_with('.') && rest.len() > 1)
+            .unwrap_or(false),
+        None => *entry == host,
+    })
+}
+
+pub fn allow_list(conn: &PgConnection, hdl_id: Uuid) -> QueryResult<Vec<String>> {
+    use schema::handler_allowed_hosts::dsl::{handler_allowed_hosts, handler_id, host};
+
+    handler_allowed_hosts
+        .filter(handler_id.eq(hdl_id))
+        .order(host.asc())
+        .select(host)
+        .load::<String>(conn)
+}
+
+/// A request a guest wants the executor to make for it.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub url: String,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// What a guest asked for and what came of it. These are stored with the
+/// execution once it finishes.
+#[derive(Debug, Clone)]
+pub struct Log {
+    pub method: String,
+    pub url: String,
+    pub status: Option<i32>,
+    pub response_bytes: i64,
+    pub duration: Duration,
+    pub error: Option<String>,
+}
+
+impl Log {
+    pub fn into_record(self, execution_id: Uuid) -> models::NewExecutionHttpRequest {
+        models::NewExecutionHttpRequest {
+            execution_id,
+            method: self.method,
+            url: self.url,
+            status: self.status,
+            response_bytes: self.response_bytes,
+            duration_ms: self.duration.as_millis() as i32,
+            error: self.error,
+        }
+    }
+}
+
+/// Makes a request on behalf of a guest if its handler may reach the host.
+#[instrument(skip(allow, req), fields(method = &req.method[..], url = &req.url[..]))]
+pub fn fetch(allow: &[String], req: Request) -> (Result<Response, String>, Log) {
+    let start = Instant::now();
+    let result = send(allow, &req);
+
+    let log = match &result {
+        Ok(resp) => Log {
+            method: req.method,
+            url: req.url,
+            status: Some(resp.status as i32),
+            response_bytes: resp.body.len() as i64,
+            duration: start.elapsed(),
+            error: None,
+        },
+        Err(why) => Log {
+            method: req.method,
+            url: req.url,
+            status: None,
+            response_bytes: 0,
+            duration: start.elapsed(),
+            error: Some(why.clone()),
+        },
+    };
+    info!(
+        status = ?log.status,
+        duration = log.duration.as_millis() as i64,
+        error = ?log.error,
+        "guest http request"
+    );
+
+    (result, log)
+}
+
+fn send(allow: &[String], req: &Request) -> Result<Response, String> {
+    let u = url::Url::parse(&req.url).map_err(|why| format!("invalid url: {}", why))?;
+    match u.scheme() {
+        "http" | "https" => {}
+        scheme => return Err(format!("scheme {} is not allowed", scheme)),
+    }
+    let host = u.host_str().ok_or_else(|| "url has no host".to_string())?;
+    if !host_allowed(allow, host) {
+        return Err(format!("host {} is not in the allow-list", host));
+    }
+
+    // Redirects are not followed, they could lead anywhere.
+    let mut request = ureq::request(&req.method, u.as_str());
+    request
+        .timeout(*REQUEST_TIMEOUT)
+        .redirects(0)
+        .set("User-Agent", crate::APP_USER_AGENT);
+    if let Some(content_type) = req.content_type.as_ref() {
+        request.set("Content-Type", content_type);
+    }
+
+    let resp = request.send_bytes(&req.body);
+    if let Some(why) = resp.synthetic_error() {
+        return Err(why.to_string());
+    }
+
+    let status = resp.status();
+    let content_type = resp.header("Content-Type").map(str::to_string);
+    let mut body = vec![];
+    resp.into_reader()
+        .take(*MAX_RESPONSE_BYTES + 1)
+        .read_to_end(&mut body)
+        .map_err(|why| format!("can't read response: {}", why))?;
+    if body.len() as u64 > *MAX_RESPONSE_BYTES {
+        return Err(format!(
+            "response is larger than {} bytes",
+            *MAX_RESPONSE_BYTES
+        ));
+    }
+
+    Ok(Response {
+        status,
+        content_type,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allow(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|e| e.to_string()).collect()
+    }
+
+    fn get(url: &str) -> Request {
+        Request {
+            method: "GET".into(),
+            url: url.into(),
+            content_type: None,
+            body: vec![],
+        }
+    }
+
+    #[test]
+    fn validates_entries() {
+        assert!(valid_host("api.example.com"));
+        assert!(valid_host("*.example.com"));
+        assert!(valid_host("localhost"));
+        assert!(!valid_host(""));
+        assert!(!valid_host("*."));
+        assert!(!valid_host("*example.com"));
+        assert!(!valid_host("a.*.example.com"));
+        assert!(!valid_host("API.example.com"));
+        assert!(!valid_host("example.com:443"));
+        assert!(!valid_host("a..example.com"));
+    }
+
+    #[test]
+    fn allows_exact_hosts() {
+        let allow = allow(&["api.example.com"]);
+        assert!(host_allowed(&allow, "api.example.com"));
+        assert!(host_allowed(&allow, "API.Example.com"));
+        assert!(!host_allowed(&allow, "example.com"));
+        assert!(!host_allowed(&allow, "v2.api.example.com"));
+        assert!(!host_allowed(&allow, "api.example.com.evil.test"));
+        assert!(!host_allowed(&[], "api.example.com"));
+    }
+
+    #[test]
+    fn allows_subdomains_of_wildcards() {
+        let allow = allow(&["*.example.com"]);
+        assert!(host_allowed(&allow, "api.example.com"));
+        assert!(host_allowed(&allow, "v2.api.example.com"));
+        assert!(!host_allowed(&allow, "example.com"));
+        assert!(!host_allowed(&allow, ".example.com"));
+        assert!(!host_allowed(&allow, "badexample.com"));
+        assert!(!host_allowed(&allow, "example.com.evil.test"));
+    }
+
+    #[test]
+    fn refuses_before_connecting() {
+        let allow = allow(&["example.com"]);
+        for url in &[
+            "not a url",
+            "ftp://example.com/file",
+            "file:///etc/passwd",
+            "http://other.example/",
+            "http://127.0.0.1/",
+        ] {
+            let (result, log) = fetch(&allow, get(url));
+            assert!(result.is_err(), "{}", url);
+            assert_eq!(log.status, None);
+            assert!(log.error.is_some());
+        }
+    }
+}
@@ -0,0 +1,388 @@
+use color_eyre::eyre::Result;
+use diesel::{pg::PgConnection, prelude::*, sql_types::Text};
+use lazy_static::lazy_static;
+use postgres::{fallible_iterator::FallibleIterator, Client, NoTls};
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    io::{self, Read},
+    mem,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+/// The Postgres channel the executor announces what it is doing on.
+pub const CHANNEL: &str = "wasmcloud_executions";
+
+/// Notification payloads are limited to 8000 bytes, long log lines are cut
+/// short to fit. The full line is still stored with the execution.
+const MAX_MESSAGE_LEN: usize = 4096;
+const MAX_PAYLOAD_LEN: usize = 7900;
+
+/// How long log lines may wait to be sent along with the next ones.
+pub const FLUSH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often a quiet stream sends a comment so proxies don't close it.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Started {
+        handler_id: Uuid,
+        execution_id: Uuid,
+        version_id: Option<Uuid>,
+    },
+    Log {
+        handler_id: Uuid,
+        execution_id: Uuid,
+        stream: String,
+        line_number: i32,
+        message: String,
+    },
+    Finished {
+        handler_id: Uuid,
+        execution_id: Uuid,
+        exit_code: Option<i32>,
+        execution_time: i32,
+    },
+}
+
+impl Event {
+    pub fn handler_id(&self) -> Uuid {
+        match self {
+            Event::Started { handler_id, .. }
+            | Event::Log { handler_id, .. }
+            | Event::Finished { handler_id, .. } => *handler_id,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Event::Started { .. } => "started",
+            Event::Log { .. } => "log",
+            Event::Finished { .. } => "finished",
+        }
+    }
+}
+
+fn truncate(message: &mut String) {
+    if message.len() > MAX_MESSAGE_LEN {
+        let mut end = MAX_MESSAGE_LEN;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+}
+
+lazy_static! {
+    /// The subscribers of the API server.
+    pub static ref HUB: Hub = Hub::default();
+
+    /// How many event streams the API server serves at once. Every stream
+    /// keeps a worker busy, so this has to stay well below the number of
+    /// workers.
+    pub static ref MAX_STREAMS: usize = env::var("EVENT_STREAM_LIMIT")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(4);
+}
+
+/// Tells everyone listening about an event. Failing to do so is logged but
+/// otherwise ignored, nobody may be listening anyways.
+pub fn notify(conn: &PgConnection, event: Event) {
+    let mut batch = Batch::new(conn);
+    batch.push(event);
+}
+
+/// Events that are sent together, in as few notifications as fit. Log lines
+/// come in quickly, one notification each would keep the database busy.
+/// Whatever is left is sent when the batch is dropped.
+pub struct Batch<'a> {
+    conn: &'a PgConnection,
+    /// The encoded events, without the brackets around them.
+    payload: String,
+    since: Instant,
+}
+
+impl<'a> Batch<'a> {
+    pub fn new(conn: &'a PgConnection) -> Self {
+        Batch {
+            conn,
+            payload: String::new(),
+            since: Instant::now(),
+        }
+    }
+
+    pub fn push(&mut self, mut event: Event) {
+        if let Event::Log { message, .. } = &mut event {
+            truncate(message);
+        }
+        let encoded = match serde_json::to_string(&event) {
+            Ok(encoded) => encoded,
+            Err(why) => {
+                error!("can't encode event: {}", why);
+                return;
+            }
+        };
+
+        if self.payload.len() + encoded.len() + 3 > MAX_PAYLOAD_LEN {
+            self.flush();
+        }
+        if !self.payload.is_empty() {
+            self.payload.push(',');
+        }
+        self.payload.push_str(&encoded);
+
+        // Only log lines are worth waiting for more of.
+        if !matches!(event, Event::Log { .. }) || self.since.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    /// Sends what is waiting, if anything.
+    pub fn flush(&mut self) {
+        self.since = Instant::now();
+        if self.payload.is_empty() {
+            return;
+        }
+
+        let payload = format!("[{}]", mem::take(&mut self.payload));
+        if let Err(why) = diesel::sql_query("SELECT pg_notify($1, $2)")
+            .bind::<Text, _>(CHANNEL)
+            .bind::<Text, _>(payload)
+            .execute(self.conn)
+        {
+            error!("can't send events: {}", why);
+        }
+    }
+}
+
+impl Drop for Batch<'_> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Hands events from the database out to everyone watching a handler.
+#[derive(Default)]
+pub struct Hub(Mutex<Vec<(Uuid, mpsc::Sender<Event>)>>);
+
+impl Hub {
+    pub fn subscribe(&self, handler_id: Uuid) -> mpsc::Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+        self.0.lock().unwrap().push((handler_id, tx));
+        rx
+    }
+
+    /// Passes an event on, forgetting about subscribers that went away.
+    fn publish(&self, event: Event) {
+        let handler_id = event.handler_id();
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|(id, tx)| *id != handler_id || tx.send(event.clone()).is_ok());
+    }
+}
+
+/// Listens for events in the background and publishes them to HUB,
+/// reconnecting whenever the connection to the database is lost.
+pub fn listen(database_url: String) {
+    thread::spawn(move || loop {
+        if let Err(why) = listen_once(&database_url, &HUB) {
+            error!("lost event listener connection: {}", why);
+        }
+        thread::sleep(Duration::from_secs(1));
+    });
+}
+
+fn listen_once(database_url: &str, hub: &Hub) -> Result<()> {
+    let mut client = Client::connect(database_url, NoTls)?;
+    client.batch_execute(&format!("LISTEN {}", CHANNEL))?;
+    info!(channel = CHANNEL, "listening for execution events");
+
+    let mut notifications = client.notifications();
+    let mut iter = notifications.blocking_iter();
+    while let Some(notification) = iter.next()? {
+        match serde_json::from_str::<Vec<Event>>(notification.payload()) {
+            Ok(events) => events.into_iter().for_each(|event| hub.publish(event)),
+            Err(why) => error!("can't decode events: {}", why),
+        }
+    }
+
+    Ok(())
+}
+
+static OPEN_STREAMS: AtomicUsize = AtomicUsize::new(0);
+
+/// One of the MAX_STREAMS event streams, given back when dropped.
+pub struct StreamSlot(());
+
+impl StreamSlot {
+    /// Takes a slot, unless they are all in use.
+    pub fn take() -> Option<Self> {
+        let mut open = OPEN_STREAMS.load(Ordering::SeqCst);
+        loop {
+            if open >= *MAX_STREAMS {
+                return None;
+            }
+            match OPEN_STREAMS.compare_exchange(open, open + 1, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return Some(StreamSlot(())),
+                Err(now) => open = now,
+            }
+        }
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        OPEN_STREAMS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Server-Sent Events for one subscriber. Rocket fills a whole chunk before
+/// sending it, so every event is followed by an empty read that makes it flush
+/// what it has.
+pub struct EventStream {
+    events: mpsc::Receiver<Event>,
+    pending: Vec<u8>,
+    flush: bool,
+    _slot: StreamSlot,
+}
+
+impl EventStream {
+    pub fn new(events: mpsc::Receiver<Event>, slot: StreamSlot) -> Self {
+        EventStream {
+            events,
+            pending: b"retry: 5000\n\n".to_vec(),
+            flush: false,
+            _slot: slot,
+        }
+    }
+}
+
+impl Read for EventStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            if self.flush {
+                self.flush = false;
+                return Ok(0);
+            }
+
+            self.pending = match self.events.recv_timeout(KEEP_ALIVE) {
+                Ok(event) => format!(
+                    "event: {}\ndata: {}\n\n",
+                    event.name(),
+                    serde_json::to_string(&event)?
+                )
+                .into_bytes(),
+                Err(mpsc::RecvTimeoutError::Timeout) => b": keep-alive\n\n".to_vec(),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+        }
+
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        if self.pending.is_empty() {
+            self.flush = true;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(message: &str) -> Event {
+        Event::Log {
+            handler_id: Uuid::nil(),
+            execution_id: Uuid::nil(),
+            stream: "stdout".into(),
+            line_number: 1,
+            message: message.into(),
+        }
+    }
+
+    #[test]
+    fn tags_events_with_their_type() {
+        let event = Event::Finished {
+            handler_id: Uuid::nil(),
+            execution_id: Uuid::nil(),
+            exit_code: Some(0),
+            execution_time: 12,
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["type"], "finished");
+        assert_eq!(json["execution_time"], 12);
+        assert_eq!(event.name(), "finished");
+
+        match serde_json::from_value::<Event>(json).unwrap() {
+            Event::Finished { exit_code, .. } => assert_eq!(exit_code, Some(0)),
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn truncates_on_char_boundaries() {
+        let mut message = "é".repeat(MAX_MESSAGE_LEN);
+        truncate(&mut message);
+        assert!(message.len() <= MAX_MESSAGE_LEN);
+        assert!(message.len() >= MAX_MESSAGE_LEN - 1);
+
+        let mut short = "hello".to_string();
+        truncate(&mut short);
+        assert_eq!(short, "hello");
+    }
+
+    #[test]
+    fn streams_events_as_sse() {
+        let (tx, rx) = mpsc::channel();
+        tx.send(log("hi")).unwrap();
+        drop(tx);
+
+        // An empty read only flushes, the stream is over after two in a row.
+        let mut stream = EventStream::new(rx);
+        let mut out = vec![];
+        let mut buf = [0; 64];
+        let mut empty = 0;
+        while empty < 2 {
+            match stream.read(&mut buf).unwrap() {
+                0 => empty += 1,
+                n => {
+                    empty = 0;
+                    out.extend_from_slice(&buf[..n]);
+                }
+            }
+        }
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("retry: 5000\n\n"));
+        assert!(out.contains("event: log\ndata: {"));
+        assert!(out.contains("\"message\":\"hi\""));
+    }
+
+    #[test]
+    fn publishes_to_matching_subscribers() {
+        let hub = Hub::default();
+        let other = Uuid::new_v4();
+        let mine = hub.subscribe(Uuid::nil());
+        let theirs = hub.subscribe(other);
+
+        hub.publish(log("hi"));
+        assert!(mine.try_recv().is_ok());
+        assert!(theirs.try_recv().is_err());
+
+        drop(mine);
+        hub.publish(log("again"));
+        assert_eq!(hub.0.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,184 @@
+use crate::{api::Result, b2, schema};
+use chrono::prelude::*;
+use diesel::{pg::PgConnection, prelude::*};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::{collections::HashSet, env};
+use uuid::Uuid;
+
+lazy_static! {
+    /// How long a blob nothing refers to is kept anyways. This covers uploads
+    /// that haven't been recorded yet.
+    pub static ref GRACE_PERIOD: chrono::Duration = chrono::Duration::hours(
+        env::var("GC_GRACE_HOURS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(72)
+    );
+}
+
+/// What a collection found, and removed unless it was a dry run.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub dry_run: bool,
+    /// How many distinct blobs are still in use.
+    pub referenced: usize,
+    /// How many blobs are in the bucket.
+    pub scanned: usize,
+    /// Unused blobs that are kept because they are within the grace period.
+    pub recent: usize,
+    /// The blobs that were (or would have been) deleted.
+    pub deleted: Vec<String>,
+    pub deleted_bytes: u64,
+    /// Blobs that couldn't be deleted, with why.
+    pub failed: Vec<String>,
+}
+
+/// The URLs of every module and asset bundle a handler, environment or version
+/// refers to. Deleted handlers count until they are purged, they can be
+/// restored until then.
+pub fn referenced(conn: &PgConnection) -> QueryResult<HashSet<String>> {
+    let (live, current): (Vec<Uuid>, Vec<Option<String>>) = {
+        use schema::handlers::dsl::{current_version, handlers, id};
+        handlers
+            .select((id, current_version))
+            .load::<(Uuid, Option<String>)>(conn)?
+            .into_iter()
+            .unzip()
+    };
+
+    let environments = {
+        use schema::handler_environments::dsl::{
+            current_version, handler_environments, handler_id,
+        };
+        handler_environments
+            .select(current_version)
+            .filter(handler_id.eq_any(&live))
+            .load::<Option<String>>(conn)?
+    };
+
+    let versions = {
+        use schema::handler_versions::dsl::{assets_url, handler_id, handler_versions, module_url};
+        handler_versions
+            .select((module_url, assets_url))
+            .filter(handler_id.eq_any(&live))
+            .load::<(String, Option<String>)>(conn)?
+    };
+
+    let mut urls: HashSet<String> = current.into_iter().flatten().collect();
+    urls.extend(environments.into_iter().flatten());
+    for (module, assets) in versions {
+        urls.insert(module);
+        urls.extend(assets);
+    }
+
+    Ok(urls)
+}
+
+/// Deletes the blobs in the module bucket that nothing refers to anymore and
+/// that are older than the grace period. A dry run only reports what would be
+/// deleted.
+#[instrument(skip(conn), err)]
+pub fn collect(conn: &PgConnection, dry_run: bool) -> Result<Report> {
+    let referenced = referenced(conn)?;
+    let blobs = b2::list()?;
+    let cutoff = (Utc::now() - *GRACE_PERIOD).timestamp_millis() as u64;
+
+    let mut report = Report {
+        dry_run,
+        referenced: referenced.len(),
+        scanned: blobs.len(),
+        ..Report::default()
+    };
+
+    for blob in blobs {
+        if referenced.contains(&blob.url()) {
+            continue;
+        }
+        if blob.uploaded_at > cutoff {
+            report.recent += 1;
+            continue;
+        }
+
+        if !dry_run {
+            if let Err(why) = b2::delete(&blob) {
+                error!(blob = blob.name.as_str(), "can't delete blob: {}", why);
+                report.failed.push(format!("{}: {}", blob.name, why));
+                continue;
+            }
+        }
+        report.deleted_bytes += blob.size;
+        report.deleted.push(blob.name);
+    }
+
+    info!(
+        dry_run = dry_run,
+        scanned = report.scanned as u64,
+        deleted = report.deleted.len() as u64,
+        deleted_bytes = report.deleted_bytes,
+        "collected module blobs"
+    );
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models, testing};
+
+    fn deleted(conn: &PgConnection, hdl: &models::Handler, at: NaiveDateTime) {
+        use schema::handlers::dsl::{deleted_at, handlers};
+        diesel::update(handlers.find(hdl.id))
+            .set(deleted_at.eq(Some(at)))
+            .execute(conn)
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn references_live_and_recently_deleted_handlers() {
+        use schema::handlers::dsl::{current_version, handlers};
+
+        let conn = testing::conn();
+        let user = testing::user(&conn);
+
+        let live = testing::handler(&conn, &user);
+        diesel::update(handlers.find(live.id))
+            .set(current_version.eq(Some("https://cdn.test/live")))
+            .execute(&conn)
+            .unwrap();
+        diesel::insert_into(schema::handler_environments::table)
+            .values(&models::NewHandlerEnvironment {
+                handler_id: live.id,
+                name: "staging".into(),
+                current_version: Some("https://cdn.test/staging".into()),
+                version_id: None,
+            })
+            .execute(&conn)
+            .unwrap();
+        testing::version(&conn, &live, "https://cdn.test/old");
+
+        let recent = testing::handler(&conn, &user);
+        testing::version(&conn, &recent, "https://cdn.test/recent");
+        deleted(&conn, &recent, Utc::now().naive_utc());
+
+        let gone = testing::handler(&conn, &user);
+        testing::version(&conn, &gone, "https://cdn.test/gone");
+        deleted(
+            &conn,
+            &gone,
+            Utc::now().naive_utc() - *GRACE_PERIOD - chrono::Duration::hours(1),
+        );
+
+        let urls = referenced(&conn).unwrap();
+        for url in &["live", "staging", "old", "recent"] {
+            assert!(
+                urls.contains(&format!("https://cdn.test/{}", url)),
+                "{}",
+                url
+            );
+        }
+        assert!(!urls.contains("https://cdn.test/gone"));
+    }
+}
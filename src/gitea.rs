@@ -112,6 +112,7 @@ pub fn callback(
     let tok: models::Token = diesel::insert_into(tokens::table)
         .values(&models::NewToken {
             user_id: user.id.clone(),
+            scopes: jwt::SCOPE_API.to_string(),
         })
         .get_result(&*conn)
         .map_err(api::Error::Database)?;
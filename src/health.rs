@@ -0,0 +1,215 @@
+use crate::{b2, MainDatabasePool};
+use diesel::prelude::*;
+use lazy_static::lazy_static;
+use rocket::{
+    http::Status,
+    response::{self, Responder},
+    Request, State,
+};
+use rocket_contrib::json::{Json, JsonValue};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How long readiness waits for a database connection.
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long readiness waits for storage, and how long its answer is reused.
+/// Asking storage costs a transaction, probes come every few seconds.
+const STORAGE_TIMEOUT: Duration = Duration::from_secs(5);
+const STORAGE_TTL: Duration = Duration::from_secs(60);
+
+/// How one dependency is doing.
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl<E: std::fmt::Display> From<std::result::Result<(), E>> for Check {
+    fn from(result: std::result::Result<(), E>) -> Self {
+        match result {
+            Ok(()) => Check {
+                ok: true,
+                error: None,
+            },
+            Err(why) => Check {
+                ok: false,
+                error: Some(why.to_string()),
+            },
+        }
+    }
+}
+
+/// Whether a service is ready, with how each of its dependencies is doing. It
+/// is answered with a 503 unless everything is fine.
+#[derive(Debug, Default, Serialize)]
+pub struct Readiness {
+    pub ok: bool,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+impl Readiness {
+    pub fn check(mut self, name: &'static str, check: impl Into<Check>) -> Self {
+        self.checks.insert(name, check.into());
+        self.ok = self.checks.values().all(|c| c.ok);
+        self
+    }
+}
+
+impl<'a> Responder<'a> for Readiness {
+    fn respond_to(self, req: &Request) -> response::Result<'a> {
+        let status = if self.ok {
+            Status::Ok
+        } else {
+            Status::ServiceUnavailable
+        };
+        response::Response::build_from(Json(self).respond_to(req)?)
+            .status(status)
+            .ok()
+    }
+}
+
+/// Gets a connection from the pool and makes a trivial query with it.
+pub fn database(pool: &MainDatabasePool) -> Result<(), String> {
+    let conn = pool
+        .0
+        .get_timeout(DATABASE_TIMEOUT)
+        .map_err(|why| why.to_string())?;
+    diesel::sql_query("SELECT 1")
+        .execute(&*conn)
+        .map_err(|why| why.to_string())?;
+    Ok(())
+}
+
+/// Makes sure files can be created in a directory.
+pub fn writable(dir: &Path) -> Result<(), String> {
+    let probe = dir.join(format!(".ready-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(dir).map_err(|why| why.to_string())?;
+    fs::write(&probe, b"").map_err(|why| why.to_string())?;
+    fs::remove_file(&probe).map_err(|why| why.to_string())?;
+    Ok(())
+}
+
+/// Looks for an executable in `PATH`.
+pub fn executable(name: &str) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = env::var_os("PATH").unwrap_or_default();
+    env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .filter_map(|path| fs::metadata(path).ok())
+        .find(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .map(|_| ())
+        .ok_or_else(|| format!("{} is not in PATH", name))
+}
+
+/// Makes an HTTP request and counts any answer from the server as reachable.
+pub fn reachable(url: &str) -> Result<(), String> {
+    let resp = ureq::head(url)
+        .set("User-Agent", crate::APP_USER_AGENT)
+        .timeout(STORAGE_TIMEOUT)
+        .call();
+    match resp.synthetic_error() {
+        Some(why) => Err(why.to_string()),
+        None => Ok(()),
+    }
+}
+
+/// The last result of a check that is too slow or costly to make every time.
+#[derive(Default)]
+pub struct Cached(Mutex<Option<(Instant, Result<(), String>)>>);
+
+impl Cached {
+    /// Makes the check unless its last result is younger than ttl. Checks
+    /// that come in meanwhile wait for it instead of making their own.
+    pub fn get(
+        &self,
+        ttl: Duration,
+        check: impl FnOnce() -> Result<(), String>,
+    ) -> Result<(), String> {
+        let mut last = self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match last.as_ref() {
+            Some((at, result)) if at.elapsed() < ttl => result.clone(),
+            _ => {
+                let result = check();
+                *last = Some((Instant::now(), result.clone()));
+                result
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref STORAGE: Cached = Cached::default();
+    static ref CDN: Cached = Cached::default();
+}
+
+/// Whether the CDN modules are downloaded from is [reachable]. Like storage,
+/// the answer is reused for a while.
+pub fn cdn(url: &str) -> Result<(), String> {
+    CDN.get(STORAGE_TTL, || reachable(url))
+}
+
+/// Whether the process is up at all.
+#[get("/healthz")]
+pub fn healthz() -> JsonValue {
+    json!({ "ok": true })
+}
+
+/// Whether the API server can serve requests: its database and the storage
+/// modules are uploaded to must be reachable.
+#[get("/readyz")]
+pub fn readyz(pool: State<MainDatabasePool>) -> Readiness {
+    Readiness::default()
+        .check("database", database(&pool))
+        .check(
+            "storage",
+            STORAGE.get(STORAGE_TTL, || {
+                b2::reachable(STORAGE_TIMEOUT).map_err(|why| why.to_string())
+            }),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn reuses_fresh_results() {
+        let cached = Cached::default();
+        let calls = Cell::new(0);
+        let check = || {
+            calls.set(calls.get() + 1);
+            Err("down".to_string())
+        };
+
+        assert!(cached.get(Duration::from_secs(60), check).is_err());
+        assert!(cached.get(Duration::from_secs(60), check).is_err());
+        assert_eq!(calls.get(), 1);
+
+        assert!(cached.get(Duration::from_secs(0), || Ok(())).is_ok());
+        assert!(cached.get(Duration::from_secs(60), check).is_ok());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn reports_failed_checks() {
+        let ready = Readiness::default()
+            .check("a", Ok::<(), String>(()))
+            .check("b", Err::<(), _>("nope"));
+        assert!(!ready.ok);
+        assert_eq!(ready.checks["b"].error.as_deref(), Some("nope"));
+        assert!(Readiness::default().check("a", Ok::<(), String>(())).ok);
+    }
+}
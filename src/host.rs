@@ -0,0 +1,136 @@
+use crate::egress;
+use rand::{rngs::OsRng, RngCore};
+use std::{collections::HashMap, sync::Mutex};
+use uuid::Uuid;
+
+/// What the executor knows about a running guest when it makes a host call.
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub handler_id: Uuid,
+    /// The tier of the user owning the handler, which decides its quotas.
+    pub tier: i32,
+    /// The hosts the guest may make HTTP requests to.
+    pub allow: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Session {
+    caller: Caller,
+    requests: Vec<egress::Log>,
+}
+
+/// Guests reach the host over HTTP with a token that is only valid while
+/// their execution runs. This keeps track of those tokens.
+#[derive(Debug, Default)]
+pub struct Sessions(Mutex<HashMap<String, Session>>);
+
+impl Sessions {
+    /// Starts accepting host calls for an execution and returns its token.
+    pub fn open(&self, caller: Caller) -> String {
+        let mut buf = [0u8; 32];
+        OsRng.fill_bytes(&mut buf);
+        let token = hex::encode(buf);
+
+        self.0.lock().unwrap().insert(
+            token.clone(),
+            Session {
+                caller,
+                requests: vec![],
+            },
+        );
+        token
+    }
+
+    /// Stops accepting host calls for an execution and returns the outbound
+    /// requests it made.
+    pub fn close(&self, token: &str) -> Vec<egress::Log> {
+        self.0
+            .lock()
+            .unwrap()
+            .remove(token)
+            .map(|s| s.requests)
+            .unwrap_or_default()
+    }
+
+    pub fn caller(&self, token: &str) -> Option<Caller> {
+        self.0.lock().unwrap().get(token).map(|s| s.caller.clone())
+    }
+
+    pub fn record(&self, token: &str, log: egress::Log) {
+        if let Some(session) = self.0.lock().unwrap().get_mut(token) {
+            session.requests.push(log);
+        }
+    }
+}
+
+/// Renders a pahi policy that only lets a guest reach the host calls of its
+/// own execution under `base_url`.
+pub fn policy(base_url: &str) -> String {
+    let mut escaped = String::new();
+    for c in base_url.chars() {
+        if !c.is_ascii_alphanumeric() && c != '/' && c != ':' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    format!("allow (\n  ^{}.*$\n)\n", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn caller() -> Caller {
+        Caller {
+            handler_id: Uuid::new_v4(),
+            tier: 0,
+            allow: vec!["example.com".into()],
+        }
+    }
+
+    fn log(url: &str) -> egress::Log {
+        egress::Log {
+            method: "GET".into(),
+            url: url.into(),
+            status: Some(200),
+            response_bytes: 0,
+            duration: Duration::from_millis(1),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn tokens_only_work_while_open() {
+        let sessions = Sessions::default();
+        let caller = caller();
+        let token = sessions.open(caller.clone());
+        assert_ne!(token, sessions.open(caller.clone()));
+        assert_eq!(
+            sessions.caller(&token).map(|c| c.handler_id),
+            Some(caller.handler_id)
+        );
+        assert!(sessions.caller("nope").is_none());
+
+        sessions.record(&token, log("http://example.com/a"));
+        sessions.record(&token, log("http://example.com/b"));
+        let requests = sessions.close(&token);
+        assert_eq!(
+            requests.iter().map(|r| &r.url[..]).collect::<Vec<_>>(),
+            vec!["http://example.com/a", "http://example.com/b"]
+        );
+
+        assert!(sessions.caller(&token).is_none());
+        sessions.record(&token, log("http://example.com/c"));
+        assert!(sessions.close(&token).is_empty());
+    }
+
+    #[test]
+    fn policy_only_allows_the_host() {
+        assert_eq!(
+            policy("http://127.0.0.1:8001/host/abc/"),
+            "allow (\n  ^http://127\\.0\\.0\\.1:8001/host/abc/.*$\n)\n"
+        );
+    }
+}
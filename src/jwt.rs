@@ -1,7 +1,7 @@
 use crate::{models, schema, MainDatabase};
 
 use color_eyre::eyre::{eyre, Result};
-use diesel::prelude::*;
+use diesel::{pg::PgConnection, prelude::*};
 use hmac::{Hmac, NewMac};
 use jwt::{SignWithKey, VerifyWithKey};
 use lazy_static::lazy_static;
@@ -15,6 +15,12 @@ lazy_static! {
         .to_string();
 }
 
+/// Tokens with this scope can use the API.
+pub const SCOPE_API: &str = "api";
+/// Tokens with this scope can run handlers of their user that only accept JWTs.
+pub const SCOPE_INVOKE: &str = "invoke";
+pub const SCOPES: &[&str] = &[SCOPE_API, SCOPE_INVOKE];
+
 #[instrument]
 pub fn make(user_id: uuid::Uuid, token_id: uuid::Uuid) -> Result<String> {
     let key: Hmac<Sha256> = Hmac::new_varkey(&*SECRET.as_bytes()).unwrap();
@@ -27,8 +33,26 @@ pub fn make(user_id: uuid::Uuid, token_id: uuid::Uuid) -> Result<String> {
     Ok(token_str)
 }
 
+/// The ID of the token row a JWT was made for. The signature isn't checked,
+/// this is for clients that want to refer to their own token.
+pub fn token_id(token: &str) -> Result<uuid::Uuid> {
+    let token: jwt::Token<jwt::Header, BTreeMap<String, String>, _> =
+        jwt::Token::parse_unverified(token)?;
+    let jti = token
+        .claims()
+        .get("jti")
+        .ok_or(eyre!("can't get token ID from JWT"))?;
+    Ok(uuid::Uuid::parse_str(jti)?)
+}
+
 #[instrument(skip(token, conn))]
 pub fn verify(token: String, conn: MainDatabase) -> Result<models::User> {
+    verify_scoped(token, &*conn, SCOPE_API)
+}
+
+/// Verifies a token and checks that it was issued with the given scope.
+#[instrument(skip(token, conn))]
+pub fn verify_scoped(token: String, conn: &PgConnection, scope: &str) -> Result<models::User> {
     use schema::{tokens::dsl::tokens, users::dsl::users};
     let key: Hmac<Sha256> = Hmac::new_varkey(&*SECRET.as_bytes()).unwrap();
 
@@ -44,7 +68,7 @@ pub fn verify(token: String, conn: MainDatabase) -> Result<models::User> {
 
     let tok = tokens
         .find(uuid::Uuid::parse_str(&jti)?)
-        .get_result::<models::Token>(&*conn)?;
+        .get_result::<models::Token>(conn)?;
 
     if tok.deleted_at.is_some() {
         return Err(eyre!("token was deleted at {}", tok.deleted_at.unwrap()));
@@ -54,7 +78,11 @@ pub fn verify(token: String, conn: MainDatabase) -> Result<models::User> {
         return Err(eyre!("token and user mismatch"));
     }
 
-    let user = users.find(uid).get_result::<models::User>(&*conn)?;
+    if !tok.has_scope(scope) {
+        return Err(eyre!("token lacks the {} scope", scope));
+    }
+
+    let user = users.find(uid).get_result::<models::User>(conn)?;
 
     Ok(user)
 }
@@ -0,0 +1,331 @@
+use crate::{
+    api::{Error, Result},
+    models, schema,
+};
+use chrono::{prelude::*, Duration};
+use diesel::{
+    dsl::sql,
+    pg::PgConnection,
+    prelude::*,
+    sql_types::{BigInt, Nullable},
+};
+use uuid::Uuid;
+
+/// The longest key a handler can store.
+pub const MAX_KEY_LEN: usize = 512;
+
+/// How many bytes of keys and values a handler may store, by the tier of its
+/// user.
+pub fn quota(tier: i32) -> i64 {
+    match tier {
+        t if t <= 0 => 1024 * 1024,
+        1 => 64 * 1024 * 1024,
+        _ => 1024 * 1024 * 1024,
+    }
+}
+
+pub fn valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LEN && !key.chars().any(char::is_control)
+}
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+/// How many bytes an entry counts against the quota.
+fn entry_size(key: &str, value: &[u8]) -> i64 {
+    (key.len() + value.len()) as i64
+}
+
+/// Checks whether a handler of a tier that stores `usage` bytes may replace
+/// an entry of `replaced` bytes with one of `added` bytes, and returns how
+/// much it would store then.
+fn within_quota(tier: i32, usage: i64, replaced: i64, added: i64) -> Result<i64> {
+    let used = usage - replaced + added;
+    if used > quota(tier) {
+        return Err(Error::BadRequest(format!(
+            "storing this would use {} of {} bytes",
+            used,
+            quota(tier)
+        )));
+    }
+    Ok(used)
+}
+
+/// When an entry written at `at` with a TTL expires.
+fn expiry(at: NaiveDateTime, ttl: Option<Duration>) -> Option<NaiveDateTime> {
+    ttl.map(|ttl| at + ttl)
+}
+
+/// Loads a key of a handler unless it has expired.
+pub fn get(conn: &PgConnection, hdl_id: Uuid, key: &str) -> QueryResult<Option<models::HandlerKv>> {
+    use schema::handler_kv::dsl::{expires_at, handler_id, handler_kv, key_name};
+
+    handler_kv
+        .filter(handler_id.eq(hdl_id))
+        .filter(key_name.eq(key))
+        .filter(expires_at.is_null().or(expires_at.gt(now())))
+        .first::<models::HandlerKv>(conn)
+        .optional()
+}
+
+/// Lists the keys of a handler that haven't expired, optionally only the ones
+/// starting with a prefix.
+pub fn list(
+    conn: &PgConnection,
+    hdl_id: Uuid,
+    prefix: Option<&str>,
+) -> QueryResult<Vec<models::HandlerKv>> {
+    use schema::handler_kv::dsl::{expires_at, handler_id, handler_kv, key_name};
+
+    let mut query = handler_kv
+        .filter(handler_id.eq(hdl_id))
+        .filter(expires_at.is_null().or(expires_at.gt(now())))
+        .order(key_name.asc())
+        .into_boxed();
+    if let Some(prefix) = prefix {
+        let pattern = format!(
+            "{}%",
+            prefix
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+        query = query.filter(key_name.like(pattern));
+    }
+
+    query.load::<models::HandlerKv>(conn)
+}
+
+/// How many bytes of keys and values a handler stores right now.
+pub fn usage(conn: &PgConnection, hdl_id: Uuid) -> QueryResult<i64> {
+    use schema::handler_kv::dsl::{expires_at, handler_id, handler_kv};
+
+    handler_kv
+        .filter(handler_id.eq(hdl_id))
+        .filter(expires_at.is_null().or(expires_at.gt(now())))
+        .select(sql::<Nullable<BigInt>>(
+            "SUM(octet_length(key_name) + octet_length(value))::BIGINT",
+        ))
+        .get_result::<Option<i64>>(conn)
+        .map(|n| n.unwrap_or(0))
+}
+
+/// Stores a value, replacing whatever was stored under the key before. The
+/// quota is checked with the handler row locked so that concurrent writes
+/// can't go over it together.
+#[instrument(skip(conn, value), err)]
+pub fn put(
+    conn: &PgConnection,
+    hdl_id: Uuid,
+    tier: i32,
+    key: &str,
+    value: Vec<u8>,
+    ttl: Option<Duration>,
+) -> Result<models::HandlerKv> {
+    use schema::handler_kv::dsl::{
+        expires_at, handler_id, handler_kv, key_name, value as kv_value,
+    };
+    use schema::handlers::dsl::handlers;
+
+    if !valid_key(key) {
+        return Err(Error::BadRequest(format!("invalid key {:?}", key)));
+    }
+
+    conn.transaction(|| {
+        handlers
+            .find(hdl_id)
+            .for_update()
+            .get_result::<models::Handler>(conn)?;
+        purge_expired(conn, hdl_id)?;
+
+        let replaced = get(conn, hdl_id, key)?
+            .map(|kv| entry_size(&kv.key_name, &kv.value))
+            .unwrap_or(0);
+        within_quota(
+            tier,
+            usage(conn, hdl_id)?,
+            replaced,
+            entry_size(key, &value),
+        )?;
+
+        let expiry = expiry(now(), ttl);
+        Ok(diesel::insert_into(handler_kv)
+            .values(&models::NewHandlerKv {
+                handler_id: hdl_id,
+                key_name: key.to_string(),
+                value: value.clone(),
+                expires_at: expiry,
+            })
+            .on_conflict((handler_id, key_name))
+            .do_update()
+            .set((kv_value.eq(&value), expires_at.eq(expiry)))
+            .get_result::<models::HandlerKv>(conn)?)
+    })
+}
+
+/// Removes a key. Returns whether there was anything to remove.
+pub fn delete(conn: &PgConnection, hdl_id: Uuid, key: &str) -> QueryResult<bool> {
+    use schema::handler_kv::dsl::{handler_id, handler_kv, key_name};
+
+    diesel::delete(
+        handler_kv
+            .filter(handler_id.eq(hdl_id))
+            .filter(key_name.eq(key)),
+    )
+    .execute(conn)
+    .map(|n| n > 0)
+}
+
+pub fn purge_expired(conn: &PgConnection, hdl_id: Uuid) -> QueryResult<usize> {
+    use schema::handler_kv::dsl::{expires_at, handler_id, handler_kv};
+
+    diesel::delete(
+        handler_kv
+            .filter(handler_id.eq(hdl_id))
+            .filter(expires_at.le(now())),
+    )
+    .execute(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn quotas_grow_with_the_tier() {
+        assert_eq!(quota(-1), quota(0));
+        assert!(quota(0) < quota(1));
+        assert!(quota(1) < quota(2));
+        assert_eq!(quota(2), quota(99));
+    }
+
+    #[test]
+    fn keys() {
+        assert!(valid_key("a"));
+        assert!(valid_key("users/42/name ünïcode"));
+        assert!(valid_key(&"k".repeat(MAX_KEY_LEN)));
+        assert!(!valid_key(""));
+        assert!(!valid_key(&"k".repeat(MAX_KEY_LEN + 1)));
+        assert!(!valid_key("line\nbreak"));
+        assert!(!valid_key("nul\0"));
+    }
+
+    #[test]
+    fn counts_keys_and_values() {
+        assert_eq!(entry_size("key", b"value"), 8);
+        assert_eq!(entry_size("ü", b""), 2);
+    }
+
+    #[test]
+    fn replacing_frees_the_old_entry() {
+        let q = quota(0);
+        assert_eq!(within_quota(0, 0, 0, q).unwrap(), q);
+        assert!(within_quota(0, 0, 0, q + 1).is_err());
+        assert!(within_quota(0, q, 0, 1).is_err());
+        // Swapping an entry for one of the same size fits in a full store.
+        assert_eq!(within_quota(0, q, 10, 10).unwrap(), q);
+        assert_eq!(within_quota(0, q, 10, 4).unwrap(), q - 6);
+        assert!(within_quota(0, q, 10, 11).is_err());
+        assert!(within_quota(1, q, 0, 1).is_ok());
+    }
+
+    #[test]
+    fn expires_after_the_ttl() {
+        let at = NaiveDate::from_ymd(2020, 11, 10).and_hms(12, 0, 0);
+        assert_eq!(expiry(at, None), None);
+        assert_eq!(
+            expiry(at, Some(Duration::seconds(90))),
+            Some(NaiveDate::from_ymd(2020, 11, 10).and_hms(12, 1, 30))
+        );
+        assert_eq!(expiry(at, Some(Duration::zero())), Some(at));
+    }
+
+    #[test]
+    #[ignore]
+    fn enforces_the_quota() {
+        let conn = testing::conn();
+        let user = testing::user(&conn);
+        let hdl = testing::handler(&conn, &user);
+        let half = (quota(0) / 2) as usize;
+
+        put(&conn, hdl.id, 0, "a", vec![0; half - 1], None).unwrap();
+        put(&conn, hdl.id, 0, "b", vec![0; half - 1], None).unwrap();
+        assert_eq!(usage(&conn, hdl.id).unwrap(), quota(0));
+        assert!(matches!(
+            put(&conn, hdl.id, 0, "c", vec![0], None),
+            Err(Error::BadRequest(_))
+        ));
+        // Overwriting with a smaller value is fine, so is a higher tier.
+        put(&conn, hdl.id, 0, "b", vec![], None).unwrap();
+        put(&conn, hdl.id, 1, "c", vec![0; half], None).unwrap();
+        assert_eq!(
+            usage(&conn, hdl.id).unwrap(),
+            (1 + half - 1 + 1 + 1 + half) as i64
+        );
+    }
+
+    #[test]
+    #[ignore]
+    fn expired_entries_are_gone() {
+        let conn = testing::conn();
+        let user = testing::user(&conn);
+        let hdl = testing::handler(&conn, &user);
+
+        put(
+            &conn,
+            hdl.id,
+            0,
+            "kept",
+            b"1".to_vec(),
+            Some(Duration::hours(1)),
+        )
+        .unwrap();
+        put(
+            &conn,
+            hdl.id,
+            0,
+            "gone",
+            b"2".to_vec(),
+            Some(Duration::seconds(-1)),
+        )
+        .unwrap();
+        assert!(get(&conn, hdl.id, "kept").unwrap().is_some());
+        assert!(get(&conn, hdl.id, "gone").unwrap().is_none());
+        assert_eq!(
+            list(&conn, hdl.id, None)
+                .unwrap()
+                .into_iter()
+                .map(|kv| kv.key_name)
+                .collect::<Vec<_>>(),
+            vec!["kept"]
+        );
+        assert_eq!(usage(&conn, hdl.id).unwrap(), 5);
+        assert_eq!(purge_expired(&conn, hdl.id).unwrap(), 1);
+    }
+
+    #[test]
+    #[ignore]
+    fn lists_by_literal_prefix() {
+        let conn = testing::conn();
+        let user = testing::user(&conn);
+        let hdl = testing::handler(&conn, &user);
+        for key in &["a_1", "ab", "a%", "b"] {
+            put(&conn, hdl.id, 0, key, vec![], None).unwrap();
+        }
+
+        let keys = |prefix| {
+            list(&conn, hdl.id, Some(prefix))
+                .unwrap()
+                .into_iter()
+                .map(|kv| kv.key_name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys("a_"), vec!["a_1"]);
+        assert_eq!(keys("a%"), vec!["a%"]);
+        assert_eq!(keys("a"), vec!["a%", "a_1", "ab"]);
+        assert!(delete(&conn, hdl.id, "ab").unwrap());
+        assert!(!delete(&conn, hdl.id, "ab").unwrap());
+    }
+}
@@ -12,11 +12,32 @@ extern crate tracing;
 use diesel::pg::PgConnection;
 
 pub mod api;
+pub mod assets;
+pub mod auth;
 pub mod b2;
+pub mod config;
+pub mod domains;
+pub mod egress;
+pub mod events;
+pub mod gc;
 pub mod gitea;
+pub mod health;
+pub mod host;
 pub mod jwt;
+pub mod kv;
+pub mod logs;
+pub mod metrics;
 pub mod models;
+pub mod purge;
+pub mod request_id;
+pub mod retention;
+pub mod routing;
 pub mod schema;
+pub mod secrets;
+pub mod telemetry;
+#[cfg(test)]
+mod testing;
+pub mod traffic;
 
 #[database("main_data")]
 pub struct MainDatabase(PgConnection);
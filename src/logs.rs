@@ -0,0 +1,182 @@
+use crate::models;
+use chrono::prelude::*;
+use lazy_static::lazy_static;
+use std::{
+    env,
+    io::{self, BufRead, BufReader, Read},
+};
+use uuid::Uuid;
+
+lazy_static! {
+    /// How many bytes of each output stream of an execution are kept. The rest
+    /// is read and thrown away so the module doesn't block on a full pipe.
+    pub static ref STREAM_LIMIT: usize = env::var("EXECUTION_LOG_LIMIT")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(64 * 1024);
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub at: NaiveDateTime,
+    pub message: String,
+}
+
+/// Everything that was kept of one output stream.
+#[derive(Debug, Clone)]
+pub struct Captured {
+    pub stream: Stream,
+    pub lines: Vec<Line>,
+    /// Whether output was dropped because of the size limit.
+    pub truncated: bool,
+}
+
+impl Captured {
+    /// The whole stream as one string, like it was written.
+    pub fn text(&self) -> String {
+        let mut text = self
+            .lines
+            .iter()
+            .map(|l| l.message.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        if self.truncated {
+            text.push_str("\n[output truncated]");
+        }
+        text
+    }
+
+    pub fn into_records(self, execution_id: Uuid) -> Vec<models::NewExecutionLog> {
+        let stream = self.stream;
+        self.lines
+            .into_iter()
+            .enumerate()
+            .map(|(i, l)| models::NewExecutionLog {
+                execution_id,
+                stream: stream.as_str().to_string(),
+                line_number: i as i32 + 1,
+                message: l.message,
+                logged_at: l.at,
+            })
+            .collect()
+    }
+}
+
+/// Reads an output stream line by line until it closes, noting when each line
+/// came in. Output that isn't UTF-8 is decoded lossily and NUL bytes, which
+/// Postgres can't store in text, are replaced. Every line that is kept is also
+/// handed to on_line as soon as it is read.
+///
+/// No more than limit bytes are ever buffered, a line that doesn't fit is cut
+/// off. Once the limit is reached the rest of the stream is thrown away.
+pub fn capture(
+    stream: Stream,
+    reader: impl Read,
+    limit: usize,
+    mut on_line: impl FnMut(&Line),
+) -> Captured {
+    let mut reader = BufReader::new(reader);
+    let mut captured = Captured {
+        stream,
+        lines: vec![],
+        truncated: false,
+    };
+    let mut kept = 0;
+    let mut buf = vec![];
+
+    while !captured.truncated {
+        let room = limit - kept;
+        buf.clear();
+        // One byte more than fits, to tell a line that fits exactly apart
+        // from one that doesn't.
+        match (&mut reader)
+            .take(room as u64 + 1)
+            .read_until(b'\n', &mut buf)
+        {
+            Ok(0) => return captured,
+            Ok(_) => {}
+            Err(why) => {
+                error!(stream = stream.as_str(), "can't read output: {}", why);
+                return captured;
+            }
+        }
+        let at = Utc::now().naive_utc();
+
+        if buf.len() > room {
+            buf.truncate(room);
+            captured.truncated = true;
+            if buf.is_empty() {
+                break;
+            }
+        }
+        kept += buf.len();
+
+        let line = Line {
+            at,
+            message: String::from_utf8_lossy(&buf)
+                .trim_end_matches(&['\r', '\n'][..])
+                .replace('\0', "\u{fffd}"),
+        };
+        on_line(&line);
+        captured.lines.push(line);
+    }
+
+    // Keep reading so the module doesn't block on a full pipe.
+    if let Err(why) = io::copy(&mut reader, &mut io::sink()) {
+        error!(stream = stream.as_str(), "can't read output: {}", why);
+    }
+
+    captured
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(captured: &Captured) -> Vec<&str> {
+        captured.lines.iter().map(|l| l.message.as_str()).collect()
+    }
+
+    #[test]
+    fn splits_lines() {
+        let captured = capture(Stream::Stdout, &b"one\r\ntwo\nthree"[..], 1024, |_| {});
+        assert_eq!(messages(&captured), vec!["one", "two", "three"]);
+        assert!(!captured.truncated);
+    }
+
+    #[test]
+    fn cuts_off_long_lines() {
+        let output = vec![b'a'; 4096];
+        let captured = capture(Stream::Stdout, &output[..], 10, |_| {});
+        assert_eq!(messages(&captured), vec!["aaaaaaaaaa"]);
+        assert!(captured.truncated);
+    }
+
+    #[test]
+    fn stops_at_the_limit() {
+        let captured = capture(Stream::Stderr, &b"12345\n12345\n12345\n"[..], 12, |_| {});
+        assert_eq!(messages(&captured), vec!["12345", "12345"]);
+        assert!(captured.truncated);
+    }
+
+    #[test]
+    fn replaces_nul_bytes() {
+        let captured = capture(Stream::Stdout, &b"a\0b\n"[..], 1024, |_| {});
+        assert_eq!(messages(&captured), vec!["a\u{fffd}b"]);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::MainDatabasePool;
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounterVec, IntGauge, TextEncoder,
+};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{ContentType, Status},
+    request::{self, FromRequest},
+    response::content::Content,
+    Data, Outcome, Request, Response, State,
+};
+use std::{env, time::Instant};
+
+lazy_static! {
+    /// The bearer token scrapers have to bring. Without it metrics aren't
+    /// served at all, they name handlers.
+    static ref SCRAPE_TOKEN: Option<String> = env::var("METRICS_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty());
+
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "wasmcloud_http_requests_total",
+        "HTTP requests by route and status.",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    pub static ref HTTP_DURATION: HistogramVec = register_histogram_vec!(
+        "wasmcloud_http_request_duration_seconds",
+        "How long HTTP requests took to answer by route.",
+        &["method", "route"]
+    )
+    .unwrap();
+    pub static ref EXECUTIONS: IntCounterVec = register_int_counter_vec!(
+        "wasmcloud_executions_total",
+        "Executions by handler and outcome (success, failure or error).",
+        &["handler_id", "outcome"]
+    )
+    .unwrap();
+    pub static ref EXECUTION_DURATION: HistogramVec = register_histogram_vec!(
+        "wasmcloud_execution_duration_seconds",
+        "How long modules ran by handler.",
+        &["handler_id"],
+        exponential_buckets(0.005, 2.0, 14).unwrap()
+    )
+    .unwrap();
+    pub static ref DOWNLOADS: IntCounterVec = register_int_counter_vec!(
+        "wasmcloud_downloads_total",
+        "Files the executor fetched from the CDN by kind (module or assets).",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref DOWNLOAD_BYTES: IntCounterVec = register_int_counter_vec!(
+        "wasmcloud_download_bytes_total",
+        "Bytes the executor fetched from the CDN by kind.",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref DOWNLOAD_CACHE: IntCounterVec = register_int_counter_vec!(
+        "wasmcloud_download_cache_total",
+        "Module and asset lookups by kind and whether they were already on disk (hit or miss).",
+        &["kind", "result"]
+    )
+    .unwrap();
+    pub static ref UPLOAD_BYTES: HistogramVec = register_histogram_vec!(
+        "wasmcloud_upload_bytes",
+        "Sizes of files uploaded to storage by extension.",
+        &["extension"],
+        exponential_buckets(1024.0, 4.0, 10).unwrap()
+    )
+    .unwrap();
+    pub static ref DB_CONNECTIONS: IntGauge = register_int_gauge!(
+        "wasmcloud_db_pool_connections",
+        "Connections in the database pool."
+    )
+    .unwrap();
+    pub static ref DB_IDLE_CONNECTIONS: IntGauge = register_int_gauge!(
+        "wasmcloud_db_pool_idle_connections",
+        "Idle connections in the database pool."
+    )
+    .unwrap();
+}
+
+/// When a request came in, kept in its local cache.
+struct Started(Instant);
+
+/// Counts and times every request by the route that answered it, so new
+/// routes are covered without doing anything.
+#[derive(Default)]
+pub struct Metrics;
+
+impl Fairing for Metrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Prometheus metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        request.local_cache(|| Started(Instant::now()));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let started = request.local_cache(|| Started(Instant::now()));
+        // Routes are labelled by their pattern so that IDs in paths don't make
+        // a new series for every request.
+        let route = request
+            .route()
+            .map(|route| route.uri.path().to_string())
+            .unwrap_or_else(|| "unmatched".to_string());
+        let method = request.method().as_str();
+
+        HTTP_REQUESTS
+            .with_label_values(&[method, &route, &response.status().code.to_string()])
+            .inc();
+        HTTP_DURATION
+            .with_label_values(&[method, &route])
+            .observe(started.0.elapsed().as_secs_f64());
+    }
+}
+
+/// A request that brought the scrape token.
+pub struct Scraper;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Scraper {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let expected = match SCRAPE_TOKEN.as_ref() {
+            Some(token) => token,
+            None => return Outcome::Failure((Status::NotFound, ())),
+        };
+        let given = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|auth| auth.strip_prefix("Bearer "))
+            .unwrap_or("");
+
+        // Hashes compare in constant time.
+        if blake3::hash(given.as_bytes()) == blake3::hash(expected.as_bytes()) {
+            Outcome::Success(Scraper)
+        } else {
+            Outcome::Failure((Status::Unauthorized, ()))
+        }
+    }
+}
+
+/// Metrics for Prometheus, for scrapers with `Authorization: Bearer` and the
+/// token in `METRICS_TOKEN`.
+#[get("/metrics")]
+pub fn metrics(_scraper: Scraper, pool: State<MainDatabasePool>) -> Content<Vec<u8>> {
+    let state = pool.0.state();
+    DB_CONNECTIONS.set(state.connections as i64);
+    DB_IDLE_CONNECTIONS.set(state.idle_connections as i64);
+
+    let encoder = TextEncoder::new();
+    let mut buf = vec![];
+    if let Err(why) = encoder.encode(&prometheus::gather(), &mut buf) {
+        error!("can't encode metrics: {}", why);
+    }
+
+    Content(
+        ContentType::parse_flexible(encoder.format_type()).unwrap_or(ContentType::Plain),
+        buf,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::local::Client;
+
+    #[get("/greet/<name>")]
+    fn greet(name: String) -> String {
+        format!("hi {}", name)
+    }
+
+    #[test]
+    fn labels_requests_by_route_pattern() {
+        let rocket = rocket::ignite().attach(Metrics).mount("/", routes![greet]);
+        let client = Client::new(rocket).unwrap();
+
+        let before = HTTP_REQUESTS
+            .with_label_values(&["GET", "/greet/<name>", "200"])
+            .get();
+        client.get("/greet/alice").dispatch();
+        client.get("/greet/bob").dispatch();
+        client.get("/nowhere").dispatch();
+
+        assert_eq!(
+            HTTP_REQUESTS
+                .with_label_values(&["GET", "/greet/<name>", "200"])
+                .get(),
+            before + 2
+        );
+        assert!(
+            HTTP_REQUESTS
+                .with_label_values(&["GET", "unmatched", "404"])
+                .get()
+                >= 1
+        );
+    }
+}
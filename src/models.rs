@@ -1,4 +1,4 @@
-use crate::schema::*;
+use crate::{schema::*, secrets};
 use chrono::NaiveDateTime;
 use serde::Serialize;
 use std::fmt;
@@ -54,6 +54,7 @@ pub struct GiteaToken {
 #[table_name = "tokens"]
 pub struct NewToken {
     pub user_id: Uuid,
+    pub scopes: String,
 }
 
 #[derive(Queryable, Debug, Clone, Serialize)]
@@ -63,6 +64,13 @@ pub struct Token {
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
     pub deleted_at: Option<NaiveDateTime>,
+    pub scopes: String,
+}
+
+impl Token {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.split(',').any(|s| s == scope)
+    }
 }
 
 #[derive(Insertable)]
@@ -72,6 +80,9 @@ pub struct NewHandler {
     pub human_name: String,
     pub current_version: Option<String>,
     pub async_impl: bool,
+    pub visibility: String,
+    pub description: String,
+    pub tags: Vec<String>,
 }
 
 #[derive(Queryable, Debug, Clone, Serialize)]
@@ -84,6 +95,26 @@ pub struct Handler {
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
     pub deleted_at: Option<NaiveDateTime>,
+    pub current_version_id: Option<Uuid>,
+    pub visibility: String,
+    pub description: String,
+    pub tags: Vec<String>,
+    /// How long an execution may run before it is killed, if at all.
+    pub timeout_ms: Option<i32>,
+}
+
+/// Changes to the settings of a handler. Fields that are None are left as
+/// they are.
+#[derive(AsChangeset, Debug, Default)]
+#[table_name = "handlers"]
+pub struct HandlerChanges {
+    pub human_name: Option<String>,
+    pub async_impl: Option<bool>,
+    pub visibility: Option<String>,
+    pub description: Option<String>,
+    pub tags: Option<Vec<String>>,
+    /// Some(None) removes the timeout.
+    pub timeout_ms: Option<Option<i32>>,
 }
 
 #[derive(Insertable)]
@@ -92,6 +123,7 @@ pub struct NewHandlerConfig {
     pub key_name: String,
     pub value_contents: String,
     pub handler_id: Uuid,
+    pub is_secret: bool,
 }
 
 #[derive(Queryable, Debug, Clone, Serialize)]
@@ -101,6 +133,307 @@ pub struct HandlerConfig {
     pub handler_id: Uuid,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub is_secret: bool,
+}
+
+impl HandlerConfig {
+    /// Hides the (encrypted) value of secret entries so they can be returned
+    /// to API clients.
+    pub fn masked(mut self) -> Self {
+        if self.is_secret {
+            self.value_contents = secrets::MASK.to_string();
+        }
+        self
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "handler_config_history"]
+pub struct NewHandlerConfigHistory {
+    pub handler_id: Uuid,
+    pub key_name: String,
+    pub action: String,
+    pub old_value_hash: Option<String>,
+    pub new_value_hash: Option<String>,
+    pub new_value_contents: Option<String>,
+    pub is_secret: bool,
+    pub changed_by: Uuid,
+}
+
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct HandlerConfigHistory {
+    pub id: Uuid,
+    pub handler_id: Uuid,
+    pub key_name: String,
+    pub action: String,
+    pub old_value_hash: Option<String>,
+    pub new_value_hash: Option<String>,
+    #[serde(skip_serializing)]
+    pub new_value_contents: Option<String>,
+    pub is_secret: bool,
+    pub changed_by: Uuid,
+    pub created_at: NaiveDateTime,
+    /// Orders changes made at the same time.
+    #[serde(skip_serializing)]
+    pub seq: i64,
+}
+
+#[derive(Insertable)]
+#[table_name = "handler_versions"]
+pub struct NewHandlerVersion {
+    pub handler_id: Uuid,
+    pub module_url: String,
+    pub created_by: Uuid,
+    pub assets_url: Option<String>,
+}
+
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct HandlerVersion {
+    pub id: Uuid,
+    pub handler_id: Uuid,
+    pub module_url: String,
+    pub created_by: Uuid,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub assets_url: Option<String>,
+}
+
+#[derive(Insertable)]
+#[table_name = "handler_environments"]
+pub struct NewHandlerEnvironment {
+    pub handler_id: Uuid,
+    pub name: String,
+    pub current_version: Option<String>,
+    pub version_id: Option<Uuid>,
+}
+
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct HandlerEnvironment {
+    pub id: Uuid,
+    pub handler_id: Uuid,
+    pub name: String,
+    pub current_version: Option<String>,
+    pub version_id: Option<Uuid>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "environment_config"]
+pub struct NewEnvironmentConfig {
+    pub key_name: String,
+    pub value_contents: String,
+    pub environment_id: Uuid,
+    pub is_secret: bool,
+}
+
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct EnvironmentConfig {
+    pub key_name: String,
+    pub value_contents: String,
+    pub environment_id: Uuid,
+    pub is_secret: bool,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl EnvironmentConfig {
+    /// See [HandlerConfig::masked].
+    pub fn masked(mut self) -> Self {
+        if self.is_secret {
+            self.value_contents = secrets::MASK.to_string();
+        }
+        self
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "handler_traffic"]
+pub struct NewHandlerTraffic {
+    pub handler_id: Uuid,
+    pub version_id: Uuid,
+    pub weight: i32,
+}
+
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct HandlerTraffic {
+    pub handler_id: Uuid,
+    pub version_id: Uuid,
+    pub weight: i32,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "handler_canary_policies"]
+pub struct NewHandlerCanaryPolicy {
+    pub handler_id: Uuid,
+    pub stable_version_id: Uuid,
+    pub failure_threshold: f64,
+    pub min_executions: i32,
+}
+
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct HandlerCanaryPolicy {
+    pub handler_id: Uuid,
+    pub stable_version_id: Uuid,
+    pub failure_threshold: f64,
+    pub min_executions: i32,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "handler_aliases"]
+pub struct NewHandlerAlias {
+    pub name: String,
+    pub handler_id: Uuid,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct HandlerAlias {
+    pub name: String,
+    pub handler_id: Uuid,
+    pub expires_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "handler_allowed_hosts"]
+pub struct NewHandlerAllowedHost {
+    pub handler_id: Uuid,
+    pub host: String,
+}
+
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct HandlerAllowedHost {
+    pub handler_id: Uuid,
+    pub host: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "handler_kv"]
+pub struct NewHandlerKv {
+    pub handler_id: Uuid,
+    pub key_name: String,
+    pub value: Vec<u8>,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct HandlerKv {
+    pub handler_id: Uuid,
+    pub key_name: String,
+    #[serde(skip_serializing)]
+    pub value: Vec<u8>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "invocation_keys"]
+pub struct NewInvocationKey {
+    pub handler_id: Uuid,
+    pub name: String,
+    pub key_hash: String,
+}
+
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct InvocationKey {
+    pub id: Uuid,
+    pub handler_id: Uuid,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub key_hash: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub deleted_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "custom_domains"]
+pub struct NewCustomDomain {
+    pub handler_id: Uuid,
+    pub domain: String,
+    pub verification_token: String,
+}
+
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct CustomDomain {
+    pub id: Uuid,
+    pub handler_id: Uuid,
+    pub domain: String,
+    pub verification_token: String,
+    pub verified_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "config_groups"]
+pub struct NewConfigGroup {
+    pub user_id: Uuid,
+    pub name: String,
+}
+
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct ConfigGroup {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "config_group_entries"]
+pub struct NewConfigGroupEntry {
+    pub key_name: String,
+    pub value_contents: String,
+    pub group_id: Uuid,
+    pub is_secret: bool,
+}
+
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct ConfigGroupEntry {
+    pub key_name: String,
+    pub value_contents: String,
+    pub group_id: Uuid,
+    pub is_secret: bool,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl ConfigGroupEntry {
+    /// See [HandlerConfig::masked].
+    pub fn masked(mut self) -> Self {
+        if self.is_secret {
+            self.value_contents = secrets::MASK.to_string();
+        }
+        self
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "handler_config_groups"]
+pub struct NewHandlerConfigGroup {
+    pub handler_id: Uuid,
+    pub group_id: Uuid,
+    pub priority: i32,
+}
+
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct HandlerConfigGroup {
+    pub handler_id: Uuid,
+    pub group_id: Uuid,
+    pub priority: i32,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
 }
 
 #[derive(Insertable)]
@@ -109,7 +442,11 @@ pub struct NewExecution {
     pub handler_id: Uuid,
     pub finished: bool,
     pub stderr: Option<String>,
-    pub execution_time: i32,
+    pub execution_time: Option<i32>,
+    pub version_id: Option<Uuid>,
+    pub exit_code: Option<i32>,
+    pub stdout: Option<String>,
+    pub trace_id: Option<String>,
 }
 
 #[derive(Queryable, Debug, Clone, Serialize)]
@@ -118,8 +455,60 @@ pub struct Execution {
     pub handler_id: Uuid,
     pub finished: bool,
     pub stderr: Option<String>,
-    pub execution_time: i32,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub execution_time: Option<i32>,
+    pub version_id: Option<Uuid>,
+    pub exit_code: Option<i32>,
+    pub stdout: Option<String>,
+    /// The W3C trace the execution is part of.
+    pub trace_id: Option<String>,
+}
 
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "execution_http_requests"]
+pub struct NewExecutionHttpRequest {
+    pub execution_id: Uuid,
+    pub method: String,
+    pub url: String,
+    pub status: Option<i32>,
+    pub response_bytes: i64,
+    pub duration_ms: i32,
+    pub error: Option<String>,
+}
+
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct ExecutionHttpRequest {
+    pub id: Uuid,
+    pub execution_id: Uuid,
+    pub method: String,
+    pub url: String,
+    pub status: Option<i32>,
+    pub response_bytes: i64,
+    pub duration_ms: i32,
+    pub error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "execution_logs"]
+pub struct NewExecutionLog {
+    pub execution_id: Uuid,
+    pub stream: String,
+    pub line_number: i32,
+    pub message: String,
+    pub logged_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Debug, Clone, Serialize)]
+pub struct ExecutionLog {
+    pub id: Uuid,
+    pub execution_id: Uuid,
+    pub stream: String,
+    pub line_number: i32,
+    pub message: String,
+    pub logged_at: NaiveDateTime,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
 }
@@ -0,0 +1,193 @@
+use crate::schema;
+use chrono::prelude::*;
+use diesel::{pg::PgConnection, prelude::*};
+use lazy_static::lazy_static;
+use std::env;
+use uuid::Uuid;
+
+lazy_static! {
+    /// How long deleted handlers and tokens can be restored. After that they
+    /// are purged for good and handler names can be taken again.
+    pub static ref UNDELETE_WINDOW: chrono::Duration = chrono::Duration::hours(
+        env::var("UNDELETE_WINDOW_HOURS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(7 * 24)
+    );
+}
+
+/// Whether something deleted at the given time can still be restored.
+pub fn restorable(deleted_at: NaiveDateTime) -> bool {
+    deleted_at > Utc::now().naive_utc() - *UNDELETE_WINDOW
+}
+
+/// What a purge removed.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Purged {
+    pub handlers: usize,
+    pub tokens: usize,
+}
+
+/// Hard-deletes the handlers and tokens that were deleted longer ago than the
+/// undelete window.
+#[instrument(skip(conn), err)]
+pub fn purge(conn: &PgConnection) -> QueryResult<Purged> {
+    let cutoff = Utc::now().naive_utc() - *UNDELETE_WINDOW;
+
+    let doomed: Vec<Uuid> = {
+        use schema::handlers::dsl::{deleted_at, handlers, id};
+        handlers
+            .select(id)
+            .filter(deleted_at.lt(cutoff))
+            .load(conn)?
+    };
+    let mut purged = Purged::default();
+    for hdl_id in doomed {
+        if let Err(why) = purge_handler(conn, hdl_id) {
+            error!(
+                handler.id = &hdl_id.to_string()[..],
+                "can't purge handler: {}", why
+            );
+            continue;
+        }
+        purged.handlers += 1;
+    }
+
+    purged.tokens = {
+        use schema::tokens::dsl::{deleted_at, tokens};
+        diesel::delete(tokens.filter(deleted_at.lt(cutoff))).execute(conn)?
+    };
+
+    Ok(purged)
+}
+
+/// Deletes a handler with everything that belongs to it. Tables that don't
+/// cascade are cleared first, in an order that satisfies their foreign keys.
+fn purge_handler(conn: &PgConnection, hdl_id: Uuid) -> QueryResult<()> {
+    conn.transaction(|| {
+        // The handler points at one of its versions, which go first.
+        {
+            use schema::handlers::dsl::{current_version_id, handlers};
+            diesel::update(handlers.find(hdl_id))
+                .set(current_version_id.eq(None::<Uuid>))
+                .execute(conn)?;
+        }
+        {
+            use schema::executions::dsl::{executions, handler_id};
+            diesel::delete(executions.filter(handler_id.eq(hdl_id))).execute(conn)?;
+        }
+        {
+            use schema::handler_traffic::dsl::{handler_id, handler_traffic};
+            diesel::delete(handler_traffic.filter(handler_id.eq(hdl_id))).execute(conn)?;
+        }
+        {
+            use schema::handler_canary_policies::dsl::{handler_canary_policies, handler_id};
+            diesel::delete(handler_canary_policies.filter(handler_id.eq(hdl_id))).execute(conn)?;
+        }
+        {
+            use schema::handler_environments::dsl::{handler_environments, handler_id};
+            diesel::delete(handler_environments.filter(handler_id.eq(hdl_id))).execute(conn)?;
+        }
+        {
+            use schema::handler_config_groups::dsl::{handler_config_groups, handler_id};
+            diesel::delete(handler_config_groups.filter(handler_id.eq(hdl_id))).execute(conn)?;
+        }
+        {
+            use schema::handler_config::dsl::{handler_config, handler_id};
+            diesel::delete(handler_config.filter(handler_id.eq(hdl_id))).execute(conn)?;
+        }
+        {
+            use schema::handler_config_history::dsl::{handler_config_history, handler_id};
+            diesel::delete(handler_config_history.filter(handler_id.eq(hdl_id))).execute(conn)?;
+        }
+        {
+            use schema::handler_aliases::dsl::{handler_aliases, handler_id};
+            diesel::delete(handler_aliases.filter(handler_id.eq(hdl_id))).execute(conn)?;
+        }
+        {
+            use schema::handler_versions::dsl::{handler_id, handler_versions};
+            diesel::delete(handler_versions.filter(handler_id.eq(hdl_id))).execute(conn)?;
+        }
+        {
+            use schema::handlers::dsl::handlers;
+            diesel::delete(handlers.find(hdl_id)).execute(conn)?;
+        }
+
+        info!(handler.id = &hdl_id.to_string()[..], "purged handler");
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models, testing};
+
+    fn delete(conn: &PgConnection, hdl: &models::Handler, ago: chrono::Duration) {
+        use schema::handlers::dsl::{deleted_at, handlers};
+        diesel::update(handlers.find(hdl.id))
+            .set(deleted_at.eq(Some(Utc::now().naive_utc() - ago)))
+            .execute(conn)
+            .unwrap();
+    }
+
+    fn exists(conn: &PgConnection, hdl: &models::Handler) -> bool {
+        use schema::handlers::dsl::handlers;
+        handlers
+            .find(hdl.id)
+            .get_result::<models::Handler>(conn)
+            .optional()
+            .unwrap()
+            .is_some()
+    }
+
+    #[test]
+    fn restores_within_the_window() {
+        let now = Utc::now().naive_utc();
+        assert!(restorable(now));
+        assert!(restorable(
+            now - *UNDELETE_WINDOW + chrono::Duration::minutes(1)
+        ));
+        assert!(!restorable(
+            now - *UNDELETE_WINDOW - chrono::Duration::minutes(1)
+        ));
+    }
+
+    #[test]
+    #[ignore]
+    fn purges_handlers_with_everything_they_point_at() {
+        let conn = testing::conn();
+        let user = testing::user(&conn);
+
+        let old = testing::handler(&conn, &user);
+        let version = testing::version(&conn, &old, "https://cdn.test/old");
+        {
+            use schema::handlers::dsl::{current_version_id, handlers};
+            diesel::update(handlers.find(old.id))
+                .set(current_version_id.eq(Some(version.id)))
+                .execute(&conn)
+                .unwrap();
+        }
+        diesel::insert_into(schema::handler_environments::table)
+            .values(&models::NewHandlerEnvironment {
+                handler_id: old.id,
+                name: "staging".into(),
+                current_version: Some(version.module_url.clone()),
+                version_id: Some(version.id),
+            })
+            .execute(&conn)
+            .unwrap();
+        testing::execution(&conn, &old, Some(version.id), Some(0));
+        delete(&conn, &old, *UNDELETE_WINDOW + chrono::Duration::hours(1));
+
+        let recent = testing::handler(&conn, &user);
+        delete(&conn, &recent, chrono::Duration::hours(1));
+        let live = testing::handler(&conn, &user);
+
+        let purged = purge(&conn).unwrap();
+        assert!(purged.handlers >= 1);
+        assert!(!exists(&conn, &old));
+        assert!(exists(&conn, &recent));
+        assert!(exists(&conn, &live));
+    }
+}
@@ -0,0 +1,62 @@
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+    Data, Request, Response,
+};
+
+/// The header requests are identified by. A client (or a proxy in front of
+/// us) can pick the ID, otherwise one is made up.
+pub static REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// The longest request ID that is taken from a client.
+const MAX_LEN: usize = 128;
+
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// The ID of a request, made up on first use.
+    pub fn of(request: &Request) -> String {
+        request
+            .local_cache(|| {
+                let id = request
+                    .headers()
+                    .get_one(REQUEST_ID_HEADER)
+                    .filter(|id| valid(id))
+                    .map(str::to_string)
+                    .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+                RequestId(id)
+            })
+            .0
+            .clone()
+    }
+}
+
+fn valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+}
+
+/// Gives every request an ID and sends it back with the response.
+#[derive(Default)]
+pub struct RequestIds;
+
+impl Fairing for RequestIds {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request IDs",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        RequestId::of(request);
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        response.set_header(Header::new(REQUEST_ID_HEADER, RequestId::of(request)));
+    }
+}
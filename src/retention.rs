@@ -0,0 +1,582 @@
+use crate::{purge, schema};
+use chrono::prelude::*;
+use color_eyre::eyre::Result;
+use diesel::{pg::PgConnection, prelude::*};
+use filetime::FileTime;
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    thread,
+    time::{Duration, SystemTime},
+};
+use uuid::Uuid;
+
+lazy_static! {
+    /// How often old executions and downloads are cleaned up.
+    pub static ref INTERVAL: Duration = Duration::from_secs(
+        env::var("RETENTION_INTERVAL_SECS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(60 * 60)
+    );
+
+    /// How long a downloaded module or asset bundle is kept after it was last
+    /// used.
+    pub static ref DOWNLOAD_MAX_AGE: Duration = Duration::from_secs(
+        env::var("RETENTION_DOWNLOAD_MAX_AGE_SECS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(24 * 60 * 60)
+    );
+
+    /// How old a scratch directory has to be before it is taken to be left
+    /// over from an executor that died. This has to be longer than any
+    /// execution runs.
+    pub static ref SCRATCH_MAX_AGE: Duration = Duration::from_secs(
+        env::var("RETENTION_SCRATCH_MAX_AGE_SECS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(24 * 60 * 60)
+    );
+
+    /// The downloads and unpacked bundles that running executions use, with
+    /// how many of them use each.
+    static ref IN_USE: Mutex<HashMap<PathBuf, usize>> = Mutex::default();
+}
+
+/// Keeps a download or an unpacked bundle from being cleaned up while an
+/// execution uses it.
+#[derive(Debug)]
+pub struct InUse(PathBuf);
+
+impl InUse {
+    /// Marks path as used until the InUse is dropped. This has to happen
+    /// before checking whether path exists, so that it can't be cleaned up in
+    /// between. If it does exist it is touched, downloads are kept by when
+    /// they were last used.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        *in_use().entry(path.clone()).or_insert(0) += 1;
+        if path.exists() {
+            if let Err(why) = filetime::set_file_mtime(&path, FileTime::now()) {
+                warn!("can't touch {}: {}", path.display(), why);
+            }
+        }
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for InUse {
+    fn drop(&mut self) {
+        let mut in_use = in_use();
+        if let Some(count) = in_use.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                in_use.remove(&self.0);
+            }
+        }
+    }
+}
+
+fn in_use() -> MutexGuard<'static, HashMap<PathBuf, usize>> {
+    // The map stays consistent even if a thread panicked while holding it.
+    IN_USE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The key of the advisory lock that keeps executors from pruning and purging
+/// at the same time.
+const LOCK_KEY: i64 = 0x7265_7465_6e74;
+
+/// How many executions of a handler are kept, and for how long.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Policy {
+    pub max_age_days: i64,
+    pub max_count: i64,
+}
+
+/// The retention policy for the handlers of a user, by their tier. Both limits
+/// can be overridden per tier with `RETENTION_DAYS_TIER_<n>` and
+/// `RETENTION_COUNT_TIER_<n>`.
+pub fn policy(tier: i32) -> Policy {
+    let default = match tier {
+        t if t <= 0 => Policy {
+            max_age_days: 7,
+            max_count: 1_000,
+        },
+        1 => Policy {
+            max_age_days: 30,
+            max_count: 10_000,
+        },
+        _ => Policy {
+            max_age_days: 90,
+            max_count: 100_000,
+        },
+    };
+    let tier = tier.max(0);
+
+    Policy {
+        max_age_days: override_for("RETENTION_DAYS_TIER", tier).unwrap_or(default.max_age_days),
+        max_count: override_for("RETENTION_COUNT_TIER", tier).unwrap_or(default.max_count),
+    }
+}
+
+fn override_for(prefix: &str, tier: i32) -> Option<i64> {
+    env::var(format!("{}_{}", prefix, tier))
+        .ok()
+        .and_then(|n| n.parse().ok())
+}
+
+/// What a cleanup removed.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Report {
+    pub executions: usize,
+    pub log_lines: usize,
+    pub files: usize,
+    pub bytes: u64,
+    pub handlers: usize,
+    pub tokens: usize,
+    pub scratch_dirs: usize,
+    pub orphans: usize,
+}
+
+/// Deletes the finished executions of every handler that are older than its
+/// policy allows or beyond the newest ones it may keep, along with their log
+/// lines and outbound requests. Executions that never finished because their
+/// executor went away are marked as failed first, so they are pruned like the
+/// rest.
+#[instrument(skip(conn), err)]
+pub fn prune_executions(conn: &PgConnection) -> QueryResult<Report> {
+    let orphan_cutoff = Utc::now().naive_utc()
+        - chrono::Duration::from_std(*SCRATCH_MAX_AGE)
+            .unwrap_or_else(|_| chrono::Duration::days(1));
+    let orphans = fail_orphans(conn, orphan_cutoff)?;
+
+    let tiers: HashMap<Uuid, i32> = {
+        use schema::users::dsl::{id, tier, users};
+        users.select((id, tier)).load(conn)?.into_iter().collect()
+    };
+    let owners: Vec<(Uuid, Uuid)> = {
+        use schema::handlers::dsl::{handlers, id, user_id};
+        handlers.select((id, user_id)).load(conn)?
+    };
+
+    let mut report = Report {
+        orphans,
+        ..Report::default()
+    };
+    for (hdl_id, owner) in owners {
+        let policy = policy(tiers.get(&owner).copied().unwrap_or(0));
+        let cutoff = Utc::now().naive_utc() - chrono::Duration::days(policy.max_age_days);
+        let (executions, log_lines) = prune_handler(conn, hdl_id, cutoff, policy.max_count)?;
+        report.executions += executions;
+        report.log_lines += log_lines;
+    }
+
+    Ok(report)
+}
+
+/// Marks the executions that started before cutoff and never finished as
+/// finished without an exit code, which is how failed runs are recorded.
+fn fail_orphans(conn: &PgConnection, cutoff: NaiveDateTime) -> QueryResult<usize> {
+    use schema::executions::dsl::{created_at, executions, finished};
+
+    diesel::update(
+        executions
+            .filter(finished.eq(false))
+            .filter(created_at.lt(cutoff)),
+    )
+    .set(finished.eq(true))
+    .execute(conn)
+}
+
+fn prune_handler(
+    conn: &PgConnection,
+    hdl_id: Uuid,
+    cutoff: NaiveDateTime,
+    max_count: i64,
+) -> QueryResult<(usize, usize)> {
+    use schema::executions::dsl::{created_at, executions, finished, handler_id, id};
+
+    let mut doomed: Vec<Uuid> = executions
+        .select(id)
+        .filter(handler_id.eq(hdl_id))
+        .filter(finished.eq(true))
+        .filter(created_at.lt(cutoff))
+        .load(conn)?;
+    doomed.extend(
+        executions
+            .select(id)
+            .filter(handler_id.eq(hdl_id))
+            .filter(finished.eq(true))
+            .filter(created_at.ge(cutoff))
+            .order(created_at.desc())
+            .offset(max_count)
+            .load::<Uuid>(conn)?,
+    );
+    if doomed.is_empty() {
+        return Ok((0, 0));
+    }
+
+    conn.transaction(|| {
+        let mut counts = (0, 0);
+        // Postgres takes at most 65535 bind parameters per statement.
+        for chunk in doomed.chunks(1000) {
+            use schema::execution_logs::dsl::{execution_id, execution_logs};
+            counts.1 +=
+                diesel::delete(execution_logs.filter(execution_id.eq_any(chunk))).execute(conn)?;
+            counts.0 += diesel::delete(executions.filter(id.eq_any(chunk))).execute(conn)?;
+        }
+        Ok(counts)
+    })
+}
+
+/// Deletes downloaded modules and asset archives that haven't been used in a
+/// while. Unpacked asset bundles go once their archive is gone. Scratch
+/// directories and policy files belong to running executions and are left
+/// alone, as is anything a running execution uses.
+#[instrument(err)]
+pub fn clean_downloads(dir: &Path, max_age: Duration) -> io::Result<Report> {
+    let mut report = Report::default();
+    let now = SystemTime::now();
+    // Executions can't start using anything while it is being removed.
+    let in_use = in_use();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        let path = entry.path();
+        if !meta.is_file()
+            || path.extension().map_or(false, |ext| ext == "policy")
+            || in_use.contains_key(&path)
+        {
+            continue;
+        }
+        let age = now.duration_since(meta.modified()?).unwrap_or_default();
+        if age > max_age {
+            fs::remove_file(&path)?;
+            report.files += 1;
+            report.bytes += meta.len();
+        }
+    }
+
+    let assets = dir.join("assets");
+    if assets.exists() {
+        for entry in fs::read_dir(&assets)? {
+            let path = entry?.path();
+            let name = match path.file_name() {
+                Some(name) => name.to_string_lossy().to_string(),
+                None => continue,
+            };
+            let archive = dir.join(format!("{}.tar", name));
+            let age = now
+                .duration_since(fs::symlink_metadata(&path)?.modified()?)
+                .unwrap_or_default();
+            if archive.exists() || age <= max_age || in_use.contains_key(&path) {
+                continue;
+            }
+
+            report.bytes += remove_bundle(&path)?;
+            report.files += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Deletes scratch directories older than max_age along with their policy
+/// files. Executions clean up after themselves, these are left over from ones
+/// whose executor went away.
+#[instrument(err)]
+pub fn clean_scratch(dir: &Path, max_age: Duration) -> io::Result<Report> {
+    let mut report = Report::default();
+    let now = SystemTime::now();
+
+    let scratch = dir.join("scratch");
+    if !scratch.exists() {
+        return Ok(report);
+    }
+
+    for entry in fs::read_dir(&scratch)? {
+        let path = entry?.path();
+        let age = now
+            .duration_since(fs::symlink_metadata(&path)?.modified()?)
+            .unwrap_or_default();
+        if age <= max_age {
+            continue;
+        }
+
+        if let Some(token) = path.file_name() {
+            let policy = dir.join(format!("{}.policy", token.to_string_lossy()));
+            if policy.exists() {
+                fs::remove_file(policy)?;
+            }
+        }
+        report.bytes += remove_bundle(&path)?;
+        report.scratch_dirs += 1;
+    }
+
+    Ok(report)
+}
+
+/// Removes an unpacked asset bundle, which was made read-only, or a scratch
+/// directory and returns how many bytes it held.
+fn remove_bundle(path: &Path) -> io::Result<u64> {
+    let meta = fs::symlink_metadata(path)?;
+    if !meta.is_dir() {
+        fs::remove_file(path)?;
+        return Ok(meta.len());
+    }
+
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    let mut bytes = 0;
+    for entry in fs::read_dir(path)? {
+        bytes += remove_bundle(&entry?.path())?;
+    }
+    fs::remove_dir(path)?;
+    Ok(bytes)
+}
+
+/// Takes the lock that makes sure only one executor prunes and purges at a
+/// time. It is held until conn is closed.
+fn try_lock(conn: &PgConnection) -> QueryResult<bool> {
+    use diesel::{dsl::sql, sql_types::Bool};
+
+    diesel::select(sql::<Bool>(&format!("pg_try_advisory_lock({})", LOCK_KEY))).get_result(conn)
+}
+
+fn run_once(database_url: &str, download_dir: &Path) -> Result<Report> {
+    let conn = PgConnection::establish(database_url)?;
+    let mut report = if try_lock(&conn)? {
+        let pruned = prune_executions(&conn)?;
+        let purged = purge::purge(&conn)?;
+        Report {
+            handlers: purged.handlers,
+            tokens: purged.tokens,
+            ..pruned
+        }
+    } else {
+        debug!("another executor is pruning executions");
+        Report::default()
+    };
+    drop(conn);
+
+    // Downloads and scratch directories are local to each executor.
+    if download_dir.exists() {
+        let cleaned = clean_downloads(download_dir, *DOWNLOAD_MAX_AGE)?;
+        let scratch = clean_scratch(download_dir, *SCRATCH_MAX_AGE)?;
+        report.files = cleaned.files;
+        report.bytes = cleaned.bytes + scratch.bytes;
+        report.scratch_dirs = scratch.scratch_dirs;
+    }
+
+    Ok(report)
+}
+
+/// Enforces the retention policy in the background every INTERVAL. Deleted
+/// handlers and tokens are purged along the way. With several executors only
+/// one of them prunes and purges each time, but all clean up their own files.
+pub fn spawn(database_url: String, download_dir: PathBuf) {
+    thread::spawn(move || loop {
+        match run_once(&database_url, &download_dir) {
+            Ok(report) => info!(
+                executions = report.executions as u64,
+                log_lines = report.log_lines as u64,
+                files = report.files as u64,
+                bytes = report.bytes,
+                handlers = report.handlers as u64,
+                tokens = report.tokens as u64,
+                scratch_dirs = report.scratch_dirs as u64,
+                orphans = report.orphans as u64,
+                "retention cleanup finished"
+            ),
+            Err(why) => error!("retention cleanup failed: {}", why),
+        }
+        thread::sleep(*INTERVAL);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn scratch_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("retention-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn age(path: &Path, secs: i64) {
+        let then = FileTime::from_unix_time(FileTime::now().unix_seconds() - secs, 0);
+        filetime::set_file_mtime(path, then).unwrap();
+    }
+
+    fn file(path: &Path, secs: i64) {
+        fs::write(path, b"hunter2").unwrap();
+        age(path, secs);
+    }
+
+    fn bundle(path: &Path, secs: i64) {
+        fs::create_dir_all(path).unwrap();
+        fs::write(path.join("index.html"), b"<p>hi</p>").unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o555)).unwrap();
+        age(path, secs);
+    }
+
+    #[test]
+    fn policy_by_tier() {
+        assert_eq!(policy(-1), policy(0));
+        assert_eq!(
+            policy(1),
+            Policy {
+                max_age_days: 30,
+                max_count: 10_000
+            }
+        );
+        assert_eq!(policy(3).max_age_days, 90);
+    }
+
+    #[test]
+    fn policy_overrides() {
+        env::set_var("RETENTION_DAYS_TIER_7", "3");
+        env::set_var("RETENTION_COUNT_TIER_7", "lots");
+
+        assert_eq!(
+            policy(7),
+            Policy {
+                max_age_days: 3,
+                max_count: 100_000
+            }
+        );
+        assert_eq!(policy(6).max_age_days, 90);
+    }
+
+    #[test]
+    fn marking_in_use_touches_and_lets_go() {
+        let dir = scratch_dir();
+        let path = dir.join("module.wasm");
+        file(&path, 60 * 60);
+
+        let held = InUse::new(&path);
+        let modified = FileTime::from_last_modification_time(&fs::metadata(&path).unwrap());
+        assert!(FileTime::now().unix_seconds() - modified.unix_seconds() < 60);
+        let again = InUse::new(&path);
+        drop(held);
+        assert!(in_use().contains_key(&path));
+        drop(again);
+        assert!(!in_use().contains_key(&path));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cleans_old_downloads() {
+        let dir = scratch_dir();
+        let old = 2 * 60 * 60;
+        file(&dir.join("old.wasm"), old);
+        file(&dir.join("new.wasm"), 0);
+        file(&dir.join("busy.wasm"), old);
+        file(&dir.join("token.policy"), old);
+        file(&dir.join("kept.tar"), old);
+        bundle(&dir.join("assets/kept"), old);
+        bundle(&dir.join("assets/stale"), old);
+        bundle(&dir.join("assets/fresh"), 0);
+        bundle(&dir.join("assets/running"), old);
+        let busy = InUse::new(dir.join("busy.wasm"));
+        let running = InUse::new(dir.join("assets/running"));
+        age(busy.path(), old);
+        age(running.path(), old);
+
+        let report = clean_downloads(&dir, Duration::from_secs(60 * 60)).unwrap();
+        assert_eq!(report.files, 2);
+        assert!(!dir.join("old.wasm").exists());
+        assert!(!dir.join("assets/stale").exists());
+        for kept in &[
+            "new.wasm",
+            "busy.wasm",
+            "token.policy",
+            "kept.tar",
+            "assets/kept",
+            "assets/fresh",
+            "assets/running",
+        ] {
+            assert!(dir.join(kept).exists(), "{} was removed", kept);
+        }
+
+        drop((busy, running));
+        remove_bundle(&dir).unwrap();
+    }
+
+    #[test]
+    fn cleans_left_over_scratch() {
+        let dir = scratch_dir();
+        for (token, secs) in &[("gone", 2 * 60 * 60), ("running", 0)] {
+            fs::create_dir_all(dir.join("scratch").join(token)).unwrap();
+            file(&dir.join("scratch").join(token).join("out.txt"), 0);
+            file(&dir.join(format!("{}.policy", token)), 0);
+            age(&dir.join("scratch").join(token), *secs);
+        }
+
+        let report = clean_scratch(&dir, Duration::from_secs(60 * 60)).unwrap();
+        assert_eq!(report.scratch_dirs, 1);
+        assert!(!dir.join("scratch/gone").exists());
+        assert!(!dir.join("gone.policy").exists());
+        assert!(dir.join("scratch/running").exists());
+        assert!(dir.join("running.policy").exists());
+
+        remove_bundle(&dir).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn prunes_old_and_surplus_executions() {
+        use schema::executions::dsl::{created_at, executions, finished, id};
+
+        let conn = testing::conn();
+        let user = testing::user(&conn);
+        let hdl = testing::handler(&conn, &user);
+        let now = Utc::now().naive_utc();
+        let execution = |hours: i64, done: bool| {
+            let e = testing::execution(&conn, &hdl, None, Some(0));
+            diesel::update(executions.find(e.id))
+                .set((
+                    created_at.eq(now - chrono::Duration::hours(hours)),
+                    finished.eq(done),
+                ))
+                .execute(&conn)
+                .unwrap();
+            e.id
+        };
+        let expired = execution(48, true);
+        let orphan = execution(30, false);
+        let running = execution(1, false);
+        let surplus = execution(3, true);
+        let newest = execution(2, true);
+
+        let cutoff = now - chrono::Duration::hours(24);
+        assert_eq!(fail_orphans(&conn, cutoff).unwrap(), 1);
+        let (pruned, _) = prune_handler(&conn, hdl.id, cutoff, 1).unwrap();
+        assert_eq!(pruned, 3);
+
+        let left: Vec<Uuid> = executions
+            .select(id)
+            .filter(schema::executions::handler_id.eq(hdl.id))
+            .order(created_at.desc())
+            .load(&conn)
+            .unwrap();
+        assert_eq!(left, vec![running, newest]);
+        for gone in &[expired, orphan, surplus] {
+            assert!(!left.contains(gone));
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{
+    api::{Error, Result},
+    models, schema,
+};
+use chrono::prelude::*;
+use diesel::{pg::PgConnection, prelude::*};
+use uuid::Uuid;
+
+/// What a name in an invocation URL points to.
+#[derive(Debug, Clone)]
+pub enum Resolved {
+    Handler(models::Handler),
+    /// The name belonged to this handler before it was renamed.
+    Moved(models::Handler),
+}
+
+/// Handler names and aliases end up in URLs, so they are kept simple.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 63
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// Aliases may also be custom paths made of several valid names.
+pub fn valid_path(path: &str) -> bool {
+    path.split('/').all(valid_name)
+}
+
+fn not_found(what: &str) -> Error {
+    Error::NotFound(what.to_string())
+}
+
+/// Finds a handler that has not been deleted by its ID.
+pub fn by_id(conn: &PgConnection, uuid: Uuid) -> Result<models::Handler> {
+    use schema::handlers::dsl::{deleted_at, handlers};
+
+    handlers
+        .find(uuid)
+        .filter(deleted_at.is_null())
+        .get_result::<models::Handler>(conn)
+        .optional()?
+        .ok_or_else(|| not_found("handler"))
+}
+
+/// Finds a handler that has not been deleted by its name or one of its
+/// aliases. Aliases left behind by renames stop working once they expire.
+pub fn by_name(conn: &PgConnection, name: &str) -> Result<Resolved> {
+    use schema::{handler_aliases, handlers};
+
+    if let Some(hdl) = handlers::table
+        .filter(handlers::human_name.eq(name))
+        .filter(handlers::deleted_at.is_null())
+        .first::<models::Handler>(conn)
+        .optional()?
+    {
+        return Ok(Resolved::Handler(hdl));
+    }
+
+    let alias = handler_aliases::table
+        .find(name)
+        .get_result::<models::HandlerAlias>(conn)
+        .optional()?
+        .ok_or_else(|| not_found("handler"))?;
+
+    let hdl = by_id(conn, alias.handler_id)?;
+    match alias.expires_at {
+        None => Ok(Resolved::Handler(hdl)),
+        Some(at) if at > Utc::now().naive_utc() => Ok(Resolved::Moved(hdl)),
+        Some(_) => Err(not_found("handler")),
+    }
+}
+
+/// Finds an environment of a handler by name.
+pub fn environment(
+    conn: &PgConnection,
+    hdl: &models::Handler,
+    env_name: &str,
+) -> Result<models::HandlerEnvironment> {
+    use schema::handler_environments::dsl::{handler_environments, handler_id, name};
+
+    handler_environments
+        .filter(handler_id.eq(hdl.id))
+        .filter(name.eq(env_name))
+        .first::<models::HandlerEnvironment>(conn)
+        .optional()?
+        .ok_or_else(|| not_found("environment"))
+}
+
+/// Checks whether a handler name or alias is in use. Expired aliases are
+/// cleaned up so their names can be taken again.
+pub fn name_taken(conn: &PgConnection, name: &str) -> QueryResult<bool> {
+    use diesel::dsl::{exists, select};
+    use schema::{handler_aliases, handlers};
+
+    diesel::delete(
+        handler_aliases::table
+            .filter(handler_aliases::name.eq(name))
+            .filter(handler_aliases::expires_at.lt(Utc::now().naive_utc())),
+    )
+    .execute(conn)?;
+
+    let handler = select(exists(
+        handlers::table.filter(handlers::human_name.eq(name)),
+    ))
+    .get_result::<bool>(conn)?;
+    let alias = select(exists(handler_aliases::table.find(name))).get_result::<bool>(conn)?;
+
+    Ok(handler || alias)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn names() {
+        for name in &["hello", "hello-world", "a", "404", &"a".repeat(63)] {
+            assert!(valid_name(name), "{}", name);
+        }
+        for name in &[
+            "",
+            "-hello",
+            "hello-",
+            "Hello",
+            "hello_world",
+            "hello.world",
+            "hello/world",
+            &"a".repeat(64),
+        ] {
+            assert!(!valid_name(name), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn paths() {
+        assert!(valid_path("team/billing/webhook"));
+        assert!(valid_path("webhook"));
+        assert!(!valid_path("team//webhook"));
+        assert!(!valid_path("/webhook"));
+        assert!(!valid_path("team/webhook/"));
+        assert!(!valid_path("team/Webhook"));
+    }
+
+    fn alias(conn: &PgConnection, hdl: &models::Handler, name: &str, expires_in: Option<i64>) {
+        diesel::insert_into(schema::handler_aliases::table)
+            .values(&models::NewHandlerAlias {
+                name: name.to_string(),
+                handler_id: hdl.id,
+                expires_at: expires_in
+                    .map(|hours| Utc::now().naive_utc() + chrono::Duration::hours(hours)),
+            })
+            .execute(conn)
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn resolves_names_and_aliases() {
+        let conn = testing::conn();
+        let user = testing::user(&conn);
+        let hdl = testing::handler(&conn, &user);
+        let moved = format!("{}-old", hdl.human_name);
+        let expired = format!("{}-older", hdl.human_name);
+        let custom = format!("team/{}", hdl.human_name);
+        alias(&conn, &hdl, &moved, Some(1));
+        alias(&conn, &hdl, &expired, Some(-1));
+        alias(&conn, &hdl, &custom, None);
+
+        assert!(matches!(
+            by_name(&conn, &hdl.human_name).unwrap(),
+            Resolved::Handler(h) if h.id == hdl.id
+        ));
+        assert!(matches!(
+            by_name(&conn, &custom).unwrap(),
+            Resolved::Handler(h) if h.id == hdl.id
+        ));
+        assert!(matches!(
+            by_name(&conn, &moved).unwrap(),
+            Resolved::Moved(h) if h.id == hdl.id
+        ));
+        assert!(matches!(by_name(&conn, &expired), Err(Error::NotFound(_))));
+        assert!(matches!(
+            by_name(&conn, "no-such-handler-at-all"),
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[test]
+    #[ignore]
+    fn expired_aliases_free_their_name() {
+        let conn = testing::conn();
+        let user = testing::user(&conn);
+        let hdl = testing::handler(&conn, &user);
+        let moved = format!("{}-old", hdl.human_name);
+        let expired = format!("{}-older", hdl.human_name);
+        alias(&conn, &hdl, &moved, Some(1));
+        alias(&conn, &hdl, &expired, Some(-1));
+
+        assert!(name_taken(&conn, &hdl.human_name).unwrap());
+        assert!(name_taken(&conn, &moved).unwrap());
+        assert!(!name_taken(&conn, &expired).unwrap());
+        assert!(!name_taken(&conn, "no-such-handler-at-all").unwrap());
+    }
+}
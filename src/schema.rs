@@ -1,3 +1,75 @@
+table! {
+    config_group_entries (key_name, group_id) {
+        key_name -> Varchar,
+        value_contents -> Varchar,
+        group_id -> Uuid,
+        is_secret -> Bool,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    config_groups (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        name -> Varchar,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    custom_domains (id) {
+        id -> Uuid,
+        handler_id -> Uuid,
+        domain -> Varchar,
+        verification_token -> Varchar,
+        verified_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    environment_config (key_name, environment_id) {
+        key_name -> Varchar,
+        value_contents -> Varchar,
+        environment_id -> Uuid,
+        is_secret -> Bool,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    execution_http_requests (id) {
+        id -> Uuid,
+        execution_id -> Uuid,
+        method -> Varchar,
+        url -> Varchar,
+        status -> Nullable<Int4>,
+        response_bytes -> Int8,
+        duration_ms -> Int4,
+        error -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    execution_logs (id) {
+        id -> Uuid,
+        execution_id -> Uuid,
+        stream -> Varchar,
+        line_number -> Int4,
+        message -> Varchar,
+        logged_at -> Timestamp,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
 table! {
     executions (id) {
         id -> Uuid,
@@ -7,6 +79,10 @@ table! {
         created_at -> Timestamp,
         updated_at -> Timestamp,
         execution_time -> Nullable<Int4>,
+        version_id -> Nullable<Uuid>,
+        exit_code -> Nullable<Int4>,
+        stdout -> Nullable<Varchar>,
+        trace_id -> Nullable<Varchar>,
     }
 }
 
@@ -21,6 +97,36 @@ table! {
     }
 }
 
+table! {
+    handler_aliases (name) {
+        name -> Varchar,
+        handler_id -> Uuid,
+        expires_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    handler_allowed_hosts (handler_id, host) {
+        handler_id -> Uuid,
+        host -> Varchar,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    handler_canary_policies (handler_id) {
+        handler_id -> Uuid,
+        stable_version_id -> Uuid,
+        failure_threshold -> Float8,
+        min_executions -> Int4,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
 table! {
     handler_config (key_name, handler_id) {
         key_name -> Varchar,
@@ -28,6 +134,78 @@ table! {
         handler_id -> Uuid,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        is_secret -> Bool,
+    }
+}
+
+table! {
+    handler_config_history (id) {
+        id -> Uuid,
+        handler_id -> Uuid,
+        key_name -> Varchar,
+        action -> Varchar,
+        old_value_hash -> Nullable<Varchar>,
+        new_value_hash -> Nullable<Varchar>,
+        new_value_contents -> Nullable<Varchar>,
+        is_secret -> Bool,
+        changed_by -> Uuid,
+        created_at -> Timestamp,
+        seq -> Int8,
+    }
+}
+
+table! {
+    handler_config_groups (handler_id, group_id) {
+        handler_id -> Uuid,
+        group_id -> Uuid,
+        priority -> Int4,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    handler_environments (id) {
+        id -> Uuid,
+        handler_id -> Uuid,
+        name -> Varchar,
+        current_version -> Nullable<Varchar>,
+        version_id -> Nullable<Uuid>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    handler_kv (handler_id, key_name) {
+        handler_id -> Uuid,
+        key_name -> Varchar,
+        value -> Bytea,
+        expires_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    handler_traffic (handler_id, version_id) {
+        handler_id -> Uuid,
+        version_id -> Uuid,
+        weight -> Int4,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    handler_versions (id) {
+        id -> Uuid,
+        handler_id -> Uuid,
+        module_url -> Varchar,
+        created_by -> Uuid,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        assets_url -> Nullable<Varchar>,
     }
 }
 
@@ -41,6 +219,23 @@ table! {
         created_at -> Timestamp,
         updated_at -> Timestamp,
         deleted_at -> Nullable<Timestamp>,
+        current_version_id -> Nullable<Uuid>,
+        visibility -> Varchar,
+        description -> Varchar,
+        tags -> Array<Varchar>,
+        timeout_ms -> Nullable<Int4>,
+    }
+}
+
+table! {
+    invocation_keys (id) {
+        id -> Uuid,
+        handler_id -> Uuid,
+        name -> Varchar,
+        key_hash -> Varchar,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        deleted_at -> Nullable<Timestamp>,
     }
 }
 
@@ -51,6 +246,7 @@ table! {
         created_at -> Timestamp,
         updated_at -> Timestamp,
         deleted_at -> Nullable<Timestamp>,
+        scopes -> Varchar,
     }
 }
 
@@ -68,10 +264,26 @@ table! {
 }
 
 allow_tables_to_appear_in_same_query!(
+    config_group_entries,
+    config_groups,
+    custom_domains,
+    environment_config,
+    execution_http_requests,
+    execution_logs,
     executions,
     gitea_tokens,
+    handler_aliases,
+    handler_allowed_hosts,
+    handler_canary_policies,
     handler_config,
+    handler_config_groups,
+    handler_config_history,
+    handler_environments,
+    handler_kv,
+    handler_traffic,
+    handler_versions,
     handlers,
+    invocation_keys,
     tokens,
     users,
 );
@@ -0,0 +1,239 @@
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use color_eyre::eyre::{eyre, Result};
+use lazy_static::lazy_static;
+use rand::{rngs::OsRng, RngCore};
+use std::env;
+use uuid::Uuid;
+
+lazy_static! {
+    pub static ref ENVELOPE_KEY: Vec<u8> = {
+        let key = hex::decode(
+            env::var("CONFIG_ENVELOPE_KEY").expect("CONFIG_ENVELOPE_KEY to be populated"),
+        )
+        .expect("CONFIG_ENVELOPE_KEY to be hex-encoded");
+        assert_eq!(key.len(), KEY_LEN, "CONFIG_ENVELOPE_KEY must be 32 bytes");
+        key
+    };
+}
+
+/// What secret config values look like when they are handed back to users.
+pub const MASK: &str = "********";
+
+/// Values sealed before they were bound to where they are stored.
+const V1_PREFIX: &str = "v1:";
+const VERSION_PREFIX: &str = "v2:";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+const WRAPPED_KEY_LEN: usize = KEY_LEN + 16;
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; len];
+    OsRng.fill_bytes(&mut buf);
+    buf
+}
+
+fn cipher(key: &[u8]) -> XChaCha20Poly1305 {
+    XChaCha20Poly1305::new(Key::from_slice(key))
+}
+
+/// What a secret config value belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    Handler(Uuid),
+    Group(Uuid),
+    Environment(Uuid),
+}
+
+/// The associated data a value is sealed with. It binds the ciphertext to its
+/// owner and key, so a sealed value that is copied somewhere else doesn't open.
+fn associated_data(owner: Owner, key: &str) -> Vec<u8> {
+    let (kind, id) = match owner {
+        Owner::Handler(id) => ("handler", id),
+        Owner::Group(id) => ("group", id),
+        Owner::Environment(id) => ("environment", id),
+    };
+    format!("wasmcloud config v2\0{}\0{}\0{}", kind, id, key).into_bytes()
+}
+
+/// Encrypts a config value for storage at rest. Every value gets its own data
+/// key, which is in turn encrypted with the envelope key from the server
+/// config. The value is bound to the owner and key it is stored under. The
+/// result is laid out as:
+///
+/// `v2:` + hex(key nonce || wrapped data key || value nonce || ciphertext)
+#[instrument(skip(plaintext), err)]
+pub fn seal(owner: Owner, key: &str, plaintext: &str) -> Result<String> {
+    let data_key = random_bytes(KEY_LEN);
+    let key_nonce = random_bytes(NONCE_LEN);
+    let value_nonce = random_bytes(NONCE_LEN);
+
+    let wrapped_key = cipher(&ENVELOPE_KEY)
+        .encrypt(XNonce::from_slice(&key_nonce), data_key.as_ref())
+        .map_err(|why| eyre!("can't wrap data key: {}", why))?;
+    let ciphertext = cipher(&data_key)
+        .encrypt(
+            XNonce::from_slice(&value_nonce),
+            Payload {
+                msg: plaintext.as_bytes(),
+                aad: &associated_data(owner, key),
+            },
+        )
+        .map_err(|why| eyre!("can't encrypt value: {}", why))?;
+
+    let mut out = Vec::with_capacity(NONCE_LEN + WRAPPED_KEY_LEN + NONCE_LEN + ciphertext.len());
+    out.extend_from_slice(&key_nonce);
+    out.extend_from_slice(&wrapped_key);
+    out.extend_from_slice(&value_nonce);
+    out.extend_from_slice(&ciphertext);
+
+    Ok(format!("{}{}", VERSION_PREFIX, hex::encode(out)))
+}
+
+/// Decrypts a value made with [seal] for the same owner and key. Values sealed
+/// in the older `v1:` format aren't bound to anything and open anywhere.
+#[instrument(skip(sealed), err)]
+pub fn open(owner: Owner, key: &str, sealed: &str) -> Result<String> {
+    let (data, aad) = if sealed.starts_with(VERSION_PREFIX) {
+        (&sealed[VERSION_PREFIX.len()..], associated_data(owner, key))
+    } else if sealed.starts_with(V1_PREFIX) {
+        (&sealed[V1_PREFIX.len()..], vec![])
+    } else {
+        return Err(eyre!("unknown secret format"));
+    };
+    let data = hex::decode(data)?;
+    if data.len() < NONCE_LEN + WRAPPED_KEY_LEN + NONCE_LEN {
+        return Err(eyre!("secret is too short"));
+    }
+
+    let (key_nonce, rest) = data.split_at(NONCE_LEN);
+    let (wrapped_key, rest) = rest.split_at(WRAPPED_KEY_LEN);
+    let (value_nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+    let data_key = cipher(&ENVELOPE_KEY)
+        .decrypt(XNonce::from_slice(key_nonce), wrapped_key)
+        .map_err(|why| eyre!("can't unwrap data key: {}", why))?;
+    let plaintext = cipher(&data_key)
+        .decrypt(
+            XNonce::from_slice(value_nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &aad,
+            },
+        )
+        .map_err(|why| eyre!("can't decrypt value: {}", why))?;
+
+    Ok(String::from_utf8(plaintext)?)
+}
+
+/// Hashes a config value with a key derived from the envelope key. This lets
+/// config history show whether a value changed without making it possible to
+/// guess secrets from their hashes.
+pub fn fingerprint(plaintext: &str) -> String {
+    let mut key = [0u8; KEY_LEN];
+    blake3::derive_key("wasmcloud config fingerprint v1", &ENVELOPE_KEY, &mut key);
+    blake3::keyed_hash(&key, plaintext.as_bytes())
+        .to_hex()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Once;
+
+    fn init() {
+        static KEY: Once = Once::new();
+        KEY.call_once(|| env::set_var("CONFIG_ENVELOPE_KEY", hex::encode([7u8; KEY_LEN])));
+    }
+
+    fn id(n: u8) -> Uuid {
+        Uuid::from_slice(&[n; 16]).unwrap()
+    }
+
+    fn owner() -> Owner {
+        Owner::Handler(id(1))
+    }
+
+    #[test]
+    fn seal_round_trips() {
+        init();
+        for value in &["", "hunter2", "ünïcode ✓", "line\nbreaks\0and nul"] {
+            let sealed = seal(owner(), "KEY", value).unwrap();
+            assert!(sealed.starts_with(VERSION_PREFIX));
+            assert_eq!(open(owner(), "KEY", &sealed).unwrap(), *value);
+        }
+    }
+
+    #[test]
+    fn seals_differently_every_time() {
+        init();
+        assert_ne!(
+            seal(owner(), "KEY", "hunter2").unwrap(),
+            seal(owner(), "KEY", "hunter2").unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_secrets() {
+        init();
+        let sealed = seal(owner(), "KEY", "hunter2").unwrap();
+        let mut data = hex::decode(&sealed[VERSION_PREFIX.len()..]).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        let tampered = format!("{}{}", VERSION_PREFIX, hex::encode(&data));
+        assert!(open(owner(), "KEY", &tampered).is_err());
+
+        assert!(open(owner(), "KEY", "hunter2").is_err());
+        assert!(open(owner(), "KEY", "v2:abcd").is_err());
+        assert!(open(owner(), "KEY", "v2:not hex").is_err());
+    }
+
+    #[test]
+    fn binds_secrets_to_their_owner_and_key() {
+        init();
+        let sealed = seal(owner(), "KEY", "hunter2").unwrap();
+
+        assert!(open(owner(), "OTHER_KEY", &sealed).is_err());
+        assert!(open(Owner::Handler(id(2)), "KEY", &sealed).is_err());
+        assert!(open(Owner::Group(id(1)), "KEY", &sealed).is_err());
+        assert!(open(Owner::Environment(id(1)), "KEY", &sealed).is_err());
+    }
+
+    #[test]
+    fn opens_unbound_v1_secrets() {
+        init();
+        // The v1 format is the v2 one without associated data.
+        let key_nonce = random_bytes(NONCE_LEN);
+        let value_nonce = random_bytes(NONCE_LEN);
+        let data_key = random_bytes(KEY_LEN);
+        let mut data = key_nonce.clone();
+        data.extend(
+            cipher(&ENVELOPE_KEY)
+                .encrypt(XNonce::from_slice(&key_nonce), data_key.as_ref())
+                .unwrap(),
+        );
+        data.extend_from_slice(&value_nonce);
+        data.extend(
+            cipher(&data_key)
+                .encrypt(XNonce::from_slice(&value_nonce), b"hunter2".as_ref())
+                .unwrap(),
+        );
+        let sealed = format!("{}{}", V1_PREFIX, hex::encode(data));
+
+        assert_eq!(open(owner(), "KEY", &sealed).unwrap(), "hunter2");
+        assert_eq!(
+            open(Owner::Group(Uuid::nil()), "ANY", &sealed).unwrap(),
+            "hunter2"
+        );
+    }
+
+    #[test]
+    fn fingerprints_are_stable() {
+        init();
+        assert_eq!(fingerprint("hunter2"), fingerprint("hunter2"));
+        assert_ne!(fingerprint("hunter2"), fingerprint("hunter3"));
+    }
+}
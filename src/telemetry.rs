@@ -0,0 +1,240 @@
+use color_eyre::eyre::Result;
+use rand::RngCore;
+use std::fmt;
+
+/// The header W3C trace context is propagated in.
+pub static TRACEPARENT_HEADER: &str = "traceparent";
+
+/// Where in a W3C trace something happens.
+/// See https://www.w3.org/TR/trace-context/#traceparent-header.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TraceContext {
+    /// 32 lowercase hex digits.
+    pub trace_id: String,
+    /// 16 lowercase hex digits.
+    pub parent_id: String,
+    pub sampled: bool,
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut buf = vec![0; bytes];
+    rand::thread_rng().fill_bytes(&mut buf);
+    hex::encode(buf)
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len
+        && s.bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// IDs that are all zeroes are invalid.
+fn valid_id(id: &str, len: usize) -> bool {
+    is_hex(id, len) && id.bytes().any(|b| b != b'0')
+}
+
+impl TraceContext {
+    /// Starts a new trace.
+    pub fn new() -> Self {
+        TraceContext {
+            trace_id: random_hex(16),
+            parent_id: random_hex(8),
+            sampled: true,
+        }
+    }
+
+    /// Parses a `traceparent` header. Invalid headers are ignored, as the
+    /// spec asks.
+    pub fn parse(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let parent_id = parts.next()?;
+        let flags = parts.next()?;
+
+        if !is_hex(version, 2) || version == "ff" {
+            return None;
+        }
+        // Later versions may add fields, version 00 can't.
+        if version == "00" && parts.next().is_some() {
+            return None;
+        }
+        if !valid_id(trace_id, 32) || !valid_id(parent_id, 16) || !is_hex(flags, 2) {
+            return None;
+        }
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+
+        Some(TraceContext {
+            trace_id: trace_id.to_string(),
+            parent_id: parent_id.to_string(),
+            sampled: flags & 1 == 1,
+        })
+    }
+
+    /// The context for a new span within the same trace.
+    pub fn child(&self) -> Self {
+        TraceContext {
+            parent_id: random_hex(8),
+            ..self.clone()
+        }
+    }
+}
+
+impl Default for TraceContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "00-{}-{}-{:02x}",
+            self.trace_id, self.parent_id, self.sampled as u8
+        )
+    }
+}
+
+/// Keeps spans being exported until it is dropped.
+pub struct Guard {
+    #[cfg(feature = "otlp")]
+    _uninstall: Option<opentelemetry_otlp::Uninstall>,
+}
+
+/// Sets up logging, and exporting spans over OTLP when built with the `otlp`
+/// feature and `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+pub fn init(service_name: &'static str) -> Result<Guard> {
+    #[cfg(feature = "otlp")]
+    {
+        if let Ok(endpoint) = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            use opentelemetry::{global, sdk::propagation::TraceContextPropagator};
+            use tracing_subscriber::{prelude::*, EnvFilter};
+
+            global::set_text_map_propagator(TraceContextPropagator::new());
+            let (tracer, uninstall) = opentelemetry_otlp::new_pipeline()
+                .with_endpoint(endpoint)
+                .with_service_name(service_name)
+                .install()?;
+
+            tracing_subscriber::registry()
+                .with(EnvFilter::from_default_env())
+                .with(tracing_subscriber::fmt::layer())
+                .with(tracing_opentelemetry::layer().with_tracer(tracer))
+                .init();
+
+            return Ok(Guard {
+                _uninstall: Some(uninstall),
+            });
+        }
+    }
+
+    let _ = service_name;
+    tracing_subscriber::fmt::init();
+    Ok(Guard {
+        #[cfg(feature = "otlp")]
+        _uninstall: None,
+    })
+}
+
+/// The trace context of a span as it is exported, to hand on to whatever the
+/// span calls. This is None without the `otlp` feature, or when spans aren't
+/// exported.
+pub fn current(span: &tracing::Span) -> Option<TraceContext> {
+    #[cfg(feature = "otlp")]
+    {
+        use opentelemetry::trace::TraceContextExt;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let cx = span.context();
+        let sc = cx.span().span_context();
+        if sc.is_valid() {
+            return Some(TraceContext {
+                trace_id: sc.trace_id().to_hex(),
+                parent_id: sc.span_id().to_hex(),
+                sampled: sc.is_sampled(),
+            });
+        }
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    let _ = span;
+    None
+}
+
+/// Makes a span part of a trace that started elsewhere, so that it is
+/// exported under the same trace ID. This does nothing without the `otlp`
+/// feature.
+pub fn adopt(span: &tracing::Span, ctx: &TraceContext) {
+    #[cfg(feature = "otlp")]
+    {
+        use opentelemetry::global;
+        use std::collections::HashMap;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let mut carrier = HashMap::new();
+        carrier.insert(TRACEPARENT_HEADER.to_string(), ctx.to_string());
+        span.set_parent(&global::get_text_map_propagator(|p| p.extract(&carrier)));
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    let _ = (span, ctx);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn parses_traceparent() {
+        let ctx = TraceContext::parse(EXAMPLE).unwrap();
+        assert_eq!(ctx.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(ctx.parent_id, "00f067aa0ba902b7");
+        assert!(ctx.sampled);
+        assert_eq!(ctx.to_string(), EXAMPLE);
+
+        let unsampled =
+            TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00").unwrap();
+        assert!(!unsampled.sampled);
+        assert_eq!(TraceContext::parse(&format!(" {}\n", EXAMPLE)), Some(ctx));
+    }
+
+    #[test]
+    fn accepts_fields_of_later_versions() {
+        let ctx =
+            TraceContext::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra")
+                .unwrap();
+        assert_eq!(ctx.to_string(), EXAMPLE);
+    }
+
+    #[test]
+    fn ignores_invalid_traceparent() {
+        for header in &[
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-1",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902bz-01",
+        ] {
+            assert_eq!(TraceContext::parse(header), None, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn children_share_the_trace() {
+        let root = TraceContext::new();
+        assert_eq!(TraceContext::parse(&root.to_string()), Some(root.clone()));
+
+        let child = root.child();
+        assert_eq!(child.trace_id, root.trace_id);
+        assert_ne!(child.parent_id, root.parent_id);
+        assert!(valid_id(&child.parent_id, 16));
+    }
+}
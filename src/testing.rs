@@ -0,0 +1,86 @@
+// Fixtures for tests that need a database. These tests are `#[ignore]`d by
+// default, run them with `cargo test -- --include-ignored` and
+// `TEST_DATABASE_URL` pointing at a migrated database. Everything a test does
+// happens in a transaction that is never committed.
+
+use crate::{models, schema};
+use diesel::{pg::PgConnection, prelude::*};
+use std::env;
+use uuid::Uuid;
+
+pub fn conn() -> PgConnection {
+    let url = env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL to be populated");
+    let conn = PgConnection::establish(&url).expect("can connect to the test database");
+    conn.begin_test_transaction()
+        .expect("can start a test transaction");
+    conn
+}
+
+fn unique(prefix: &str) -> String {
+    format!("{}-{}", prefix, Uuid::new_v4())
+}
+
+pub fn user(conn: &PgConnection) -> models::User {
+    diesel::insert_into(schema::users::table)
+        .values(&models::NewUser {
+            email: format!("{}@example.com", unique("user")),
+            salutation: "Tester".to_string(),
+            is_admin: false,
+            is_locked: false,
+            tier: 0,
+        })
+        .get_result(conn)
+        .expect("can create a user")
+}
+
+pub fn handler(conn: &PgConnection, user: &models::User) -> models::Handler {
+    diesel::insert_into(schema::handlers::table)
+        .values(&models::NewHandler {
+            user_id: user.id,
+            human_name: unique("handler"),
+            current_version: None,
+            async_impl: false,
+            visibility: "public".to_string(),
+            description: String::new(),
+            tags: vec![],
+        })
+        .get_result(conn)
+        .expect("can create a handler")
+}
+
+pub fn version(
+    conn: &PgConnection,
+    hdl: &models::Handler,
+    module_url: &str,
+) -> models::HandlerVersion {
+    diesel::insert_into(schema::handler_versions::table)
+        .values(&models::NewHandlerVersion {
+            handler_id: hdl.id,
+            module_url: module_url.to_string(),
+            created_by: hdl.user_id,
+            assets_url: None,
+        })
+        .get_result(conn)
+        .expect("can create a handler version")
+}
+
+pub fn execution(
+    conn: &PgConnection,
+    hdl: &models::Handler,
+    version_id: Option<Uuid>,
+    exit_code: Option<i32>,
+) -> models::Execution {
+    diesel::insert_into(schema::executions::table)
+        .values(&models::NewExecution {
+            handler_id: hdl.id,
+            finished: true,
+            stderr: None,
+            execution_time: Some(0),
+            version_id,
+            exit_code,
+            stdout: None,
+            trace_id: None,
+        })
+        .get_result(conn)
+        .expect("can create an execution")
+}
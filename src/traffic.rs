@@ -0,0 +1,281 @@
+use crate::{models, schema};
+use chrono::NaiveDateTime;
+use diesel::{dsl::count_star, pg::PgConnection, prelude::*};
+use rand::Rng;
+use std::convert::TryInto;
+use uuid::Uuid;
+
+/// Picks which version of a handler serves an invocation. Requests with the
+/// same route key always land on the same version as long as the split does
+/// not change, other requests are spread randomly according to the weights.
+pub fn pick(
+    split: &[models::HandlerTraffic],
+    handler_id: Uuid,
+    route_key: Option<&str>,
+) -> Option<Uuid> {
+    let total: u64 = split.iter().map(|t| t.weight.max(0) as u64).sum();
+    if total == 0 {
+        return None;
+    }
+
+    let mut point = match route_key {
+        Some(key) => {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(handler_id.as_bytes());
+            hasher.update(key.as_bytes());
+            let hash = hasher.finalize();
+            u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap()) % total
+        }
+        None => rand::thread_rng().gen_range(0, total),
+    };
+
+    for t in split.iter() {
+        let weight = t.weight.max(0) as u64;
+        if point < weight {
+            return Some(t.version_id);
+        }
+        point -= weight;
+    }
+
+    None
+}
+
+/// Loads the traffic split of a handler, in a stable order so that sticky
+/// routing keeps working between requests.
+pub fn split(conn: &PgConnection, hdl_id: Uuid) -> QueryResult<Vec<models::HandlerTraffic>> {
+    use schema::handler_traffic::dsl::{handler_id, handler_traffic, version_id};
+
+    handler_traffic
+        .filter(handler_id.eq(hdl_id))
+        .order(version_id.asc())
+        .load::<models::HandlerTraffic>(conn)
+}
+
+/// How a version has done since it started getting traffic.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct VersionStats {
+    pub version_id: Uuid,
+    pub executions: i64,
+    pub failures: i64,
+}
+
+impl VersionStats {
+    pub fn failure_rate(&self) -> f64 {
+        if self.executions == 0 {
+            0.0
+        } else {
+            self.failures as f64 / self.executions as f64
+        }
+    }
+}
+
+/// Counts the finished executions and failures of a version since a given
+/// time. An execution failed if the module exited non-zero or was killed.
+pub fn stats(
+    conn: &PgConnection,
+    hdl_id: Uuid,
+    version: Uuid,
+    since: NaiveDateTime,
+) -> QueryResult<VersionStats> {
+    use schema::executions::dsl::{
+        created_at, executions, exit_code, finished, handler_id, version_id,
+    };
+
+    let base = executions
+        .filter(handler_id.eq(hdl_id))
+        .filter(version_id.eq(version))
+        .filter(finished.eq(true))
+        .filter(created_at.ge(since));
+
+    let total = base.clone().select(count_star()).get_result::<i64>(conn)?;
+    let failures = base
+        .filter(exit_code.is_null().or(exit_code.ne(0)))
+        .select(count_star())
+        .get_result::<i64>(conn)?;
+
+    Ok(VersionStats {
+        version_id: version,
+        executions: total,
+        failures,
+    })
+}
+
+/// Sends all traffic back to the stable version when any canary in the split
+/// fails too often. Returns the canary that was rolled back, if any.
+#[instrument(skip(conn), err)]
+pub fn check_canary(conn: &PgConnection, hdl_id: Uuid) -> QueryResult<Option<VersionStats>> {
+    use schema::handler_canary_policies::dsl::handler_canary_policies;
+
+    let policy = match handler_canary_policies
+        .find(hdl_id)
+        .get_result::<models::HandlerCanaryPolicy>(conn)
+        .optional()?
+    {
+        Some(policy) => policy,
+        None => return Ok(None),
+    };
+
+    for t in split(conn, hdl_id)?.into_iter() {
+        if t.version_id == policy.stable_version_id || t.weight == 0 {
+            continue;
+        }
+
+        let canary = stats(conn, hdl_id, t.version_id, t.updated_at)?;
+        if canary.executions < policy.min_executions as i64
+            || canary.failure_rate() <= policy.failure_threshold
+        {
+            continue;
+        }
+
+        warn!(
+            handler.id = &hdl_id.to_string()[..],
+            canary = &t.version_id.to_string()[..],
+            failure_rate = canary.failure_rate(),
+            "canary failing, rolling back to stable version"
+        );
+        rollback(conn, hdl_id, policy.stable_version_id)?;
+        return Ok(Some(canary));
+    }
+
+    Ok(None)
+}
+
+/// Replaces the traffic split of a handler with one that sends everything to
+/// a single version.
+pub fn rollback(conn: &PgConnection, hdl_id: Uuid, stable: Uuid) -> QueryResult<()> {
+    use schema::handler_traffic::dsl::{handler_id, handler_traffic};
+
+    conn.transaction(|| {
+        diesel::delete(handler_traffic.filter(handler_id.eq(hdl_id))).execute(conn)?;
+        diesel::insert_into(handler_traffic)
+            .values(&models::NewHandlerTraffic {
+                handler_id: hdl_id,
+                version_id: stable,
+                weight: 100,
+            })
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn traffic(weights: &[i32]) -> Vec<models::HandlerTraffic> {
+        let now = Utc::now().naive_utc();
+        weights
+            .iter()
+            .map(|&weight| models::HandlerTraffic {
+                handler_id: Uuid::nil(),
+                version_id: Uuid::new_v4(),
+                weight,
+                created_at: now,
+                updated_at: now,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn picks_nothing_without_weights() {
+        assert_eq!(pick(&[], Uuid::nil(), None), None);
+        assert_eq!(pick(&traffic(&[0, 0]), Uuid::nil(), Some("key")), None);
+        assert_eq!(pick(&traffic(&[-5]), Uuid::nil(), None), None);
+    }
+
+    #[test]
+    fn skips_versions_without_weight() {
+        let split = traffic(&[0, 10, -3, 0]);
+        for i in 0..100 {
+            let key = format!("key-{}", i);
+            assert_eq!(
+                pick(&split, Uuid::nil(), Some(&key)),
+                Some(split[1].version_id)
+            );
+            assert_eq!(pick(&split, Uuid::nil(), None), Some(split[1].version_id));
+        }
+    }
+
+    #[test]
+    fn sticks_to_a_version_per_key() {
+        let split = traffic(&[50, 50]);
+        let handler = Uuid::new_v4();
+        for i in 0..100 {
+            let key = format!("key-{}", i);
+            let first = pick(&split, handler, Some(&key));
+            assert!(first.is_some());
+            assert_eq!(pick(&split, handler, Some(&key)), first);
+        }
+    }
+
+    #[test]
+    fn follows_the_weights() {
+        let split = traffic(&[90, 10]);
+        let handler = Uuid::new_v4();
+        let canary = (0..10_000)
+            .filter(|i| {
+                pick(&split, handler, Some(&format!("key-{}", i))) == Some(split[1].version_id)
+            })
+            .count();
+        assert!(canary > 800 && canary < 1200, "{} of 10000", canary);
+    }
+
+    #[test]
+    fn failure_rate_of_nothing_is_zero() {
+        let version = |executions, failures| VersionStats {
+            version_id: Uuid::nil(),
+            executions,
+            failures,
+        };
+        assert_eq!(version(0, 0).failure_rate(), 0.0);
+        assert_eq!(version(4, 1).failure_rate(), 0.25);
+    }
+
+    #[test]
+    #[ignore]
+    fn rolls_back_failing_canaries() {
+        let conn = crate::testing::conn();
+        let user = crate::testing::user(&conn);
+        let hdl = crate::testing::handler(&conn, &user);
+        let stable = crate::testing::version(&conn, &hdl, "https://cdn.example.com/file/a.wasm");
+        let canary = crate::testing::version(&conn, &hdl, "https://cdn.example.com/file/b.wasm");
+
+        for version in &[&stable, &canary] {
+            diesel::insert_into(schema::handler_traffic::table)
+                .values(&models::NewHandlerTraffic {
+                    handler_id: hdl.id,
+                    version_id: version.id,
+                    weight: 50,
+                })
+                .execute(&conn)
+                .unwrap();
+        }
+        diesel::insert_into(schema::handler_canary_policies::table)
+            .values(&models::NewHandlerCanaryPolicy {
+                handler_id: hdl.id,
+                stable_version_id: stable.id,
+                failure_threshold: 0.5,
+                min_executions: 4,
+            })
+            .execute(&conn)
+            .unwrap();
+
+        // Not enough executions to judge the canary yet.
+        for _ in 0..3 {
+            crate::testing::execution(&conn, &hdl, Some(canary.id), Some(1));
+        }
+        assert!(check_canary(&conn, hdl.id).unwrap().is_none());
+
+        crate::testing::execution(&conn, &hdl, Some(canary.id), Some(0));
+        let rolled_back = check_canary(&conn, hdl.id).unwrap().unwrap();
+        assert_eq!(rolled_back.version_id, canary.id);
+        assert_eq!(rolled_back.executions, 4);
+        assert_eq!(rolled_back.failures, 3);
+
+        let split = split(&conn, hdl.id).unwrap();
+        assert_eq!(split.len(), 1);
+        assert_eq!(split[0].version_id, stable.id);
+        assert_eq!(split[0].weight, 100);
+    }
+}